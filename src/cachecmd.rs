@@ -0,0 +1,265 @@
+//! Inspection and maintenance of cache files.
+//!
+//! Cache files are opened with the same exclusive lock as during scanning, so that no scan run
+//! can interfere.
+
+use crate::cachemap::{self, open_locked, CacheMap, CacheStats, FileId, Header};
+use crate::errors::UErr;
+use crate::output::{Format, JsonPath};
+use crate::scan::ScanConfig;
+use crate::storepaths::Origin;
+use crate::system::ExecutionContext;
+
+use bytesize::ByteSize;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct CacheFile {
+    filename: PathBuf,
+    file: fs::File,
+    /// Header as read by `map()`
+    header: Header,
+}
+
+impl CacheFile {
+    /// Opens and locks an existing cache file.
+    pub fn open<P: AsRef<Path>>(filename: P, ctx: &ExecutionContext) -> Result<Self, UErr> {
+        let filename = filename.as_ref().to_owned();
+        let file = ctx.with_dropped_privileges(|| {
+            fs::metadata(&filename)
+                .map_err(|e| UErr::LoadCache(filename.clone(), cachemap::Error::from(e)))?;
+            open_locked(&filename).map_err(|e| UErr::LoadCache(filename.clone(), e))
+        })?;
+        Ok(CacheFile {
+            filename,
+            file,
+            header: Header::default(),
+        })
+    }
+
+    /// Decodes the cache file strictly, i.e. fails on any damage.
+    pub fn map(&mut self) -> Result<CacheMap, UErr> {
+        let (header, map) = CacheMap::decode(&mut self.file)
+            .map_err(|e| UErr::LoadCache(self.filename.clone(), e))?;
+        self.header = header;
+        Ok(map)
+    }
+
+    /// Header of the cache file. Only valid after `map()` has been called.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Size on disk in bytes.
+    pub fn size(&self) -> Result<u64, UErr> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Replaces the cache file's contents with `map`, keeping the header information.
+    pub fn save(&mut self, map: &CacheMap, ctx: &ExecutionContext) -> Result<(), UErr> {
+        let filename = &self.filename;
+        let file = &mut self.file;
+        let header = &self.header;
+        ctx.with_dropped_privileges(|| {
+            map.save(file, filename, header)
+                .map_err(|e| UErr::SaveCache(filename.clone(), e))
+        })
+    }
+
+    pub fn filename(&self) -> &Path {
+        &self.filename
+    }
+}
+
+#[derive(Serialize)]
+struct DumpLine<'a> {
+    dev: u64,
+    ino: u64,
+    ctime: i64,
+    ctime_nsec: u32,
+    /// False for cache lines migrated from older versions, which know only the lowest 8 bits of
+    /// `ctime_nsec`
+    exact: bool,
+    /// Hash over mtime and size
+    digest: u32,
+    path: Option<JsonPath<PathBuf>>,
+    refs: Vec<&'a Path>,
+    origins: &'a [Origin],
+    config: Option<&'a ScanConfig>,
+}
+
+/// Writes all cache lines as JSON, sorted by device and inode number.
+///
+/// Emits one object per line in NDJSON format and a single document otherwise.
+pub fn dump(map: &CacheMap, format: Format, w: &mut dyn Write) -> io::Result<()> {
+    let mut ids: Vec<FileId> = map.keys().cloned().collect();
+    ids.sort_unstable();
+    if format != Format::Ndjson {
+        write!(w, "{{\"entries\":[")?;
+    }
+    for (i, id) in ids.iter().enumerate() {
+        let cl = &map[id];
+        let line = DumpLine {
+            dev: id.0,
+            ino: id.1,
+            ctime: cl.stamp.ctime(),
+            ctime_nsec: cl.stamp.ctime_nsec(),
+            exact: cl.stamp.is_exact(),
+            digest: cl.stamp.digest(),
+            path: map.path(id).map(JsonPath),
+            refs: map.refs(cl).collect(),
+            origins: cl.origins(),
+            config: map.config(cl),
+        };
+        if format == Format::Ndjson {
+            serde_json::to_writer(&mut *w, &line)?;
+            writeln!(w)?;
+        } else {
+            write!(w, "{}", if i > 0 { ",\n" } else { "\n" })?;
+            serde_json::to_writer(&mut *w, &line)?;
+        }
+    }
+    if format != Format::Ndjson {
+        writeln!(w, "\n]}}")?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct StatsRecord<'a> {
+    version: u32,
+    store_dir: &'a Option<String>,
+    fingerprint: Option<u64>,
+    #[serde(flatten)]
+    stats: &'a CacheStats,
+    size: u64,
+}
+
+/// Writes cache statistics, either human readable or as JSON object.
+pub fn write_stats(
+    header: &Header,
+    stats: &CacheStats,
+    size: u64,
+    format: Format,
+    w: &mut dyn Write,
+) -> io::Result<()> {
+    if format != Format::Human {
+        let rec = StatsRecord {
+            version: header.version,
+            store_dir: &header.store_dir,
+            fingerprint: header.fingerprint,
+            stats,
+            size,
+        };
+        serde_json::to_writer(&mut *w, &rec)?;
+        return writeln!(w);
+    }
+    let unknown = || "unknown".to_owned();
+    writeln!(w, "Format version:     {}", header.version)?;
+    writeln!(
+        w,
+        "Store dir:          {}",
+        header.store_dir.clone().unwrap_or_else(unknown)
+    )?;
+    writeln!(
+        w,
+        "Fingerprint:        {}",
+        header
+            .fingerprint
+            .map(|f| format!("{:016x}", f))
+            .unwrap_or_else(unknown)
+    )?;
+    writeln!(w, "Entries:            {}", stats.entries)?;
+    writeln!(w, "  with path:        {}", stats.with_path)?;
+    writeln!(w, "Directories:        {}", stats.dirs)?;
+    writeln!(w, "Store paths:        {}", stats.store_paths)?;
+    writeln!(w, "References:         {}", stats.refs)?;
+    writeln!(w, "Size on disk:       {}", ByteSize::b(size))?;
+    writeln!(w, "Estimated memory:   {}", ByteSize::b(stats.memory))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cachemap::Stamp;
+    use crate::storepaths::StoreRef;
+    use tempfile::TempDir;
+
+    fn cachemap() -> CacheMap {
+        let mut cm = CacheMap::new();
+        let cl = cm.line(Stamp::at(20, 21), &[StoreRef::new("ref2", Origin::Shebang)]);
+        cm.insert((5, 2), cl);
+        let cl = cm.line(Stamp::at(10, 11), &[StoreRef::new("ref1", Origin::Text)]);
+        cm.insert((5, 1), cl);
+        cm.set_path((5, 1), Path::new("/home/user/foo"));
+        cm
+    }
+
+    #[test]
+    fn dump_should_emit_all_cachelines() {
+        let mut buf = Vec::new();
+        dump(&cachemap(), Format::Ndjson, &mut buf).unwrap();
+        assert_eq!(
+            format!(
+                "{{\"dev\":5,\"ino\":1,\"ctime\":10,\"ctime_nsec\":11,\"exact\":true,\
+                 \"digest\":{},\"path\":\"/home/user/foo\",\"refs\":[\"ref1\"],\
+                 \"origins\":[\"Text\"],\"config\":null}}\n\
+                 {{\"dev\":5,\"ino\":2,\"ctime\":20,\"ctime_nsec\":21,\"exact\":true,\
+                 \"digest\":{},\"path\":null,\"refs\":[\"ref2\"],\
+                 \"origins\":[\"Shebang\"],\"config\":null}}\n",
+                Stamp::at(10, 11).digest(),
+                Stamp::at(20, 21).digest()
+            ),
+            String::from_utf8(buf).unwrap()
+        );
+
+        let mut buf = Vec::new();
+        dump(&cachemap(), Format::Json, &mut buf).unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(2, doc["entries"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn stats_as_json() {
+        let mut buf = Vec::new();
+        write_stats(
+            &Header::new("/nix/store", 1),
+            &cachemap().stats(),
+            1234,
+            Format::Json,
+            &mut buf,
+        )
+        .unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(2, doc["entries"]);
+        assert_eq!("/nix/store", doc["store_dir"]);
+        assert_eq!(1234, doc["size"]);
+    }
+
+    #[test]
+    fn open_should_not_create_cache_file() {
+        let td = TempDir::new().unwrap();
+        let filename = td.path().join("cache");
+        assert!(CacheFile::open(&filename, &ExecutionContext::new()).is_err());
+        assert!(!filename.exists());
+    }
+
+    #[test]
+    fn save_and_reopen() {
+        let td = TempDir::new().unwrap();
+        let filename = td.path().join("cache");
+        fs::write(&filename, b"").unwrap();
+        let ctx = ExecutionContext::new();
+        {
+            let mut cf = CacheFile::open(&filename, &ctx).unwrap();
+            assert!(cf.map().is_err());
+            cf.save(&cachemap(), &ctx).unwrap();
+            assert!(cf.size().unwrap() > 0);
+        }
+        let mut cf = CacheFile::open(&filename, &ctx).unwrap();
+        assert_eq!(cachemap(), cf.map().unwrap());
+    }
+}
@@ -1,19 +1,30 @@
 //! Persistent HashMap used for caching previous scan results.
+//!
+//! Cache files start with magic bytes and a MessagePack-encoded `Header`, followed by the
+//! LZO-compressed MessagePack body. Cache files written by older versions lack the header and are
+//! migrated transparently.
 use crate::output::p2s;
+use crate::scan::ScanConfig;
+use crate::storepaths::{Origin, StoreRef};
 
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet, FnvHasher};
 use nix::fcntl;
 use rmp_serde::{decode, encode};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::prelude::*;
+use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Error {
     #[error("I/O error")]
     IO(#[from] io::Error),
@@ -21,6 +32,18 @@ pub enum Error {
     LZO(#[from] minilzo::Error),
     #[error("MessagePack decode error")]
     RmpDE(#[from] rmp_serde::decode::Error),
+    #[error("MessagePack decode error at offset {1} of {2} decompressed bytes")]
+    Decode(#[source] rmp_serde::decode::Error, u64, usize),
+    #[error("Trailing garbage at offset {0} of {1} decompressed bytes")]
+    Trailing(u64, usize),
+    #[error("Damaged cache header")]
+    Header(#[source] rmp_serde::decode::Error),
+    #[error("Unsupported cache format version {0} (written by a newer version?)")]
+    Version(u32),
+    #[error("Checksum mismatch: expected {0:016x}, got {1:016x}")]
+    Checksum(u64, u64),
+    #[error("Cache line refers to missing {0} table entry {1}")]
+    Dangling(&'static str, u32),
     #[error("MessagePack encode error")]
     RmpEN(#[from] rmp_serde::encode::Error),
     #[error("Cannot acquire lock")]
@@ -29,48 +52,436 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Identifies cache files with header
+pub const MAGIC: &[u8; 8] = b"FCUSCACH";
+/// Current cache file format. Version 1 is the headerless format written by older versions.
+/// Versions before 3 key cache lines by inode number only. Versions before 4 store references
+/// verbatim and only the lowest 8 bits of `ctime_nsec`.
+pub const FORMAT_VERSION: u32 = 4;
+
+/// Device and inode number of a file
+pub type FileId = (u64, u64);
+
+/// Device number of cache lines migrated from formats which did not record it
+const UNKNOWN_DEV: u64 = 0;
+
+/// Describes under which circumstances a cache file has been written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    /// References are relative to this store dir. Unknown for migrated cache files.
+    pub store_dir: Option<String>,
+    /// Scanner options of the last run for diagnostic purposes. Whether cached results can be
+    /// reused is decided per cache line. Unknown for migrated cache files.
+    pub fingerprint: Option<u64>,
+    /// FNV-1a hash of the compressed body
+    pub checksum: u64,
+    /// Device and inode number of the start directory. A change means that the scanned
+    /// filesystem has been replaced and inode numbers cannot be trusted anymore.
+    #[serde(default)]
+    pub root: Option<FileId>,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            version: FORMAT_VERSION,
+            store_dir: None,
+            fingerprint: None,
+            checksum: 0,
+            root: None,
+        }
+    }
+}
+
+impl Header {
+    /// Header describing the current run.
+    pub fn new(store_dir: &str, fingerprint: u64) -> Self {
+        Header {
+            store_dir: Some(store_dir.to_owned()),
+            fingerprint: Some(fingerprint),
+            ..Header::default()
+        }
+    }
+
+    /// Records the identity of the start directory.
+    pub fn with_root(mut self, meta: &fs::Metadata) -> Self {
+        self.root = Some((meta.dev(), meta.ino()));
+        self
+    }
+
+    /// Pseudo header for headerless cache files.
+    fn legacy() -> Self {
+        Header {
+            version: 1,
+            ..Header::default()
+        }
+    }
+
+    /// Tells why a cache file with header `self` cannot be used in a run described by `current`.
+    /// Unknown values are assumed to match.
+    pub fn mismatch(&self, current: &Header) -> Option<String> {
+        if let (Some(a), Some(b)) = (&self.store_dir, &current.store_dir) {
+            if a != b {
+                return Some(format!("written for store dir {}, not {}", a, b));
+            }
+        }
+        match (self.root, current.root) {
+            (Some(a), Some(b)) if a != b => Some(format!(
+                "start dir identity has changed (device/inode {}/{} instead of {}/{})",
+                b.0, b.1, a.0, a.1
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Rewrites the table indices found in `lines` so that they point into a new table containing
+/// only used entries.
+fn renumber<'a, L, T, F>(
+    mut lines: Vec<&'a mut L>,
+    table: &[T],
+    index: F,
+) -> (Vec<&'a mut L>, Vec<T>)
+where
+    T: Clone,
+    F: Fn(&mut L) -> Vec<&mut u32>,
+{
+    let mut new_ids: FnvHashMap<u32, u32> = FnvHashMap::default();
+    let mut new_table = Vec::new();
+    for cl in lines.iter_mut() {
+        for id in index(cl) {
+            *id = *new_ids.entry(*id).or_insert_with(|| {
+                new_table.push(table[*id as usize].clone());
+                (new_table.len() - 1) as u32
+            });
+        }
+    }
+    (lines, new_table)
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    let mut h = FnvHasher::default();
+    h.write(data);
+    h.finish()
+}
+
+/// Marks stamps migrated from formats which recorded only the lowest 8 bits of `ctime_nsec`
+const LEGACY: u32 = 1 << 31;
+
+/// Marks cache lines without recorded scanner configuration
+const NO_CONFIG: u32 = u32::MAX;
+
+/// Folds a hash over `values` into 32 bits. Never returns 0, which stands for "unknown".
+fn digest(values: &[u64]) -> u32 {
+    let mut h = FnvHasher::default();
+    for v in values {
+        h.write_u64(*v);
+    }
+    let d = h.finish();
+    match (d ^ (d >> 32)) as u32 {
+        0 => 1,
+        d => d,
+    }
+}
+
+/// File attributes which are used to detect changes
+///
+/// ctime is kept with full precision, while mtime and size are only represented by a hash to keep
+/// cache lines small.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy, Serialize, Deserialize)]
+pub struct Stamp {
+    ctime: i64,
+    /// Flagged with `LEGACY` for stamps migrated from older formats
+    ctime_nsec: u32,
+    /// Hash over mtime and size. Covers only the size for legacy stamps, 0 if that is unknown.
+    digest: u32,
+}
+
+impl Stamp {
+    pub fn new(ctime: i64, ctime_nsec: u32, mtime: i64, mtime_nsec: u32, size: u64) -> Self {
+        Stamp {
+            ctime,
+            ctime_nsec,
+            digest: digest(&[mtime as u64, u64::from(mtime_nsec), size]),
+        }
+    }
+
+    /// Stamp migrated from older formats which recorded only the lowest 8 bits of `ctime_nsec`
+    /// and possibly the size. Replaced by an exact stamp on the next cache hit.
+    pub fn legacy(ctime: i64, ctime_nsec: u8, size: Option<u64>) -> Self {
+        Stamp {
+            ctime,
+            ctime_nsec: u32::from(ctime_nsec) | LEGACY,
+            digest: size.map_or(0, |s| digest(&[s])),
+        }
+    }
+
+    pub fn ctime(&self) -> i64 {
+        self.ctime
+    }
+
+    /// Nanoseconds of ctime. Only the lowest 8 bits are valid for legacy stamps.
+    pub fn ctime_nsec(&self) -> u32 {
+        self.ctime_nsec & !LEGACY
+    }
+
+    /// False for stamps migrated from older formats.
+    pub fn is_exact(&self) -> bool {
+        self.ctime_nsec & LEGACY == 0
+    }
+
+    pub fn digest(&self) -> u32 {
+        self.digest
+    }
+
+    /// Tells if a file described by `meta` is unchanged.
+    pub fn matches(&self, meta: &fs::Metadata) -> bool {
+        if self.is_exact() {
+            return *self == Stamp::from(meta);
+        }
+        self.ctime == meta.ctime()
+            && self.ctime_nsec as u8 == meta.ctime_nsec() as u8
+            && (self.digest == 0 || self.digest == digest(&[meta.len()]))
+    }
+}
+
+#[cfg(test)]
+impl Stamp {
+    /// Exact stamp for tests
+    pub fn at(ctime: i64, ctime_nsec: u32) -> Self {
+        Stamp::new(ctime, ctime_nsec, ctime, ctime_nsec, 0)
+    }
+}
+
+impl From<&fs::Metadata> for Stamp {
+    fn from(meta: &fs::Metadata) -> Self {
+        Stamp::new(
+            meta.ctime(),
+            meta.ctime_nsec() as u32,
+            meta.mtime(),
+            meta.mtime_nsec() as u32,
+            meta.len(),
+        )
+    }
+}
+
+/// References of a cache line. Only allocated for files which have any.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
+struct Refs {
+    /// Indices into the cache's store path table
+    ids: Vec<u32>,
+    /// Parallel to `ids`. Empty if migrated from cache files which did not record origins.
+    origins: Vec<Origin>,
+}
+
+/// Cached scan result of a single file
+///
+/// Kept small since there is one for every file scanned. File paths are recorded in a separate
+/// table of the `CacheMap`.
 #[derive(Debug, PartialOrd, Clone, Serialize, Deserialize)]
 pub struct CacheLine {
-    pub ctime: i64,
-    pub ctime_nsec: u8,
-    pub refs: Vec<PathBuf>,
+    pub stamp: Stamp,
+    refs: Option<Box<Refs>>,
+    /// Index into the cache's configuration table. `NO_CONFIG` if migrated from older versions.
+    config: u32,
     #[serde(skip)]
     pub used: bool,
 }
 
 impl PartialEq for CacheLine {
     fn eq(&self, other: &CacheLine) -> bool {
-        self.ctime == other.ctime && self.ctime_nsec == other.ctime_nsec && self.refs == other.refs
+        self.stamp == other.stamp && self.refs == other.refs && self.config == other.config
     }
 }
 
 impl CacheLine {
-    pub fn new(ctime: i64, ctime_nsec: u8, refs: &[PathBuf]) -> Self {
-        Self {
-            ctime,
-            ctime_nsec,
-            refs: refs.to_vec(),
-            used: true,
+    fn ref_ids(&self) -> &[u32] {
+        match self.refs {
+            Some(ref r) => &r.ids,
+            None => &[],
+        }
+    }
+
+    /// Origins parallel to the references. Empty if they have not been recorded.
+    pub fn origins(&self) -> &[Origin] {
+        match self.refs {
+            Some(ref r) => &r.origins,
+            None => &[],
         }
     }
+
+    /// Index into the cache's configuration table. Unknown if migrated from older versions.
+    pub fn config_id(&self) -> Option<u32> {
+        match self.config {
+            NO_CONFIG => None,
+            id => Some(id),
+        }
+    }
+
+    pub fn set_config(&mut self, id: Option<u32>) {
+        self.config = id.unwrap_or(NO_CONFIG);
+    }
+}
+
+/// Cache line layout of format versions 1 to 3. References are stored verbatim.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LegacyCacheLine {
+    ctime: i64,
+    ctime_nsec: u8,
+    refs: Vec<PathBuf>,
+    /// Missing in cache files written by older versions.
+    #[serde(default)]
+    origins: Vec<Origin>,
+    #[serde(default)]
+    path: Option<(u32, Vec<u8>)>,
+    #[serde(default)]
+    config: Option<u32>,
+    #[serde(default)]
+    size: Option<u64>,
+}
+
+/// Overview over cache contents
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    /// Entries with recorded file path
+    pub with_path: usize,
+    /// Distinct directories in the path table
+    pub dirs: usize,
+    /// Distinct store paths
+    pub store_paths: usize,
+    /// References over all entries
+    pub refs: usize,
+    /// Estimated memory usage in bytes after loading
+    pub memory: u64,
 }
 
 /// Creates or opens a file with an exclusive flock
+///
+/// Cache files are replaced by renaming a new file over them. The lock is only valid if `path`
+/// still refers to the locked file afterwards, otherwise we try again.
 pub fn open_locked<P: AsRef<Path>>(path: P) -> Result<fs::File> {
-    let f = fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(false)
-        .open(&path)?;
-    fcntl::flock(f.as_raw_fd(), fcntl::FlockArg::LockExclusiveNonblock)?;
-    Ok(f)
+    lock(path.as_ref(), fcntl::FlockArg::LockExclusiveNonblock)
 }
 
-/// Persistent cache data structure. Maps inode numbers to cache lines.
+/// Like `open_locked`, but waits until a concurrent holder of the lock has released it.
+pub fn open_locked_wait<P: AsRef<Path>>(path: P) -> Result<fs::File> {
+    lock(path.as_ref(), fcntl::FlockArg::LockExclusive)
+}
+
+fn lock(path: &Path, arg: fcntl::FlockArg) -> Result<fs::File> {
+    loop {
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        fcntl::flock(f.as_raw_fd(), arg)?;
+        let locked = f.metadata()?;
+        match fs::metadata(path) {
+            Ok(m) if m.dev() == locked.dev() && m.ino() == locked.ino() => return Ok(f),
+            Ok(_) => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Temporary file used while replacing `filename`
+fn tempname(filename: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(filename.file_name().unwrap_or_else(|| OsStr::new("cache")));
+    name.push(".tmp");
+    filename.with_file_name(name)
+}
+
+/// Replaces `filename` with new contents produced by `write`.
+///
+/// The new contents go into a locked temporary file in the same directory which is synced and then
+/// renamed over `filename`. `file` (the locked old file) is replaced with the new one on success.
+/// On failure, the old file is left untouched.
+pub fn replace<F>(file: &mut fs::File, filename: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut fs::File) -> io::Result<()>,
+{
+    let tmpname = tempname(filename);
+    let res = (|| -> Result<fs::File> {
+        let mut tmp = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmpname)?;
+        fcntl::flock(tmp.as_raw_fd(), fcntl::FlockArg::LockExclusiveNonblock)?;
+        tmp.set_permissions(file.metadata()?.permissions())?;
+        write(&mut tmp)?;
+        tmp.sync_all()?;
+        fs::rename(&tmpname, filename)?;
+        Ok(tmp)
+    })();
+    match res {
+        Ok(tmp) => {
+            *file = tmp;
+            let dir = match filename.parent() {
+                Some(d) if !d.as_os_str().is_empty() => d,
+                _ => Path::new("."),
+            };
+            if let Err(e) = fs::File::open(dir).and_then(|d| d.sync_all()) {
+                debug!("Failed to sync directory {}: {}", p2s(dir), e);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            fs::remove_file(&tmpname).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Persistent cache data structure. Maps device and inode numbers to cache lines.
+///
+/// Store paths, directories and scanner configurations are stored only once in tables shared by
+/// all cache lines. File paths are optionally recorded as (directory, file name) pairs.
 #[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
 pub struct CacheMap {
-    map: FnvHashMap<u64, CacheLine>,
+    map: FnvHashMap<FileId, CacheLine>,
+    /// Index into the directory table and raw file name of cache lines. Only recorded on request.
+    paths: FnvHashMap<FileId, (u32, Vec<u8>)>,
+    /// Store paths referenced by `CacheLine::refs`
+    store_paths: Vec<PathBuf>,
+    #[serde(skip)]
+    store_path_ids: FnvHashMap<PathBuf, u32>,
+    /// Raw directory names referenced by `paths`
+    dirs: Vec<Vec<u8>>,
+    #[serde(skip)]
+    dir_ids: FnvHashMap<Vec<u8>, u32>,
+    /// Scanner configurations referenced by `CacheLine::config`
+    configs: Vec<ScanConfig>,
+}
+
+/// Body of cache files in format versions 1 to 3. Keyed by inode number before version 3.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound(deserialize = "K: Deserialize<'de> + Eq + Hash"))]
+struct LegacyCacheMap<K: Eq + Hash> {
+    map: FnvHashMap<K, LegacyCacheLine>,
+    #[serde(default)]
+    dirs: Vec<Vec<u8>>,
+    #[serde(default)]
+    configs: Vec<ScanConfig>,
+}
+
+/// Decodes the decompressed body of a cache file, which must be consumed completely.
+fn decode_body<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    let mut cur = io::Cursor::new(data);
+    match T::deserialize(&mut decode::Deserializer::new(&mut cur)) {
+        Ok(_) if cur.position() < data.len() as u64 => {
+            Err(Error::Trailing(cur.position(), data.len()))
+        }
+        Ok(body) => Ok(body),
+        Err(e) => Err(Error::Decode(e, cur.position(), data.len())),
+    }
 }
 
 impl CacheMap {
@@ -79,16 +490,353 @@ impl CacheMap {
         Self::default()
     }
 
-    /// Reads a cache file into a CacheMap structure
-    pub fn load<P: AsRef<Path>>(file: &mut fs::File, filename: P) -> Result<CacheMap> {
-        let mut compr = Vec::new();
+    /// Converts a cache body from older formats, using `key` to derive file ids.
+    fn from_legacy<K, F>(legacy: LegacyCacheMap<K>, key: F) -> Self
+    where
+        K: Eq + Hash,
+        F: Fn(K) -> FileId,
+    {
+        let mut cm = CacheMap {
+            dirs: legacy.dirs,
+            configs: legacy.configs,
+            ..CacheMap::default()
+        };
+        for (k, cl) in legacy.map {
+            let id = key(k);
+            let refs = if cl.refs.is_empty() {
+                None
+            } else {
+                Some(Box::new(Refs {
+                    ids: cl.refs.iter().map(|r| cm.intern_store_path(r)).collect(),
+                    origins: cl.origins,
+                }))
+            };
+            cm.map.insert(
+                id,
+                CacheLine {
+                    stamp: Stamp::legacy(cl.ctime, cl.ctime_nsec, cl.size),
+                    refs,
+                    config: cl.config.unwrap_or(NO_CONFIG),
+                    used: false,
+                },
+            );
+            if let Some(path) = cl.path {
+                cm.paths.insert(id, path);
+            }
+        }
+        cm
+    }
+
+    fn intern_store_path(&mut self, path: &Path) -> u32 {
+        if let Some(id) = self.store_path_ids.get(path) {
+            return *id;
+        }
+        let id = self.store_paths.len() as u32;
+        self.store_paths.push(path.to_owned());
+        self.store_path_ids.insert(path.to_owned(), id);
+        id
+    }
+
+    /// Creates a cache line for a file with `refs`. The cache line still needs to be inserted.
+    pub fn line(&mut self, stamp: Stamp, refs: &[StoreRef]) -> CacheLine {
+        let refs = if refs.is_empty() {
+            None
+        } else {
+            Some(Box::new(Refs {
+                ids: refs
+                    .iter()
+                    .map(|r| self.intern_store_path(&r.path))
+                    .collect(),
+                origins: refs.iter().map(|r| r.origin.clone()).collect(),
+            }))
+        };
+        CacheLine {
+            stamp,
+            refs,
+            config: NO_CONFIG,
+            used: true,
+        }
+    }
+
+    /// Inserts the cache line for file `id`, forgetting the path recorded for a previous file
+    /// with the same id.
+    pub fn insert_line(&mut self, id: FileId, cl: CacheLine) {
+        self.paths.remove(&id);
+        self.map.insert(id, cl);
+    }
+
+    /// Store paths referenced by `cl`.
+    pub fn refs<'a>(&'a self, cl: &'a CacheLine) -> impl Iterator<Item = &'a Path> + 'a {
+        cl.ref_ids()
+            .iter()
+            .map(move |&id| self.store_paths[id as usize].as_path())
+    }
+
+    /// Reassembles store references. Origins are unknown if they have not been recorded.
+    pub fn store_refs(&self, cl: &CacheLine) -> Vec<StoreRef> {
+        if cl.origins().len() != cl.ref_ids().len() {
+            return self
+                .refs(cl)
+                .map(|p| StoreRef::new(p, Origin::Unknown))
+                .collect();
+        }
+        self.refs(cl)
+            .zip(cl.origins())
+            .map(|(p, o)| StoreRef::new(p, o.clone()))
+            .collect()
+    }
+
+    fn intern(&mut self, dir: &[u8]) -> u32 {
+        if let Some(id) = self.dir_ids.get(dir) {
+            return *id;
+        }
+        let id = self.dirs.len() as u32;
+        self.dirs.push(dir.to_vec());
+        self.dir_ids.insert(dir.to_vec(), id);
+        id
+    }
+
+    /// Records `path` for the cache line of file `id`. Returns false if nothing has changed.
+    pub fn set_path(&mut self, id: FileId, path: &Path) -> bool {
+        if !self.map.contains_key(&id) {
+            return false;
+        }
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let name = path.file_name().unwrap_or_else(|| OsStr::new(""));
+        let new = (
+            self.intern(dir.as_os_str().as_bytes()),
+            name.as_bytes().to_vec(),
+        );
+        if self.paths.get(&id) == Some(&new) {
+            false
+        } else {
+            self.paths.insert(id, new);
+            true
+        }
+    }
+
+    /// Full path recorded for file `id`, if any.
+    pub fn path(&self, id: &FileId) -> Option<PathBuf> {
+        let (dir, ref name) = *self.paths.get(id)?;
+        let dir = self.dirs.get(dir as usize)?;
+        Some(Path::new(OsStr::from_bytes(dir)).join(OsStr::from_bytes(name)))
+    }
+
+    /// Interns `config` and returns its index.
+    pub fn intern_config(&mut self, config: &ScanConfig) -> u32 {
+        match self.configs.iter().position(|c| c == config) {
+            Some(id) => id as u32,
+            None => {
+                self.configs.push(config.clone());
+                (self.configs.len() - 1) as u32
+            }
+        }
+    }
+
+    /// Scanner configuration under which `cl` has been produced.
+    pub fn config<'a>(&'a self, cl: &CacheLine) -> Option<&'a ScanConfig> {
+        self.configs.get(cl.config_id()? as usize)
+    }
+
+    /// Tells for each interned configuration whether its results may be used in a run with
+    /// `current` configuration.
+    pub fn covering(&self, current: &ScanConfig) -> Vec<bool> {
+        self.configs.iter().map(|c| c.covers(current)).collect()
+    }
+
+    /// Drops store paths, directories and configurations which are not referenced by any cache
+    /// line anymore.
+    pub fn compact(&mut self) {
+        let map = &self.map;
+        self.paths.retain(|id, _| map.contains_key(id));
+        let lines: Vec<&mut CacheLine> = self.map.values_mut().collect();
+        let (lines, store_paths) = renumber(lines, &self.store_paths, |cl| match cl.refs {
+            Some(ref mut r) => r.ids.iter_mut().collect(),
+            None => vec![],
+        });
+        let (_, configs) = renumber(lines, &self.configs, |cl| match cl.config {
+            NO_CONFIG => vec![],
+            ref mut id => vec![id],
+        });
+        let paths = self.paths.values_mut().collect();
+        let (_, dirs) = renumber(paths, &self.dirs, |p| vec![&mut p.0]);
+        self.store_paths = store_paths;
+        self.dirs = dirs;
+        self.configs = configs;
+        self.rebuild_ids();
+    }
+
+    /// Removes all cache lines with a ctime before `ctime`. Returns the number of removed lines.
+    pub fn prune(&mut self, ctime: i64) -> usize {
+        let before = self.map.len();
+        self.map.retain(|_, cl| cl.stamp.ctime() >= ctime);
+        self.compact();
+        before - self.map.len()
+    }
+
+    /// Counts entries and estimates the in-memory size.
+    pub fn stats(&self) -> CacheStats {
+        let mut store_paths: FnvHashSet<u32> = FnvHashSet::default();
+        let mut stats = CacheStats {
+            entries: self.map.len(),
+            dirs: self.dirs.len(),
+            ..CacheStats::default()
+        };
+        let mut mem = self.map.capacity() * mem::size_of::<(FileId, CacheLine)>()
+            + self.paths.capacity() * mem::size_of::<(FileId, (u32, Vec<u8>))>()
+            + self.dirs.iter().map(|d| d.len() * 2 + 64).sum::<usize>()
+            + self
+                .store_paths
+                .iter()
+                .map(|p| p.as_os_str().len() * 2 + 64)
+                .sum::<usize>();
+        for cl in self.map.values() {
+            if let Some(ref r) = cl.refs {
+                stats.refs += r.ids.len();
+                mem += mem::size_of::<Refs>()
+                    + r.ids.len() * mem::size_of::<u32>()
+                    + r.origins.len() * mem::size_of::<Origin>();
+                store_paths.extend(r.ids.iter());
+            }
+        }
+        stats.with_path = self.paths.len();
+        mem += self
+            .paths
+            .values()
+            .map(|(_, name)| name.len())
+            .sum::<usize>();
+        stats.store_paths = store_paths.len();
+        stats.memory = mem as u64;
+        stats
+    }
+
+    fn rebuild_ids(&mut self) {
+        self.store_path_ids = self
+            .store_paths
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.clone(), i as u32))
+            .collect();
+        self.dir_ids = self
+            .dirs
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (d.clone(), i as u32))
+            .collect();
+    }
+
+    /// Ensures that all table indices are valid.
+    fn check_tables(&self) -> Result<()> {
+        for cl in self.map.values() {
+            if let Some(&id) = cl
+                .ref_ids()
+                .iter()
+                .find(|&&id| id as usize >= self.store_paths.len())
+            {
+                return Err(Error::Dangling("store path", id));
+            }
+            if let Some(id) = cl.config_id() {
+                if id as usize >= self.configs.len() {
+                    return Err(Error::Dangling("configuration", id));
+                }
+            }
+        }
+        for &(id, _) in self.paths.values() {
+            if id as usize >= self.dirs.len() {
+                return Err(Error::Dangling("directory", id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a cache file into a CacheMap structure. Fails on any inconsistency.
+    ///
+    /// Headerless cache files are returned with a version 1 header. Cache lines from formats
+    /// before version 3 get an unknown device number, cache lines from formats before version 4
+    /// a legacy stamp. Decoding errors carry the offset into the decompressed data at which they
+    /// occurred.
+    pub fn decode(file: &mut fs::File) -> Result<(Header, CacheMap)> {
+        let mut buf = Vec::new();
         file.seek(io::SeekFrom::Start(0))?;
-        file.read_to_end(&mut compr)?;
-        match minilzo::decompress(&compr, compr.len() * 10)
-            .map_err(Error::from)
-            .and_then(|data| decode::from_slice(&data).map_err(Error::from))
-        {
-            Ok(cachemap) => Ok(cachemap),
+        file.read_to_end(&mut buf)?;
+        let (header, compr) = if buf.starts_with(MAGIC) {
+            let mut cur = io::Cursor::new(&buf[MAGIC.len()..]);
+            let header = Header::deserialize(&mut decode::Deserializer::new(&mut cur))
+                .map_err(Error::Header)?;
+            if header.version > FORMAT_VERSION {
+                return Err(Error::Version(header.version));
+            }
+            let compr = &buf[MAGIC.len() + cur.position() as usize..];
+            let sum = checksum(compr);
+            if sum != header.checksum {
+                return Err(Error::Checksum(header.checksum, sum));
+            }
+            (header, compr)
+        } else {
+            (Header::legacy(), &buf[..])
+        };
+        let data = minilzo::decompress(compr, compr.len() * 10)?;
+        let mut cachemap = match header.version {
+            1 | 2 => CacheMap::from_legacy(decode_body::<LegacyCacheMap<u64>>(&data)?, |ino| {
+                (UNKNOWN_DEV, ino)
+            }),
+            3 => CacheMap::from_legacy(decode_body::<LegacyCacheMap<FileId>>(&data)?, |id| id),
+            _ => decode_body::<CacheMap>(&data)?,
+        };
+        cachemap.check_tables()?;
+        cachemap.rebuild_ids();
+        Ok((header, cachemap))
+    }
+
+    /// Assigns device `dev` to all cache lines with unknown device number.
+    fn adopt_device(&mut self, dev: u64) {
+        if dev == UNKNOWN_DEV || !self.map.keys().any(|&(d, _)| d == UNKNOWN_DEV) {
+            return;
+        }
+        let adopt = |(d, ino)| (if d == UNKNOWN_DEV { dev } else { d }, ino);
+        self.map = mem::take(&mut self.map)
+            .into_iter()
+            .map(|(id, cl)| (adopt(id), cl))
+            .collect();
+        self.paths = mem::take(&mut self.paths)
+            .into_iter()
+            .map(|(id, p)| (adopt(id), p))
+            .collect();
+    }
+
+    /// Reads a cache file into a CacheMap structure.
+    ///
+    /// Damaged cache files and cache files which don't fit the `current` run result in an empty
+    /// map. Cache files in older formats are accepted and converted on the next save. Cache lines
+    /// without device number are assumed to reside on the start dir's device.
+    pub fn load<P: AsRef<Path>>(
+        file: &mut fs::File,
+        filename: P,
+        current: &Header,
+    ) -> Result<CacheMap> {
+        match Self::decode(file) {
+            Ok((header, mut cachemap)) => {
+                if header.version < FORMAT_VERSION {
+                    info!(
+                        "Migrating cache {} from format version {} to {}",
+                        p2s(&filename),
+                        header.version,
+                        FORMAT_VERSION
+                    );
+                }
+                match header.mismatch(current) {
+                    Some(reason) => {
+                        info!("Discarding cache {}: {}", p2s(&filename), reason);
+                        Ok(Self::default())
+                    }
+                    None => {
+                        if let Some((dev, _)) = current.root {
+                            cachemap.adopt_device(dev);
+                        }
+                        Ok(cachemap)
+                    }
+                }
+            }
             Err(err) => {
                 warn!(
                     "Problem while trying to load cache from {}: {} - continuing with empty cache",
@@ -100,24 +848,47 @@ impl CacheMap {
         }
     }
 
-    /// Writes a CacheMap structure into an open file
-    pub fn save(&self, file: &mut fs::File) -> Result<()> {
-        file.seek(io::SeekFrom::Start(0))?;
-        file.set_len(0)?;
-        Ok(file.write_all(&minilzo::compress(&encode::to_vec(self)?)?)?)
+    /// Writes a CacheMap structure to `filename`, preceded by `header` in the current format
+    ///
+    /// The file is replaced atomically, see `replace()`. `file` must be the locked file opened
+    /// from `filename` and refers to the new file afterwards.
+    pub fn save(&self, file: &mut fs::File, filename: &Path, header: &Header) -> Result<()> {
+        self.save_with(file, filename, header, |f, buf| f.write_all(buf))
+    }
+
+    fn save_with<F>(
+        &self,
+        file: &mut fs::File,
+        filename: &Path,
+        header: &Header,
+        write: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&mut fs::File, &[u8]) -> io::Result<()>,
+    {
+        let compr = minilzo::compress(&encode::to_vec(self)?)?;
+        let header = Header {
+            version: FORMAT_VERSION,
+            checksum: checksum(&compr),
+            ..header.clone()
+        };
+        let mut buf = MAGIC.to_vec();
+        buf.extend(encode::to_vec(&header)?);
+        buf.extend(compr);
+        replace(file, filename, |f| write(f, &buf))
     }
 }
 
 impl Deref for CacheMap {
-    type Target = FnvHashMap<u64, CacheLine>;
+    type Target = FnvHashMap<FileId, CacheLine>;
 
-    fn deref(&self) -> &FnvHashMap<u64, CacheLine> {
+    fn deref(&self) -> &FnvHashMap<FileId, CacheLine> {
         &self.map
     }
 }
 
 impl DerefMut for CacheMap {
-    fn deref_mut(&mut self) -> &mut FnvHashMap<u64, CacheLine> {
+    fn deref_mut(&mut self) -> &mut FnvHashMap<FileId, CacheLine> {
         &mut self.map
     }
 }
@@ -130,34 +901,83 @@ mod tests {
 
     #[test]
     fn cacheline_should_compare_regardless_of_used_flag() {
+        let mut cm = CacheMap::new();
+        let cl = cm.line(Stamp::at(1, 2), &[]);
         assert_eq!(
+            cl,
             CacheLine {
-                ctime: 1,
-                ctime_nsec: 2,
-                refs: vec![],
-                used: true,
-            },
-            CacheLine {
-                ctime: 1,
-                ctime_nsec: 2,
-                refs: vec![],
                 used: false,
+                ..cl.clone()
             }
         )
     }
 
+    #[test]
+    fn stamp_should_compare_full_nanoseconds() {
+        let tempdir = TempDir::new().expect("failed to create tempdir");
+        let filename = tempdir.path().join("file");
+        fs::write(&filename, b"content").unwrap();
+        let meta = fs::metadata(&filename).unwrap();
+        let stamp = Stamp::from(&meta);
+        assert!(stamp.matches(&meta));
+        let (ctime, ctime_nsec) = (meta.ctime(), meta.ctime_nsec());
+        let shifted = (ctime_nsec + 256) % 1_000_000_000;
+        let (mtime, mtime_nsec) = (meta.mtime(), meta.mtime_nsec() as u32);
+        assert!(stamp.is_exact());
+        assert!(!Stamp::new(ctime, shifted as u32, mtime, mtime_nsec, meta.len()).matches(&meta));
+        assert!(!Stamp::new(ctime, ctime_nsec as u32, mtime, mtime_nsec, 1).matches(&meta));
+        assert!(
+            !Stamp::new(ctime, ctime_nsec as u32, mtime + 1, mtime_nsec, meta.len()).matches(&meta)
+        );
+        // legacy stamps cannot tell the difference
+        assert!(Stamp::legacy(ctime, shifted as u8, None).matches(&meta));
+        assert!(Stamp::legacy(ctime, shifted as u8, Some(meta.len())).matches(&meta));
+        assert!(!Stamp::legacy(ctime, ctime_nsec as u8, Some(meta.len() + 1)).matches(&meta));
+        assert!(!Stamp::legacy(ctime, shifted as u8, None).is_exact());
+    }
+
+    #[test]
+    fn cacheline_should_not_exceed_baseline_memory() {
+        // cache line layout of headerless cache files, keyed by inode number
+        #[allow(dead_code)]
+        struct BaselineCacheLine {
+            ctime: i64,
+            ctime_nsec: u8,
+            refs: Vec<PathBuf>,
+            used: bool,
+        }
+        assert!(
+            mem::size_of::<(FileId, CacheLine)>() <= mem::size_of::<(u64, BaselineCacheLine)>()
+        );
+        assert_eq!(32, mem::size_of::<CacheLine>());
+    }
+
     fn dummy_cachemap() -> CacheMap {
-        let mut cm = FnvHashMap::default();
-        cm.insert(1, CacheLine::new(10, 11, &[PathBuf::from("/nix/ref1")][..]));
-        cm.insert(
-            2,
-            CacheLine::new(
-                20,
-                21,
-                &[PathBuf::from("/nix/ref1"), PathBuf::from("/nix/ref2")][..],
-            ),
+        let mut cm = CacheMap::new();
+        let cl = cm.line(
+            Stamp::at(10, 11),
+            &[StoreRef::new("/nix/ref1", Origin::Text)],
         );
-        CacheMap { map: cm }
+        cm.insert((1, 1), cl);
+        let cl = cm.line(
+            Stamp::at(20, 21),
+            &[
+                StoreRef::new("/nix/ref1", Origin::Shebang),
+                StoreRef::new("/nix/ref2", Origin::Archive("lib/x.so".into())),
+            ],
+        );
+        cm.insert((1, 2), cl);
+        cm
+    }
+
+    /// File ids, stamps and references sorted by file id
+    fn contents(cm: &CacheMap) -> Vec<(FileId, Stamp, Vec<StoreRef>)> {
+        let mut c: Vec<_> = cm
+            .iter()
+            .map(|(id, cl)| (*id, cl.stamp, cm.store_refs(cl)))
+            .collect();
+        c.sort_by_key(|e| e.0);
+        c
     }
 
     #[test]
@@ -166,7 +986,9 @@ mod tests {
         let filename = tempdir.path().join("cache");
         {
             let mut f = open_locked(&filename).unwrap();
-            assert!(dummy_cachemap().save(&mut f).is_ok());
+            assert!(dummy_cachemap()
+                .save(&mut f, &filename, &Header::default())
+                .is_ok());
         }
         assert!(fs::metadata(&filename).unwrap().len() > 0);
     }
@@ -177,10 +999,438 @@ mod tests {
         let filename = tempdir.path().join("cache.ok");
         fs::copy(FIXTURES.join("cache.mp"), &filename).unwrap();
         let mut f = open_locked(&filename).unwrap();
-        let cm = CacheMap::load(&mut f, &filename).unwrap();
+        let cm = CacheMap::load(&mut f, &filename, &Header::default()).unwrap();
         assert_eq!(12, cm.map.len());
     }
 
+    #[test]
+    fn save_and_load_should_preserve_origins() {
+        let tempdir = TempDir::new().expect("failed to create tempdir");
+        let filename = tempdir.path().join("cache");
+        let mut f = open_locked(&filename).unwrap();
+        dummy_cachemap()
+            .save(&mut f, &filename, &Header::default())
+            .unwrap();
+        let cm = CacheMap::load(&mut f, &filename, &Header::default()).unwrap();
+        assert_eq!(dummy_cachemap(), cm);
+    }
+
+    #[test]
+    fn paths_should_be_interned_per_directory() {
+        let mut cm = dummy_cachemap();
+        let cl = cm.line(Stamp::at(30, 31), &[]);
+        cm.insert((1, 3), cl);
+        assert!(cm.set_path((1, 1), Path::new("/home/user/bin/foo")));
+        assert!(cm.set_path((1, 2), Path::new("/home/user/lib/bar")));
+        let cafe = Path::new(OsStr::from_bytes(b"/home/user/bin/caf\xe9"));
+        assert!(cm.set_path((1, 3), cafe));
+        assert!(!cm.set_path((1, 3), cafe));
+        assert!(!cm.set_path((1, 4), Path::new("/no/such/ino")));
+        assert_eq!(2, cm.dirs.len());
+        assert_eq!(Some(cafe.to_owned()), cm.path(&(1, 3)));
+
+        let tempdir = TempDir::new().expect("failed to create tempdir");
+        let filename = tempdir.path().join("cache");
+        let mut f = open_locked(&filename).unwrap();
+        cm.save(&mut f, &filename, &Header::default()).unwrap();
+        let mut loaded = CacheMap::load(&mut f, &filename, &Header::default()).unwrap();
+        assert_eq!(cm, loaded);
+        assert_eq!(
+            Some(PathBuf::from("/home/user/lib/bar")),
+            loaded.path(&(1, 2))
+        );
+
+        loaded.remove(&(1, 2));
+        loaded.compact();
+        assert_eq!(1, loaded.dirs.len());
+        assert_eq!(
+            Some(PathBuf::from("/home/user/bin/foo")),
+            loaded.path(&(1, 1))
+        );
+        assert!(!loaded.set_path((1, 1), Path::new("/home/user/bin/foo")));
+    }
+
+    #[test]
+    fn configs_should_be_interned_and_compacted() {
+        let mut cm = dummy_cachemap();
+        let weak = ScanConfig::default();
+        let strong = ScanConfig {
+            text: true,
+            ..ScanConfig::default()
+        };
+        let w = cm.intern_config(&weak);
+        let s = cm.intern_config(&strong);
+        assert_eq!(w, cm.intern_config(&weak));
+        cm.get_mut(&(1, 2)).unwrap().set_config(Some(s));
+        assert_eq!(vec![true, true], cm.covering(&weak));
+        assert_eq!(vec![false, true], cm.covering(&strong));
+        assert_eq!(None, cm.config(&cm[&(1, 1)]));
+        assert_eq!(Some(&strong), cm.config(&cm[&(1, 2)]));
+        cm.compact();
+        assert_eq!(1, cm.configs.len());
+        assert_eq!(Some(&strong), cm.config(&cm[&(1, 2)]));
+    }
+
+    #[test]
+    fn paths_should_follow_their_cache_lines() {
+        let mut cm = dummy_cachemap();
+        cm.set_path((1, 1), Path::new("/home/user/bin/foo"));
+        cm.set_path((1, 2), Path::new("/home/user/lib/bar"));
+        let cl = cm.line(Stamp::at(30, 31), &[]);
+        cm.insert_line((1, 2), cl);
+        assert_eq!(None, cm.path(&(1, 2)));
+        cm.remove(&(1, 1));
+        cm.compact();
+        assert!(cm.paths.is_empty());
+        assert!(cm.dirs.is_empty());
+    }
+
+    #[test]
+    fn prune_should_remove_old_entries() {
+        let mut cm = dummy_cachemap();
+        cm.set_path((1, 1), Path::new("/home/user/bin/foo"));
+        cm.set_path((1, 2), Path::new("/home/user/lib/bar"));
+        assert_eq!(1, cm.prune(15));
+        assert!(cm.contains_key(&(1, 2)));
+        assert_eq!(1, cm.dirs.len());
+        assert_eq!(0, cm.prune(15));
+    }
+
+    #[test]
+    fn store_paths_should_be_interned_and_compacted() {
+        let mut cm = dummy_cachemap();
+        assert_eq!(2, cm.store_paths.len());
+        assert_eq!(cm[&(1, 1)].ref_ids()[0], cm[&(1, 2)].ref_ids()[0]);
+        cm.remove(&(1, 2));
+        cm.compact();
+        assert_eq!(vec![PathBuf::from("/nix/ref1")], cm.store_paths);
+        let cl = cm.line(
+            Stamp::at(30, 31),
+            &[StoreRef::new("/nix/ref1", Origin::Text)],
+        );
+        assert_eq!(cm[&(1, 1)].refs, cl.refs);
+    }
+
+    #[test]
+    fn stats_should_count_distinct_store_paths() {
+        let mut cm = dummy_cachemap();
+        cm.set_path((1, 1), Path::new("/home/user/bin/foo"));
+        let stats = cm.stats();
+        assert_eq!(2, stats.entries);
+        assert_eq!(1, stats.with_path);
+        assert_eq!(1, stats.dirs);
+        assert_eq!(2, stats.store_paths);
+        assert_eq!(3, stats.refs);
+        assert!(stats.memory > 0);
+    }
+
+    #[test]
+    fn decode_should_locate_errors() {
+        let tempdir = TempDir::new().expect("failed to create tempdir");
+        let filename = tempdir.path().join("cache");
+        let mut f = open_locked(&filename).unwrap();
+        let mut data = encode::to_vec(&legacy_cachemap(|id| id.1)).unwrap();
+        data.truncate(data.len() - 3);
+        f.write_all(&minilzo::compress(&data).unwrap()).unwrap();
+        match CacheMap::decode(&mut f) {
+            Err(Error::Decode(_, offset, len)) => {
+                assert_eq!(data.len(), len);
+                assert!(offset <= len as u64);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let mut data = encode::to_vec(&legacy_cachemap(|id| id.1)).unwrap();
+        let len = data.len();
+        data.extend_from_slice(b"\xc0\xc0");
+        f.set_len(0).unwrap();
+        f.seek(io::SeekFrom::Start(0)).unwrap();
+        f.write_all(&minilzo::compress(&data).unwrap()).unwrap();
+        match CacheMap::decode(&mut f) {
+            Err(Error::Trailing(offset, total)) => {
+                assert_eq!(len as u64, offset);
+                assert_eq!(len + 2, total);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    fn saved(cm: &CacheMap, header: &Header) -> (TempDir, PathBuf, fs::File) {
+        let tempdir = TempDir::new().expect("failed to create tempdir");
+        let filename = tempdir.path().join("cache");
+        let mut f = open_locked(&filename).unwrap();
+        cm.save(&mut f, &filename, header).unwrap();
+        (tempdir, filename, f)
+    }
+
+    #[test]
+    fn save_should_replace_file_and_keep_lock() {
+        let (_td, filename, mut f) = saved(&CacheMap::new(), &Header::default());
+        let ino = fs::metadata(&filename).unwrap().ino();
+        dummy_cachemap()
+            .save(&mut f, &filename, &Header::default())
+            .unwrap();
+        let meta = fs::metadata(&filename).unwrap();
+        assert_ne!(ino, meta.ino());
+        assert_eq!(meta.ino(), f.metadata().unwrap().ino());
+        assert!(!tempname(&filename).exists());
+        assert!(open_locked(&filename).is_err());
+        assert_eq!(dummy_cachemap(), CacheMap::decode(&mut f).unwrap().1);
+    }
+
+    #[test]
+    fn failed_save_should_keep_previous_cache() {
+        let (_td, filename, mut f) = saved(&dummy_cachemap(), &Header::default());
+        let before = fs::read(&filename).unwrap();
+        let res = CacheMap::new().save_with(&mut f, &filename, &Header::default(), |f, buf| {
+            f.write_all(&buf[..buf.len() / 2])?;
+            Err(io::Error::from_raw_os_error(nix::libc::ENOSPC))
+        });
+        assert!(res.is_err());
+        assert_eq!(before, fs::read(&filename).unwrap());
+        assert!(!tempname(&filename).exists());
+        assert!(open_locked(&filename).is_err());
+        assert_eq!(dummy_cachemap(), CacheMap::decode(&mut f).unwrap().1);
+    }
+
+    #[test]
+    fn save_should_fail_if_tempfile_cannot_be_created() {
+        let (_td, filename, mut f) = saved(&dummy_cachemap(), &Header::default());
+        let before = fs::read(&filename).unwrap();
+        fs::create_dir(tempname(&filename)).unwrap();
+        assert!(CacheMap::new()
+            .save(&mut f, &filename, &Header::default())
+            .is_err());
+        assert_eq!(before, fs::read(&filename).unwrap());
+    }
+
+    #[test]
+    fn header_should_be_checked_on_load() {
+        let (_td, filename, mut f) = saved(&dummy_cachemap(), &Header::new("/nix/store", 42));
+        let (header, _) = CacheMap::decode(&mut f).unwrap();
+        assert_eq!(FORMAT_VERSION, header.version);
+        assert_eq!(Some("/nix/store".to_owned()), header.store_dir);
+        assert_eq!(Some(42), header.fingerprint);
+
+        let load = |f: &mut fs::File, h| CacheMap::load(f, &filename, &h).unwrap();
+        assert_eq!(2, load(&mut f, Header::new("/nix/store", 42)).len());
+        assert_eq!(2, load(&mut f, Header::default()).len());
+        assert_eq!(0, load(&mut f, Header::new("/opt/store", 42)).len());
+        assert_eq!(2, load(&mut f, Header::new("/nix/store", 43)).len());
+    }
+
+    /// `dummy_cachemap()` as written by format versions 1 to 3
+    fn legacy_cachemap<K, F>(key: F) -> LegacyCacheMap<K>
+    where
+        K: Eq + Hash,
+        F: Fn(FileId) -> K,
+    {
+        let cm = dummy_cachemap();
+        let map = cm
+            .iter()
+            .map(|(id, cl)| {
+                let line = LegacyCacheLine {
+                    ctime: cl.stamp.ctime(),
+                    ctime_nsec: cl.stamp.ctime_nsec() as u8,
+                    refs: cm.refs(cl).map(Path::to_owned).collect(),
+                    origins: cl.origins().to_vec(),
+                    path: None,
+                    config: None,
+                    size: None,
+                };
+                (key(*id), line)
+            })
+            .collect();
+        LegacyCacheMap {
+            map,
+            dirs: vec![],
+            configs: vec![],
+        }
+    }
+
+    fn legacy_body() -> Vec<u8> {
+        minilzo::compress(&encode::to_vec(&legacy_cachemap(|id| id.1)).unwrap()).unwrap()
+    }
+
+    /// Expected `contents()` of `dummy_cachemap()` after migration
+    fn migrated() -> Vec<(FileId, Stamp, Vec<StoreRef>)> {
+        contents(&dummy_cachemap())
+            .into_iter()
+            .map(|(id, stamp, refs)| {
+                let stamp = Stamp::legacy(stamp.ctime(), stamp.ctime_nsec() as u8, None);
+                (id, stamp, refs)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn headerless_cache_should_be_migrated() {
+        let (_td, filename, mut f) = saved(&CacheMap::new(), &Header::default());
+        f.set_len(0).unwrap();
+        f.seek(io::SeekFrom::Start(0)).unwrap();
+        f.write_all(&legacy_body()).unwrap();
+        let (header, cm) = CacheMap::decode(&mut f).unwrap();
+        assert_eq!(Header::legacy(), header);
+        assert!(cm.contains_key(&(UNKNOWN_DEV, 2)));
+        let current = Header {
+            root: Some((1, 100)),
+            ..Header::new("/nix/store", 42)
+        };
+        let cm = CacheMap::load(&mut f, &filename, &current).unwrap();
+        assert_eq!(migrated(), contents(&cm));
+        cm.save(&mut f, &filename, &current).unwrap();
+        let (header, _) = CacheMap::decode(&mut f).unwrap();
+        assert_eq!(FORMAT_VERSION, header.version);
+        assert_eq!(Some(42), header.fingerprint);
+        assert_eq!(Some((1, 100)), header.root);
+    }
+
+    #[test]
+    fn version2_cache_should_be_migrated() {
+        let body = legacy_body();
+        let header = Header {
+            version: 2,
+            checksum: checksum(&body),
+            ..Header::new("/nix/store", 42)
+        };
+        let (_td, filename, mut f) = saved(&CacheMap::new(), &Header::default());
+        f.set_len(0).unwrap();
+        f.seek(io::SeekFrom::Start(0)).unwrap();
+        f.write_all(MAGIC).unwrap();
+        f.write_all(&encode::to_vec(&header).unwrap()).unwrap();
+        f.write_all(&body).unwrap();
+        let current = Header {
+            root: Some((1, 100)),
+            ..Header::new("/nix/store", 42)
+        };
+        assert_eq!(
+            migrated(),
+            contents(&CacheMap::load(&mut f, &filename, &current).unwrap())
+        );
+    }
+
+    #[test]
+    fn version3_cache_should_be_migrated() {
+        let body = minilzo::compress(&encode::to_vec(&legacy_cachemap(|id| id)).unwrap()).unwrap();
+        let header = Header {
+            version: 3,
+            checksum: checksum(&body),
+            ..Header::new("/nix/store", 42)
+        };
+        let (_td, filename, mut f) = saved(&CacheMap::new(), &Header::default());
+        f.set_len(0).unwrap();
+        f.seek(io::SeekFrom::Start(0)).unwrap();
+        f.write_all(MAGIC).unwrap();
+        f.write_all(&encode::to_vec(&header).unwrap()).unwrap();
+        f.write_all(&body).unwrap();
+        assert_eq!(
+            migrated(),
+            contents(&CacheMap::load(&mut f, &filename, &Header::default()).unwrap())
+        );
+    }
+
+    #[test]
+    fn dangling_table_index_should_fail_decoding() {
+        let mut cm = dummy_cachemap();
+        cm.get_mut(&(1, 1)).unwrap().refs.as_mut().unwrap().ids = vec![7];
+        let (_td, _, mut f) = saved(&cm, &Header::default());
+        match CacheMap::decode(&mut f) {
+            Err(Error::Dangling("store path", 7)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn changed_root_identity_should_discard_cache() {
+        let written = Header {
+            root: Some((1, 100)),
+            ..Header::new("/nix/store", 42)
+        };
+        let (_td, filename, mut f) = saved(&dummy_cachemap(), &written);
+        let load = |f: &mut fs::File, root| {
+            let h = Header {
+                root,
+                ..written.clone()
+            };
+            CacheMap::load(f, &filename, &h).unwrap().len()
+        };
+        assert_eq!(2, load(&mut f, Some((1, 100))));
+        assert_eq!(2, load(&mut f, None));
+        assert_eq!(0, load(&mut f, Some((1, 101))));
+        assert_eq!(0, load(&mut f, Some((2, 100))));
+    }
+
+    #[test]
+    fn damaged_body_should_fail_checksum() {
+        let (_td, filename, mut f) = saved(&dummy_cachemap(), &Header::default());
+        let len = f.metadata().unwrap().len();
+        f.seek(io::SeekFrom::Start(len - 2)).unwrap();
+        f.write_all(b"\x00\xff").unwrap();
+        match CacheMap::decode(&mut f) {
+            Err(Error::Checksum(..)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(
+            0,
+            CacheMap::load(&mut f, &filename, &Header::default())
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn newer_format_should_be_rejected() {
+        let (_td, _filename, mut f) = saved(&dummy_cachemap(), &Header::default());
+        let mut buf = MAGIC.to_vec();
+        buf.extend(
+            encode::to_vec(&Header {
+                version: FORMAT_VERSION + 1,
+                ..Header::default()
+            })
+            .unwrap(),
+        );
+        f.set_len(0).unwrap();
+        f.seek(io::SeekFrom::Start(0)).unwrap();
+        f.write_all(&buf).unwrap();
+        match CacheMap::decode(&mut f) {
+            Err(Error::Version(v)) => assert_eq!(FORMAT_VERSION + 1, v),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn cachelines_without_origins_should_load() {
+        // cache line layout as written by older versions
+        #[derive(Serialize)]
+        struct OldCacheLine {
+            ctime: i64,
+            ctime_nsec: u8,
+            refs: Vec<PathBuf>,
+        }
+        let legacy = OldCacheLine {
+            ctime: 1,
+            ctime_nsec: 2,
+            refs: vec![PathBuf::from("/nix/ref1")],
+        };
+        let cl: LegacyCacheLine = decode::from_slice(&encode::to_vec(&legacy).unwrap()).unwrap();
+        assert_eq!(legacy.refs, cl.refs);
+        assert!(cl.origins.is_empty());
+        let mut map = FnvHashMap::default();
+        map.insert(1, cl);
+        let cm = CacheMap::from_legacy(
+            LegacyCacheMap {
+                map,
+                dirs: vec![],
+                configs: vec![],
+            },
+            |ino| (UNKNOWN_DEV, ino),
+        );
+        assert_eq!(
+            vec![StoreRef::new("/nix/ref1", Origin::Unknown)],
+            cm.store_refs(&cm[&(UNKNOWN_DEV, 1)])
+        );
+    }
+
     #[test]
     fn load_should_ignore_broken_cachefile() {
         let tempdir = TempDir::new().expect("failed to create tempdir");
@@ -188,7 +1438,8 @@ mod tests {
         fs::copy(FIXTURES.join("cache.mp"), &filename).unwrap();
         let mut f = open_locked(&filename).unwrap();
         f.set_len(500).unwrap();
-        let cm = CacheMap::load(&mut f, &filename).expect("should ignore truncated cache file");
+        let cm = CacheMap::load(&mut f, &filename, &Header::default())
+            .expect("should ignore truncated cache file");
         assert_eq!(cm.map.len(), 0);
     }
 }
@@ -0,0 +1,171 @@
+//! Progress of time-budgeted scans.
+//!
+//! A scan limited with --max-runtime walks the files directly in the start dir first and then
+//! each top-level subdirectory on its own. Subtrees which have been walked completely are recorded
+//! in a checkpoint file next to the cache file, so that the next run continues with the remaining
+//! ones. The checkpoint is removed as soon as all subtrees have been done.
+
+use crate::cachemap::{self, open_locked};
+use crate::errors::UErr;
+use crate::output::p2s;
+
+use rmp_serde::{decode, encode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Normalized start dir
+    startdir: Vec<u8>,
+    /// Identifies the scanner configuration
+    fingerprint: u64,
+    /// Names of top-level subdirectories which have been walked completely
+    done: BTreeSet<Vec<u8>>,
+}
+
+impl Checkpoint {
+    pub fn new(startdir: &Path, fingerprint: u64) -> Self {
+        Self {
+            startdir: startdir.as_os_str().as_bytes().to_vec(),
+            fingerprint,
+            done: BTreeSet::new(),
+        }
+    }
+
+    /// Checkpoint file which belongs to `cachefile`.
+    pub fn filename(cachefile: &Path) -> PathBuf {
+        let mut name = OsString::from(cachefile);
+        name.push(".checkpoint");
+        PathBuf::from(name)
+    }
+
+    /// Reads the checkpoint from `filename`.
+    ///
+    /// Starts afresh if there is no checkpoint or if it has been recorded for a different start
+    /// dir or with different scanner options. Damaged checkpoints are discarded with a warning.
+    pub fn load(filename: &Path, startdir: &Path, fingerprint: u64) -> Self {
+        let fresh = Self::new(startdir, fingerprint);
+        let data = match fs::read(filename) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return fresh,
+            Err(e) => {
+                warn!("Failed to read checkpoint {}: {}", p2s(filename), e);
+                return fresh;
+            }
+        };
+        match decode::from_slice::<Checkpoint>(&data) {
+            Ok(cp) if cp.startdir == fresh.startdir && cp.fingerprint == fingerprint => {
+                info!(
+                    "Resuming from checkpoint {} ({} subtrees done)",
+                    p2s(filename),
+                    cp.done.len()
+                );
+                cp
+            }
+            Ok(_) => {
+                info!(
+                    "Discarding checkpoint {} (different start dir or scanner options)",
+                    p2s(filename)
+                );
+                fresh
+            }
+            Err(e) => {
+                warn!("Discarding damaged checkpoint {}: {}", p2s(filename), e);
+                fresh
+            }
+        }
+    }
+
+    /// Writes the checkpoint to `filename`, replacing any previous one atomically and durably.
+    pub fn save(&self, filename: &Path) -> Result<(), UErr> {
+        let err = |e| UErr::SaveCheckpoint(filename.to_owned(), e);
+        let data = encode::to_vec(self)
+            .map_err(|e| err(io::Error::new(ErrorKind::InvalidData, e).into()))?;
+        let mut file = open_locked(filename).map_err(err)?;
+        cachemap::replace(&mut file, filename, |f| f.write_all(&data)).map_err(err)
+    }
+
+    /// Deletes the checkpoint file at `filename` if it exists.
+    pub fn remove(filename: &Path) -> Result<(), UErr> {
+        match fs::remove_file(filename) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(UErr::SaveCheckpoint(filename.to_owned(), e.into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns true if the top-level subdirectory `name` has been walked completely.
+    pub fn is_done(&self, name: &OsStr) -> bool {
+        self.done.contains(name.as_bytes())
+    }
+
+    pub fn mark_done(&mut self, name: &OsStr) {
+        self.done.insert(name.as_bytes().to_vec());
+    }
+
+    /// Number of top-level subdirectories which have been walked completely.
+    pub fn completed(&self) -> usize {
+        self.done.len()
+    }
+}
+
+/// Parts of the start dir which have been walked completely during an incomplete scan.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Walked {
+    /// Files directly in the start dir
+    pub toplevel: bool,
+    /// Top-level subdirectories
+    pub subtrees: Vec<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn save_and_load() {
+        let td = TempDir::new().unwrap();
+        let filename = Checkpoint::filename(&td.path().join("cache"));
+        assert_eq!(td.path().join("cache.checkpoint"), filename);
+        let mut cp = Checkpoint::load(&filename, Path::new("/srv"), 1);
+        assert_eq!(0, cp.completed());
+        cp.mark_done(OsStr::new("www"));
+        cp.save(&filename).unwrap();
+
+        let cp = Checkpoint::load(&filename, Path::new("/srv"), 1);
+        assert!(cp.is_done(OsStr::new("www")));
+        assert!(!cp.is_done(OsStr::new("data")));
+        Checkpoint::remove(&filename).unwrap();
+        assert!(!filename.exists());
+        Checkpoint::remove(&filename).unwrap();
+    }
+
+    #[test]
+    fn load_should_discard_foreign_checkpoint() {
+        let td = TempDir::new().unwrap();
+        let filename = td.path().join("cache.checkpoint");
+        let mut cp = Checkpoint::new(Path::new("/srv"), 1);
+        cp.mark_done(OsStr::new("www"));
+        cp.save(&filename).unwrap();
+        assert_eq!(
+            0,
+            Checkpoint::load(&filename, Path::new("/home"), 1).completed()
+        );
+        assert_eq!(
+            0,
+            Checkpoint::load(&filename, Path::new("/srv"), 2).completed()
+        );
+        fs::write(&filename, b"garbage").unwrap();
+        assert_eq!(
+            0,
+            Checkpoint::load(&filename, Path::new("/srv"), 1).completed()
+        );
+    }
+}
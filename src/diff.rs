@@ -0,0 +1,219 @@
+//! Changes to the GC roots which a dry run would have made.
+//!
+//! With --dry-run, the registry computes the links which would be created, replaced and removed
+//! (including empty directories) instead of acting on them. The result is printed either as a
+//! diff-like listing or as JSON.
+
+use crate::output::{display, Format, JsonPath};
+
+use colored::Colorize;
+use serde::{Serialize, Serializer};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Change {
+    /// New link pointing to `target`
+    Create {
+        #[serde(serialize_with = "json_path")]
+        link: PathBuf,
+        #[serde(serialize_with = "json_path")]
+        target: PathBuf,
+    },
+    /// Existing link which would be pointed from `old` to `target`
+    Replace {
+        #[serde(serialize_with = "json_path")]
+        link: PathBuf,
+        #[serde(serialize_with = "json_path")]
+        old: PathBuf,
+        #[serde(serialize_with = "json_path")]
+        target: PathBuf,
+    },
+    /// Unused link which currently points to `target`
+    Remove {
+        #[serde(serialize_with = "json_path")]
+        link: PathBuf,
+        #[serde(serialize_with = "json_path")]
+        target: PathBuf,
+    },
+    /// Empty link directory
+    RemoveDir {
+        #[serde(serialize_with = "json_path")]
+        dir: PathBuf,
+    },
+}
+
+fn json_path<P: AsRef<Path>, S: Serializer>(path: &P, s: S) -> Result<S::Ok, S::Error> {
+    JsonPath(path.as_ref()).serialize(s)
+}
+
+impl Change {
+    /// Link or directory affected by the change
+    pub fn path(&self) -> &Path {
+        match self {
+            Change::Create { link, .. }
+            | Change::Replace { link, .. }
+            | Change::Remove { link, .. } => link,
+            Change::RemoveDir { dir } => dir,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Diff {
+    changes: Vec<Change>,
+}
+
+impl Diff {
+    pub fn push(&mut self, change: Change) {
+        self.changes.push(change)
+    }
+
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    /// Returns true if a link would be created directly in `dir`.
+    pub fn creates_in(&self, dir: &Path) -> bool {
+        self.changes.iter().any(|c| match c {
+            Change::Create { link, .. } => link.parent() == Some(dir),
+            _ => false,
+        })
+    }
+
+    /// Returns true if `path` would be removed.
+    pub fn removes(&self, path: &Path) -> bool {
+        self.changes.iter().any(|c| match c {
+            Change::Remove { link, .. } => link == path,
+            Change::RemoveDir { dir } => dir == path,
+            _ => false,
+        })
+    }
+
+    /// Writes all changes sorted by path.
+    ///
+    /// Emits one object per line in NDJSON format and a single document in JSON format.
+    pub fn write(&self, format: Format, w: &mut dyn Write) -> io::Result<()> {
+        let mut changes: Vec<&Change> = self.changes.iter().collect();
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        match format {
+            Format::Human => {
+                for c in changes {
+                    write_human(w, c)?;
+                }
+                Ok(())
+            }
+            Format::Json => {
+                write!(w, "{{\"changes\":[")?;
+                for (i, c) in changes.iter().enumerate() {
+                    write!(w, "{}", if i > 0 { ",\n" } else { "\n" })?;
+                    serde_json::to_writer(&mut *w, c)?;
+                }
+                writeln!(w, "\n]}}")
+            }
+            Format::Ndjson => {
+                for c in changes {
+                    serde_json::to_writer(&mut *w, c)?;
+                    writeln!(w)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn print(&self, format: Format) -> io::Result<()> {
+        let w = io::stdout();
+        let mut w = io::BufWriter::new(w.lock());
+        self.write(format, &mut w)?;
+        w.flush()
+    }
+}
+
+fn write_human(w: &mut dyn Write, c: &Change) -> io::Result<()> {
+    match c {
+        Change::Create { link, target } => writeln!(
+            w,
+            "{} {} -> {}",
+            "+".green().bold(),
+            display(link),
+            display(target)
+        ),
+        Change::Replace { link, old, target } => writeln!(
+            w,
+            "{} {} -> {} (was {})",
+            "~".yellow().bold(),
+            display(link),
+            display(target),
+            display(old)
+        ),
+        Change::Remove { link, target } => writeln!(
+            w,
+            "{} {} -> {}",
+            "-".red().bold(),
+            display(link),
+            display(target)
+        ),
+        Change::RemoveDir { dir } => writeln!(w, "{} {}/", "-".red().bold(), display(dir)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff() -> Diff {
+        let mut d = Diff::default();
+        d.push(Change::RemoveDir {
+            dir: PathBuf::from("/gc/b"),
+        });
+        d.push(Change::Create {
+            link: PathBuf::from("/gc/a/11111111111111111111111111111111"),
+            target: PathBuf::from("/nix/store/11111111111111111111111111111111-foo"),
+        });
+        d.push(Change::Replace {
+            link: PathBuf::from("/gc/a/22222222222222222222222222222222"),
+            old: PathBuf::from("/nix/store/22222222222222222222222222222222-old"),
+            target: PathBuf::from("/nix/store/22222222222222222222222222222222-new"),
+        });
+        d
+    }
+
+    #[test]
+    fn write_human_sorted_by_path() {
+        let mut buf = Vec::new();
+        diff().write(Format::Human, &mut buf).unwrap();
+        assert_eq!(
+            "+ /gc/a/11111111111111111111111111111111 -> \
+             /nix/store/11111111111111111111111111111111-foo\n\
+             ~ /gc/a/22222222222222222222222222222222 -> \
+             /nix/store/22222222222222222222222222222222-new \
+             (was /nix/store/22222222222222222222222222222222-old)\n\
+             - /gc/b/\n",
+            String::from_utf8(buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn write_json() {
+        let mut buf = Vec::new();
+        diff().write(Format::Json, &mut buf).unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!("create", doc["changes"][0]["action"]);
+        assert_eq!("remove-dir", doc["changes"][2]["action"]);
+        assert_eq!("/gc/b", doc["changes"][2]["dir"]);
+
+        let mut buf = Vec::new();
+        diff().write(Format::Ndjson, &mut buf).unwrap();
+        let lines: Vec<serde_json::Value> = buf
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(3, lines.len());
+        assert_eq!(
+            "/nix/store/22222222222222222222222222222222-old",
+            lines[1]["old"]
+        );
+    }
+}
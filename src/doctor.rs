@@ -0,0 +1,168 @@
+//! Finds files whose Nix dependencies are gone.
+//!
+//! This is the inverse of registration: instead of protecting referenced store paths, each one is
+//! checked for existence. Files referencing non-existent store paths are most likely broken, e.g.
+//! after an unfortunate garbage collection run.
+
+use crate::output::{display, Output};
+use crate::registry::{GCRootsRx, Register};
+use crate::storecheck::StoreCheck;
+
+use colored::Colorize;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct Doctor {
+    store: PathBuf,
+    check: StoreCheck,
+    /// Missing store paths together with all files referencing them
+    broken: BTreeMap<PathBuf, Vec<PathBuf>>,
+    output: Output,
+}
+
+impl Doctor {
+    /// Creates a doctor which looks for store paths below `store` (e.g., /nix/store).
+    pub fn new(store: &str, output: &Output) -> Self {
+        Doctor {
+            store: PathBuf::from(store),
+            check: StoreCheck::new(store),
+            output: output.clone(),
+            ..Doctor::default()
+        }
+    }
+
+    /// Returns true if at least one file references a non-existent store path.
+    pub fn is_broken(&self) -> bool {
+        !self.broken.is_empty()
+    }
+
+    /// Number of distinct files which reference at least one non-existent store path.
+    fn broken_files(&self) -> usize {
+        let mut files: Vec<&Path> = self
+            .broken
+            .values()
+            .flatten()
+            .map(|p| p.as_path())
+            .collect();
+        files.sort();
+        files.dedup();
+        files.len()
+    }
+
+    /// Lists all missing store paths, each followed by the files referencing it.
+    pub fn write_report(&self, w: &mut dyn Write) -> io::Result<()> {
+        for (storepath, files) in &self.broken {
+            let missing = display(self.store.join(storepath));
+            writeln!(w, "{} missing, referenced by:", missing.purple().bold())?;
+            for f in files {
+                writeln!(w, "    {}", display(f))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints the report to stdout and a short summary to the log.
+    pub fn report(&self) {
+        let w = io::stdout();
+        let mut w = io::BufWriter::new(w.lock());
+        self.write_report(&mut w).ok();
+        if self.is_broken() {
+            warn!(
+                "{} file(s) reference {} non-existent store path(s)",
+                self.broken_files(),
+                self.broken.len()
+            );
+        } else {
+            info!("No references to non-existent store paths found");
+        }
+    }
+}
+
+impl Register for Doctor {
+    fn register_loop(&mut self, rx: GCRootsRx) {
+        for sp in rx {
+            self.output.print_store_paths(&sp);
+            for r in sp.iter_refs() {
+                if !self.check.exists(r) {
+                    self.broken
+                        .entry(r.to_owned())
+                        .or_default()
+                        .push(sp.path().to_owned());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storepaths::{Origin, StorePaths, StoreRef};
+    use crate::tests::{dent, FIXTURES};
+    use std::fs;
+    use std::sync::mpsc::channel;
+    use tempfile::TempDir;
+
+    fn sp(path: &str, refs: &[&str]) -> StorePaths {
+        StorePaths::new(
+            dent(path),
+            refs.iter()
+                .map(|r| StoreRef::new(r, Origin::Text))
+                .collect(),
+            0,
+            None,
+        )
+    }
+
+    #[test]
+    fn should_group_files_by_missing_store_path() {
+        let store = TempDir::new().unwrap();
+        fs::create_dir(store.path().join("11111111111111111111111111111111-foo")).unwrap();
+        let mut doctor = Doctor::new(store.path().to_str().unwrap(), &Output::default());
+        let (tx, rx) = channel::<StorePaths>();
+        tx.send(sp(
+            "dir1/proto-http.la",
+            &[
+                "11111111111111111111111111111111-foo",
+                "22222222222222222222222222222222-bar",
+            ],
+        ))
+        .unwrap();
+        tx.send(sp("dir2/lftp", &["22222222222222222222222222222222-bar"]))
+            .unwrap();
+        tx.send(sp(
+            "dir2/lftp.offset",
+            &["11111111111111111111111111111111-foo"],
+        ))
+        .unwrap();
+        drop(tx);
+        doctor.register_loop(rx);
+
+        assert!(doctor.is_broken());
+        assert_eq!(2, doctor.broken_files());
+        let mut buf = Vec::new();
+        doctor.write_report(&mut buf).unwrap();
+        assert_eq!(
+            format!(
+                "{}/22222222222222222222222222222222-bar missing, referenced by:\n    {}\n    {}\n",
+                store.path().display(),
+                FIXTURES.join("dir1/proto-http.la").display(),
+                FIXTURES.join("dir2/lftp").display()
+            ),
+            String::from_utf8_lossy(&buf)
+        );
+    }
+
+    #[test]
+    fn nothing_missing_should_be_healthy() {
+        let store = TempDir::new().unwrap();
+        let mut doctor = Doctor::new(store.path().to_str().unwrap(), &Output::default());
+        let (tx, rx) = channel::<StorePaths>();
+        tx.send(sp("dir2/lftp", &[])).unwrap();
+        drop(tx);
+        doctor.register_loop(rx);
+        assert!(!doctor.is_broken());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::cachemap;
+use bytesize::ByteSize;
 use std::io;
 use std::path::PathBuf;
 use thiserror::Error;
@@ -6,9 +7,12 @@ use users::uid_t;
 use zip::result::ZipError;
 
 #[derive(Debug, Error)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum UErr {
     #[error("internal: abort directory walk")]
     WalkAbort,
+    #[error("Scan interrupted; partial results have been saved, incomplete subtrees have not been cleaned up")]
+    Interrupted,
     #[error("DirEntry for '{0}' does not contain metadata; cannot process")]
     DentNoMetadata(PathBuf),
     #[error("Cache limit {0} exceeded")]
@@ -19,6 +23,12 @@ pub enum UErr {
     UnknownUser(uid_t),
     #[error("Failed to unpack ZIP archive '{0}': {1}")]
     ZIP(PathBuf, #[source] ZipError),
+    #[error("Failed to unpack tar archive '{0}': {1}")]
+    Tar(PathBuf, #[source] io::Error),
+    #[error("'{0}' decompresses to more than {1}; skipping")]
+    DecompressLimit(PathBuf, ByteSize),
+    #[error("compression ratio exceeds {1}:1 in '{0}'")]
+    CompressionRatio(PathBuf, u64),
     #[error("Cannot determine current user. Who am I?")]
     WhoAmI,
     #[error("startdir must be an absolute path")]
@@ -37,6 +47,10 @@ pub enum UErr {
     LoadCache(PathBuf, #[source] cachemap::Error),
     #[error("Failed to save cache to '{0}'")]
     SaveCache(PathBuf, #[source] cachemap::Error),
+    #[error("Failed to update link manifest '{0}'")]
+    Manifest(PathBuf, #[source] cachemap::Error),
+    #[error("Failed to save checkpoint to '{0}'")]
+    SaveCheckpoint(PathBuf, #[source] cachemap::Error),
     #[error("I/O error")]
     IO(#[from] io::Error),
     #[error("Operating system error")]
@@ -5,36 +5,54 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 
+mod cachecmd;
 mod cachemap;
+mod checkpoint;
+mod diff;
+mod doctor;
 mod errors;
+mod manifest;
 mod output;
+mod policy;
+mod referrers;
 mod registry;
 mod scan;
 mod statistics;
+mod storecheck;
 mod storepaths;
 mod system;
 #[cfg(test)]
 mod tests;
 mod walk;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use bytesize::ByteSize;
+use cachecmd::CacheFile;
+use cachemap::Header;
+use checkpoint::Checkpoint;
+use doctor::Doctor;
 use errors::UErr;
+use fnv::FnvHasher;
 use ignore::overrides::OverrideBuilder;
 use ignore::WalkBuilder;
 use lazy_static::lazy_static;
 use nix::unistd::{geteuid, getuid};
 use output::{p2s, Output};
+use policy::ErrorPolicy;
+use referrers::Referrers;
 use registry::{GCRoots, NullGCRoots, Register};
+use scan::ScanConfig;
 use statistics::Statistics;
 use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::ops::DerefMut;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use storepaths::Cache;
 use structopt::StructOpt;
 use users::os::unix::UserExt;
 
-static STORE: &str = "/nix/store/";
 static GC_PREFIX: &str = "/nix/var/nix/gcroots/per-user";
 static DOTEXCLUDE: &str = ".userscan-ignore";
 
@@ -52,7 +70,7 @@ fn add_dotexclude<U: users::Users>(mut wb: WalkBuilder, u: &U) -> Result<WalkBui
     }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct App {
     opt: Opt,
     output: Output,
@@ -64,13 +82,17 @@ pub struct App {
 impl App {
     /// WalkBuilder configured according to the cmdline arguments
     fn walker(&self) -> Result<WalkBuilder> {
-        let startdir = self.startdir()?;
-        let mut ov = OverrideBuilder::new(&startdir);
+        self.walker_at(&self.startdir()?)
+    }
+
+    /// Like `walker`, but starts at `dir` which must be located below the start dir.
+    fn walker_at(&self, dir: &Path) -> Result<WalkBuilder> {
+        let mut ov = OverrideBuilder::new(self.startdir()?);
         for o in &self.overrides {
             let _ = ov.add(o)?;
         }
 
-        let mut wb = WalkBuilder::new(startdir);
+        let mut wb = WalkBuilder::new(dir);
         wb.parents(false)
             .git_global(false)
             .git_ignore(false)
@@ -86,37 +108,64 @@ impl App {
     }
 
     fn scanner(&self) -> Result<scan::Scanner> {
-        let mut ob = OverrideBuilder::new(&self.opt.startdir);
+        let mut ob = OverrideBuilder::new(self.startarg()?);
         for glob in &self.opt.unzip {
             ob.add(glob)?;
         }
+        let mut tb = OverrideBuilder::new(self.startarg()?);
+        for glob in &self.opt.untar {
+            tb.add(glob)?;
+        }
         let baseline = probes::load::read()?.fifteen;
         let max_load = match self.opt.load_increase {
             inc if inc <= 0.0 => 0.0,
             inc => baseline + inc * num_cpus::get() as f32,
         };
         debug!("Baseline load: {}, limit: {}", baseline, max_load);
-        Ok(scan::Scanner::new(
-            self.opt.quickcheck,
-            ob.build()?,
-            max_load,
-        ))
+        Ok(
+            scan::Scanner::new(self.opt.quickcheck, ob.build()?, max_load)
+                .untar(tb.build()?)
+                .archive_limits(
+                    self.opt.max_nesting,
+                    self.opt.unpack_limit,
+                    self.opt.max_ratio,
+                )
+                .decompress(self.opt.decompress, self.opt.decompress_limit)
+                .elf(self.opt.elf || self.opt.elf_only, self.opt.elf_only)
+                .store_dir(&self.opt.store_dir)
+                .lenient(self.opt.lenient),
+        )
     }
 
     fn gcroots(&self) -> Result<Box<dyn Register>> {
-        if self.opt.register {
-            Ok(Box::new(GCRoots::new(
-                GC_PREFIX,
-                self.startdir()?,
-                &self.output,
-            )?))
+        if self.opt.register || self.opt.dry_run {
+            Ok(Box::new(
+                GCRoots::new(
+                    GC_PREFIX,
+                    &self.opt.store_dir,
+                    self.startdir()?,
+                    &self.output,
+                )?
+                .check_store(!self.opt.no_store_check)
+                .dry_run(self.opt.dry_run),
+            ))
         } else {
             Ok(Box::new(NullGCRoots::new(&self.output)))
         }
     }
 
     fn cache(&self) -> Result<Cache, errors::UErr> {
-        let cache = Cache::new(self.opt.cache_limit);
+        let mut header = Header::new(&self.opt.store_dir, self.fingerprint());
+        // cache subcommands may be run without start dir
+        if let Some(dir) = self.opt.startdir.as_deref() {
+            if let Ok(meta) = fs::metadata(dir) {
+                header = header.with_root(&meta);
+            }
+        }
+        let cache = Cache::new(self.opt.cache_limit)
+            .with_paths(self.opt.cache_paths)
+            .with_header(header)
+            .with_config(self.scan_config());
         if let Some(ref f) = self.opt.cache {
             cache.open(f, &self.exectx)
         } else {
@@ -124,18 +173,88 @@ impl App {
         }
     }
 
+    /// Scanner options which influence scan results.
+    fn scan_config(&self) -> ScanConfig {
+        let o = &self.opt;
+        ScanConfig {
+            quickcheck: o.quickcheck.as_u64(),
+            unzip: o.unzip.iter().cloned().collect(),
+            untar: o.untar.iter().cloned().collect(),
+            max_nesting: o.max_nesting,
+            unpack_limit: o.unpack_limit.as_u64(),
+            max_ratio: o.max_ratio,
+            decompress: if o.decompress {
+                Some(o.decompress_limit.as_u64())
+            } else {
+                None
+            },
+            text: !o.elf_only,
+            elf: o.elf || o.elf_only,
+            lenient: o.lenient,
+        }
+    }
+
+    /// Identifies the scanner configuration. Recorded in the cache file header for reference.
+    fn fingerprint(&self) -> u64 {
+        let mut h = FnvHasher::default();
+        self.scan_config().hash(&mut h);
+        h.finish()
+    }
+
+    /// Checkpoint file of a time-budgeted scan, if progress is to be recorded.
+    fn checkpoint_file(&self) -> Option<PathBuf> {
+        match (self.register, &self.opt.cache) {
+            (true, Some(cache)) => Some(Checkpoint::filename(cache)),
+            _ => None,
+        }
+    }
+
+    /// Loads the checkpoint if this is a time-budgeted scan.
+    fn checkpoint(&self) -> Result<Option<Checkpoint>> {
+        let file = match (self.opt.max_runtime, self.checkpoint_file()) {
+            (Some(_), Some(file)) => file,
+            _ => return Ok(None),
+        };
+        let startdir = self.startdir()?;
+        let cp = self.exectx.with_dropped_privileges(|| -> Result<_, UErr> {
+            Ok(Checkpoint::load(&file, &startdir, self.fingerprint()))
+        })?;
+        Ok(Some(cp))
+    }
+
+    /// Point in time when a time-budgeted scan should stop.
+    fn deadline(&self) -> Result<Option<Instant>> {
+        match self.opt.max_runtime {
+            Some(d) => Ok(Some(Instant::now() + d.to_std()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        ErrorPolicy::new(&self.opt.on_error)
+    }
+
     fn statistics(&self) -> Statistics {
         Statistics::new(self.opt.statistics, self.output.list)
     }
 
-    /// Normalized directory where scanning starts.
+    /// Start directory as given on the command line.
     ///
-    /// Don't use this for user messages, they should print out `self.opt.startdir` instead.
-    fn startdir(&self) -> Result<PathBuf> {
+    /// Only optional for subcommands, of which only `cache` works without it.
+    fn startarg(&self) -> Result<&Path> {
         self.opt
             .startdir
-            .canonicalize()
-            .with_context(|| format!("start dir {} is not accessible", p2s(&self.opt.startdir)))
+            .as_deref()
+            .ok_or_else(|| anyhow!("DIRECTORY is required for scanning"))
+    }
+
+    /// Normalized directory where scanning starts.
+    ///
+    /// Don't use this for user messages, they should print out `self.startarg()` instead.
+    fn startdir(&self) -> Result<PathBuf> {
+        let dir = self.startarg()?;
+        dir.canonicalize()
+            .with_context(|| format!("start dir {} is not accessible", p2s(dir)))
     }
 
     /// The Metadata entry of the start directory.
@@ -145,10 +264,156 @@ impl App {
         fs::metadata(self.startdir()?).map_err(|e| e.into())
     }
 
+    /// Reports files which reference non-existent store paths instead of registering.
+    fn doctor(&self) -> Result<i32> {
+        let mut doctor = Doctor::new(&self.opt.store_dir, &self.output);
+        let softerrors = walk::spawn_threads(self, &mut doctor)?.softerrors();
+        doctor.report();
+        match (doctor.is_broken(), softerrors) {
+            (true, _) => Ok(3),
+            (false, 0) => Ok(0),
+            _ => Ok(1),
+        }
+    }
+
+    /// Groups found references by store path instead of registering.
+    ///
+    /// If `storepaths` is given, only files referencing one of them are reported.
+    fn referrers(&self, storepaths: Option<&[String]>) -> Result<i32> {
+        let mut referrers = Referrers::new(&self.output);
+        if let Some(storepaths) = storepaths {
+            referrers = referrers.only(self.wanted(storepaths)?);
+        }
+        let softerrors = walk::spawn_threads(self, &mut referrers)?.softerrors();
+        referrers.report();
+        match softerrors {
+            0 => Ok(0),
+            _ => Ok(1),
+        }
+    }
+
+    /// Store path arguments reduced to their base names.
+    fn wanted(&self, storepaths: &[String]) -> Result<Vec<PathBuf>> {
+        storepaths
+            .iter()
+            .map(|arg| {
+                referrers::parse_storepath(arg, &self.opt.store_dir)
+                    .ok_or_else(|| anyhow!("{} is not a path into {}", arg, self.opt.store_dir))
+            })
+            .collect()
+    }
+
+    /// Opens an existing cache file for inspection.
+    fn existing_cache(&self) -> Result<Cache> {
+        let file = self
+            .opt
+            .cache
+            .as_ref()
+            .ok_or_else(|| anyhow!("cache commands require -c/--cache FILE"))?;
+        if !file.exists() {
+            return Err(anyhow!("cache file {} does not exist", p2s(file)));
+        }
+        Ok(self.cache()?)
+    }
+
+    /// Lists files and their references straight from the cache without rescanning.
+    ///
+    /// If `storepaths` are given, reports only files which reference one of them.
+    fn cache_query(&self, storepaths: &[String]) -> Result<i32> {
+        let cache = self.existing_cache()?;
+        let (files, unknown) = cache.files();
+        if unknown > 0 {
+            warn!(
+                "{} cache entries without recorded path (use --cache-paths when scanning)",
+                unknown
+            );
+        }
+        if !storepaths.is_empty() {
+            let mut referrers = Referrers::new(&self.output).only(self.wanted(storepaths)?);
+            for (path, refs) in &files {
+                referrers.add(path, refs.iter().map(|r| r.path.as_path()));
+            }
+            referrers.report();
+            return Ok(0);
+        }
+        let mut output = self.output.clone();
+        let w = io::stdout();
+        let mut w = io::BufWriter::new(w.lock());
+        output.write_header(&mut w)?;
+        for (path, refs) in files.iter().filter(|(_, refs)| !refs.is_empty()) {
+            output.write_cached(&mut w, path, refs)?;
+        }
+        output.write_footer(&mut w)?;
+        Ok(0)
+    }
+
+    /// Opens the cache file given with --cache for maintenance.
+    fn cache_file(&self) -> Result<CacheFile> {
+        let file = self
+            .opt
+            .cache
+            .as_ref()
+            .ok_or_else(|| anyhow!("cache commands require -c/--cache FILE"))?;
+        Ok(CacheFile::open(file, &self.exectx)?)
+    }
+
+    /// Inspects or maintains the cache file.
+    fn cache_cmd(&self, cmd: &CacheCommand) -> Result<i32> {
+        let w = io::stdout();
+        let mut w = io::BufWriter::new(w.lock());
+        match cmd {
+            CacheCommand::Query { storepaths } => {
+                drop(w);
+                return self.cache_query(storepaths);
+            }
+            CacheCommand::Dump => {
+                cachecmd::dump(&self.cache_file()?.map()?, self.output.format, &mut w)?
+            }
+            CacheCommand::Stats => {
+                let mut cf = self.cache_file()?;
+                let stats = cf.map()?.stats();
+                cachecmd::write_stats(cf.header(), &stats, cf.size()?, self.output.format, &mut w)?
+            }
+            CacheCommand::Verify => {
+                let mut cf = self.cache_file()?;
+                let map = cf.map()?;
+                writeln!(w, "{}: OK, {} entries", p2s(cf.filename()), map.len())?;
+            }
+            CacheCommand::Prune { older_than } => {
+                let mut cf = self.cache_file()?;
+                let mut map = cf.map()?;
+                let total = map.len();
+                let removed = map.prune((chrono::Utc::now() - *older_than).timestamp());
+                if removed > 0 {
+                    cf.save(&map, &self.exectx)?;
+                }
+                writeln!(
+                    w,
+                    "{}: removed {} of {} entries",
+                    p2s(cf.filename()),
+                    removed,
+                    total
+                )?;
+            }
+        }
+        Ok(0)
+    }
+
     /// Main entry point
     pub fn run(&self) -> Result<i32> {
         self.output.log_init();
         debug!("uid: {}, euid: {}", getuid(), geteuid());
+        if self.opt.doctor {
+            return self.doctor();
+        }
+        match self.opt.cmd {
+            Some(Command::Why { ref storepaths }) => return self.referrers(Some(storepaths)),
+            Some(Command::Cache(ref cmd)) => return self.cache_cmd(cmd),
+            None => (),
+        }
+        if self.opt.by_storepath {
+            return self.referrers(None);
+        }
         match walk::spawn_threads(self, self.gcroots()?.deref_mut())?.softerrors() {
             0 => Ok(0),
             _ => Ok(1),
@@ -162,13 +427,14 @@ impl From<Opt> for App {
         let mut overrides = vec![];
         overrides.extend(opt.exclude.iter().map(|e| format!("!{}", e)));
         overrides.extend(opt.include.iter().map(|i| i.to_owned()));
-        let register = opt.register || !opt.list;
+        let register =
+            !opt.doctor && !opt.by_storepath && opt.cmd.is_none() && (opt.register || !opt.list);
         App {
             opt,
             output,
             overrides,
             register,
-            ..Self::default()
+            exectx: system::ExecutionContext::default(),
         }
     }
 }
@@ -186,15 +452,45 @@ fn parse_kb(arg: &str) -> Result<ByteSize> {
     Ok(ByteSize::kib(n))
 }
 
-#[derive(StructOpt, Debug, Clone, Default)]
+/// Parses durations like "90s", "30m", "12h" or "7d". Plain numbers are seconds.
+fn parse_duration(arg: &str) -> Result<chrono::Duration> {
+    let (n, unit) = match arg.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => arg.split_at(i),
+        None => (arg, "s"),
+    };
+    let n: i64 = n
+        .parse()
+        .with_context(|| format!("invalid duration '{}'", arg))?;
+    match unit {
+        "s" => Ok(chrono::Duration::seconds(n)),
+        "m" => Ok(chrono::Duration::minutes(n)),
+        "h" => Ok(chrono::Duration::hours(n)),
+        "d" => Ok(chrono::Duration::days(n)),
+        _ => Err(anyhow!(
+            "unknown unit '{}' in duration (use s, m, h or d)",
+            unit
+        )),
+    }
+}
+
+fn parse_store_dir(arg: &str) -> Result<String> {
+    let dir = arg.trim_end_matches('/');
+    if !dir.starts_with('/') {
+        return Err(anyhow!("store dir must be an absolute path"));
+    }
+    Ok(dir.to_owned())
+}
+
+#[derive(StructOpt, Debug, Clone)]
 #[structopt(
     author = "© Flying Circus Internet Operations GmbH and contributors.",
-    after_help = AFTER_HELP.as_str()
+    after_help = AFTER_HELP.as_str(),
+    setting = clap::AppSettings::SubcommandsNegateReqs
 )]
 struct Opt {
     /// Starts scan in DIRECTORY
-    #[structopt(value_name = "DIRECTORY", parse(from_os_str))]
-    startdir: PathBuf,
+    #[structopt(value_name = "DIRECTORY", parse(from_os_str), set = clap::ArgSettings::Required)]
+    startdir: Option<PathBuf>,
     /// Only prints Nix store references while scanning (doesn't register)
     ///
     /// GC roots are not registered when this option is active. Specify -r/--register in addition
@@ -204,21 +500,76 @@ struct Opt {
     /// Registers references (enabled by default if --list if not given)
     #[structopt(short, long, display_order(2))]
     register: bool,
+    /// Shows which GC roots would be created, replaced and removed (doesn't register)
+    ///
+    /// Performs the full scan and prints the changes to the GC roots instead of carrying them
+    /// out, either as diff or in the format selected with --format. Checkpoints of
+    /// --max-runtime are read, but not updated.
+    #[structopt(short = "n", long, display_order(3),
+                conflicts_with_all(&["list", "doctor", "by_storepath"]))]
+    dry_run: bool,
+    /// Reports files which reference non-existent store paths (doesn't register)
+    ///
+    /// Lists each missing store path together with all files referencing it. Exits with status 3
+    /// if at least one such file has been found.
+    #[structopt(long, display_order(4), conflicts_with = "register")]
+    doctor: bool,
+    /// Lists each referenced store path followed by the files referencing it (doesn't register)
+    ///
+    /// Groups scanning results by store path instead of by file. Use the "why" subcommand to
+    /// restrict the listing to specific store paths.
+    #[structopt(long, display_order(5), conflicts_with_all(&["register", "doctor"]))]
+    by_storepath: bool,
     /// Keeps results between runs in FILE
     ///
     /// Caches scan results in FILE to avoid re-scanning unchanged files. The cache is kept as a
     /// compressed messagepack file.
     #[structopt(short, long, value_name = "FILE", parse(from_os_str))]
     cache: Option<PathBuf>,
+    /// Stops scanning after DURATION and continues in the next run
+    ///
+    /// Records which top-level subdirectories have been walked completely in a checkpoint file
+    /// next to the cache file. The next run skips them and continues with the remaining ones.
+    /// Cleanup of GC roots is restricted to completely walked subdirectories. Accepts durations
+    /// like 90s, 30m or 4h.
+    #[structopt(long, value_name = "DURATION", requires = "cache",
+                parse(try_from_str = parse_duration))]
+    max_runtime: Option<chrono::Duration>,
     /// Limits cache to N entries
     ///
     /// Aborts program execution when trying to store more than N entries in the cache. This helps
     /// to limit memory consumption.
     #[structopt(short = "L", long, value_name = "N")]
     cache_limit: Option<usize>,
+    /// Records file paths in the cache
+    ///
+    /// Makes the cache queryable with the "cache query" subcommand. File paths are stored per
+    /// directory to keep the cache compact.
+    #[structopt(long, requires = "cache")]
+    cache_paths: bool,
     /// Prints each file with references on a single line
     #[structopt(short = "1", long)]
     oneline: bool,
+    /// Terminates each listed file and reference with NUL
+    ///
+    /// Prints file names and references byte-exact, each followed by a NUL character, for safe
+    /// processing with `xargs -0` and similar tools.
+    #[structopt(short = "0", long)]
+    null: bool,
+    /// Shows where each reference was found
+    ///
+    /// Annotates listed references with their origin, e.g. shebang line, ELF interpreter or
+    /// archive member.
+    #[structopt(short = "x", long)]
+    explain: bool,
+    /// Listing format
+    ///
+    /// Selects how found references are listed: "human" (default), "json" (a single document) or
+    /// "ndjson" (one JSON object per line). Machine-readable formats end with a summary record.
+    #[structopt(long, value_name = "FORMAT", default_value = "human",
+                possible_values(&["human", "json", "ndjson"])
+    )]
+    format: String,
     /// Funky colorful output
     ///
     /// Enables colored output. If set to "auto", color is on if run in a terminal.
@@ -272,6 +623,85 @@ struct Opt {
     /// comma-separated list of glob patterns [example: *.zip,*.egg].
     #[structopt(short, long, use_delimiter(true))]
     unzip: Vec<String>,
+    /// Scans inside tar archives for files matching GLOB
+    ///
+    /// Unpacks all files with matching GLOB as tar archives and scans inside, including link
+    /// targets of symlink members. gzip, bzip2 and xz compressed archives are recognized
+    /// automatically. Accepts a comma-separated list of glob patterns [example: *.tar,*.tgz].
+    #[structopt(short = "t", long, value_name = "GLOB", use_delimiter(true))]
+    untar: Vec<String>,
+    /// Unpacks archives nested up to N levels deep
+    ///
+    /// Archive members matching the --unzip or --untar globs are unpacked recursively. Use '0' to
+    /// scan nested archives as plain files.
+    #[structopt(long, default_value = "4", value_name = "N")]
+    max_nesting: usize,
+    /// Unpacks at most SIZE kB from each archive
+    ///
+    /// Applies to the total of all members including nested archives. Further members are skipped
+    /// and reported as soft error. Use '0' to disable.
+    #[structopt(long, default_value = "1048576", value_name = "SIZE",
+                parse(try_from_str = parse_kb))]
+    unpack_limit: ByteSize,
+    /// Stops unpacking archives which decompress to more than R times their size
+    ///
+    /// Protects against ZIP bombs. Data decompressed from nested archives counts as well. The
+    /// rest of the archive is skipped and reported as soft error. Use '0' to disable.
+    #[structopt(long, default_value = "250", value_name = "R")]
+    max_ratio: u64,
+    /// Scans inside gzip, bzip2 and xz compressed files
+    ///
+    /// Compressed files are recognized by their contents, not by their names. They are unpacked
+    /// on the fly and the decompressed data is scanned for Nix store references.
+    #[structopt(short = "Z", long)]
+    decompress: bool,
+    /// Skips compressed files which unpack to more than SIZE kB
+    ///
+    /// Protects against excessive resource usage while decompressing. Use '0' to disable.
+    #[structopt(long, default_value = "102400", value_name = "SIZE",
+                parse(try_from_str = parse_kb))]
+    decompress_limit: ByteSize,
+    /// Extracts references from ELF headers
+    ///
+    /// Reads the program interpreter and the RPATH, RUNPATH and NEEDED entries of ELF binaries in
+    /// addition to the normal scan.
+    #[structopt(long)]
+    elf: bool,
+    /// Skips the full scan of ELF binaries if references are found in their headers
+    ///
+    /// Speeds up scanning large binaries considerably, but misses references which are only
+    /// present as strings in the binary. Implies --elf.
+    #[structopt(long)]
+    elf_only: bool,
+    /// Looks for references into the Nix store located at DIR
+    ///
+    /// Drives both scanning and the targets of registered GC roots. Needed for Nix installations
+    /// with a non-default store dir.
+    #[structopt(long, value_name = "DIR", env = "NIX_STORE_DIR", default_value = scan::DEFAULT_STORE_DIR,
+                parse(try_from_str = parse_store_dir))]
+    store_dir: String,
+    /// Registers references to non-existent store paths as well
+    ///
+    /// By default, GC roots are only created for store paths which actually exist. Useful for
+    /// offline scans of foreign trees.
+    #[structopt(long)]
+    no_store_check: bool,
+    /// Accepts store paths which violate Nix' naming rules
+    ///
+    /// By default, candidates whose hash contains characters outside the Nix base32 alphabet or
+    /// whose name is too long or starts with a dot are rejected. This option restores the old
+    /// behaviour of accepting everything that looks roughly like a store path.
+    #[structopt(long)]
+    lenient: bool,
+    /// Handles errors of CLASS according to POLICY
+    ///
+    /// POLICY is one of "abort", "skip-subtree" or "continue". CLASS is one of "permission"
+    /// (access denied), "traversal" (other failures to read directories) or "other" (other
+    /// failures to scan files). Without CLASS, POLICY applies to all classes. May be given
+    /// multiple times, later rules take precedence. Defaults: permission=abort, traversal=abort,
+    /// other=continue.
+    #[structopt(long, value_name = "[CLASS=]POLICY", number_of_values(1))]
+    on_error: Vec<policy::Rule>,
     /// Pauses scanning if the current load1 goes over load15+L
     ///
     /// The baseline is determined at program startup. If there are multiple CPUs present,
@@ -283,14 +713,68 @@ struct Opt {
         value_name = "L"
     )]
     load_increase: f32,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum Command {
+    /// Lists files which keep the given store paths alive (doesn't register)
+    ///
+    /// Scans DIRECTORY and reports all files referencing one of the given store paths. Store
+    /// paths may be given with or without the store dir prefix. Example: fc-userscan ~ why
+    /// /nix/store/q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24
+    Why {
+        /// Store paths to look for
+        #[structopt(value_name = "STOREPATH", required = true)]
+        storepaths: Vec<String>,
+    },
+    /// Inspects the cache file given with -c/--cache
+    Cache(CacheCommand),
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum CacheCommand {
+    /// Lists cached files and their references without rescanning
+    ///
+    /// Needs a cache which has been written with --cache-paths. If STOREPATHs are given, lists
+    /// only files referencing one of them, grouped by store path.
+    Query {
+        /// Store paths to look for
+        #[structopt(value_name = "STOREPATH")]
+        storepaths: Vec<String>,
+    },
+    /// Prints every cache entry as JSON
+    ///
+    /// Emits a single JSON document, or one object per line with --format=ndjson.
+    Dump,
+    /// Prints entry count, distinct store paths, size on disk and estimated memory usage
+    Stats,
+    /// Checks that the cache file can be decompressed and decoded
+    ///
+    /// Reports the offset of the first problem in the decompressed data. Exits with status 2 if
+    /// the cache file is damaged.
+    Verify,
+    /// Removes entries for files which have not been changed in a while
+    Prune {
+        /// Removes entries whose ctime is older than DURATION (e.g., 90d, 12h, 30m, 60s)
+        #[structopt(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+        older_than: chrono::Duration,
+    },
 }
 
 fn main() {
-    let app = App::from(Opt::from_args());
+    let opt = Opt::from_args();
+    let app = App::from(opt);
     match app.run() {
         Err(ref err) => {
             error!("{:#?}", err);
-            std::process::exit(2)
+            match err.downcast_ref::<UErr>() {
+                Some(UErr::Interrupted) => {
+                    std::process::exit(128 + app.exectx.signal().unwrap_or(nix::libc::SIGINT))
+                }
+                _ => std::process::exit(2),
+            }
         }
         Ok(exitcode) => std::process::exit(exitcode),
     }
@@ -327,4 +811,133 @@ pub mod test {
         assert!(a.output.list);
         assert!(a.register);
     }
+
+    #[test]
+    fn store_dir_should_be_normalized() {
+        assert_eq!("/nix/store", app(&[]).opt.store_dir);
+        assert_eq!(
+            "/home/user/.nix/store",
+            app(&["--store-dir", "/home/user/.nix/store/"])
+                .opt
+                .store_dir
+        );
+        assert_eq!(
+            "/nix/store",
+            app(&["--store-dir", "/nix/store"]).output.store_dir
+        );
+        assert!(Opt::from_iter_safe(&["userscan", "--store-dir", "store", "dir"]).is_err());
+    }
+
+    #[test]
+    fn doctor_should_not_register() {
+        let a = app(&["--doctor"]);
+        assert!(a.opt.doctor);
+        assert!(!a.register);
+        assert!(Opt::from_iter_safe(&["userscan", "--doctor", "--register", "dir"]).is_err());
+    }
+
+    #[test]
+    fn why_should_not_register() {
+        let a = App::from(Opt::from_iter(&[
+            "userscan",
+            "-c",
+            "cache",
+            "dir",
+            "why",
+            "/nix/store/11111111111111111111111111111111-foo",
+            "22222222222222222222222222222222-bar",
+        ]));
+        assert_eq!(Some(PathBuf::from("dir")), a.opt.startdir);
+        match a.opt.cmd {
+            Some(Command::Why { ref storepaths }) => assert_eq!(2, storepaths.len()),
+            _ => panic!("why subcommand not recognized"),
+        }
+        assert!(!a.register);
+        assert!(!app(&["--by-storepath"]).register);
+        assert!(Opt::from_iter_safe(&["userscan", "dir", "why"]).is_err());
+        assert!(Opt::from_iter_safe(&["userscan", "--by-storepath", "-r", "dir"]).is_err());
+    }
+
+    #[test]
+    fn cache_query_should_not_need_startdir() {
+        let a = App::from(Opt::from_iter(&[
+            "userscan",
+            "-c",
+            "cache",
+            "cache",
+            "query",
+            "/nix/store/11111111111111111111111111111111-foo",
+        ]));
+        assert!(a.opt.startdir.is_none());
+        match a.opt.cmd {
+            Some(Command::Cache(CacheCommand::Query { ref storepaths })) => {
+                assert_eq!(1, storepaths.len())
+            }
+            _ => panic!("cache query subcommand not recognized"),
+        }
+        assert!(Opt::from_iter_safe(&["userscan", "--cache-paths", "dir"]).is_err());
+        assert!(Opt::from_iter_safe(&["userscan", "-l"]).is_err());
+    }
+
+    #[test]
+    fn cache_query_should_run_without_startdir() {
+        let td = tempfile::TempDir::new().unwrap();
+        let cache = td.path().join("cache");
+        fs::write(&cache, b"").unwrap();
+        let a = App::from(Opt::from_iter(&[
+            "userscan",
+            "-c",
+            cache.to_str().unwrap(),
+            "cache",
+            "query",
+            "/nix/store/11111111111111111111111111111111-foo",
+        ]));
+        match a.opt.cmd {
+            Some(Command::Cache(ref cmd)) => assert_eq!(0, a.cache_cmd(cmd).unwrap()),
+            _ => panic!("cache subcommand not recognized"),
+        }
+    }
+
+    #[test]
+    fn durations_with_units() {
+        assert_eq!(chrono::Duration::seconds(90), parse_duration("90").unwrap());
+        assert_eq!(
+            chrono::Duration::seconds(90),
+            parse_duration("90s").unwrap()
+        );
+        assert_eq!(chrono::Duration::minutes(5), parse_duration("5m").unwrap());
+        assert_eq!(chrono::Duration::hours(12), parse_duration("12h").unwrap());
+        assert_eq!(chrono::Duration::days(30), parse_duration("30d").unwrap());
+        assert!(parse_duration("30w").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(Opt::from_iter_safe(&["userscan", "-c", "f", "cache", "prune"]).is_err());
+    }
+
+    #[test]
+    fn fingerprint_should_reflect_scanner_options() {
+        assert_eq!(app(&[]).fingerprint(), app(&["-l", "-v"]).fingerprint());
+        assert_ne!(app(&[]).fingerprint(), app(&["--elf"]).fingerprint());
+        assert_ne!(app(&[]).fingerprint(), app(&["-q", "1"]).fingerprint());
+    }
+
+    #[test]
+    fn scan_config_should_cover_weaker_options() {
+        let c = app(&["-q", "0", "--elf"]).scan_config();
+        assert!(c.covers(&app(&[]).scan_config()));
+        assert!(!app(&[]).scan_config().covers(&c));
+        assert!(!app(&["--elf-only"])
+            .scan_config()
+            .covers(&app(&[]).scan_config()));
+    }
+
+    #[test]
+    fn decompress_limit_in_kb() {
+        let a = app(&["-Z"]);
+        assert!(a.opt.decompress);
+        assert_eq!(ByteSize::mib(100), a.opt.decompress_limit);
+
+        let a = app(&["--decompress-limit", "64"]);
+        assert!(!a.opt.decompress);
+        assert_eq!(ByteSize::kib(64), a.opt.decompress_limit);
+    }
 }
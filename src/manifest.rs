@@ -0,0 +1,291 @@
+//! Record of the GC links created by fc-userscan.
+//!
+//! The manifest lives in the per-user GC dir and lists all links fc-userscan has created there.
+//! Cleanup only removes links listed in the manifest, so that GC roots placed there by an admin or
+//! another tool are left alone. The file starts with a header line followed by link paths relative
+//! to the per-user GC dir, each terminated by NUL.
+
+use crate::cachemap::{self, open_locked_wait};
+use crate::errors::UErr;
+use crate::output::p2s;
+
+use ignore::WalkBuilder;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// File name of the manifest in the per-user GC dir
+pub const MANIFEST: &str = ".fc-userscan-links";
+
+const HEADER: &[u8] = b"fc-userscan link manifest v1\n";
+
+#[derive(Debug, Default)]
+pub struct Manifest {
+    /// Locked manifest file and its name. Not present for in-memory manifests.
+    file: Option<(fs::File, PathBuf)>,
+    prefix: PathBuf,
+    links: HashSet<PathBuf>,
+    dirty: bool,
+}
+
+impl Manifest {
+    /// Opens and locks the manifest in `prefix`. Waits for concurrent runs which hold the lock.
+    ///
+    /// If there is no manifest yet, all links below `prefix` which have been named after the hash
+    /// of their target in `store` are adopted. These have most likely been created by earlier
+    /// versions which did not keep a manifest. A damaged manifest owns no links at all, so
+    /// existing links are left alone until a clean manifest has been written.
+    ///
+    /// If `prefix` is missing or not writable, the manifest is read like in `read` and never
+    /// saved. Creating links will most likely fail then, which is reported there.
+    pub fn open(prefix: &Path, store: &Path) -> Result<Self, UErr> {
+        let filename = prefix.join(MANIFEST);
+        let err = |e: cachemap::Error| UErr::Manifest(filename.clone(), e);
+        let mut file = match open_locked_wait(&filename) {
+            Ok(file) => file,
+            Err(cachemap::Error::IO(ref e))
+                if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::PermissionDenied) =>
+            {
+                warn!("Cannot open link manifest {}: {}", p2s(&filename), e);
+                return Self::read(prefix, store);
+            }
+            Err(e) => return Err(err(e)),
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|e| err(cachemap::Error::from(e)))?;
+        let mut m = Self::parse(prefix, store, &data, &filename);
+        m.file = Some((file, filename));
+        Ok(m)
+    }
+
+    /// Reads the manifest in `prefix` without creating or locking it. Changes are never saved.
+    pub fn read(prefix: &Path, store: &Path) -> Result<Self, UErr> {
+        let filename = prefix.join(MANIFEST);
+        let data = match fs::read(&filename) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(UErr::Manifest(filename, cachemap::Error::from(e))),
+        };
+        Ok(Self::parse(prefix, store, &data, &filename))
+    }
+
+    /// Empty `data` means that no manifest has been written yet: `open` creates the file when
+    /// locking it.
+    fn parse(prefix: &Path, store: &Path, data: &[u8], filename: &Path) -> Self {
+        let mut m = Manifest {
+            prefix: prefix.to_owned(),
+            ..Self::default()
+        };
+        if data.starts_with(HEADER) {
+            m.links = data[HEADER.len()..]
+                .split(|b| *b == 0)
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| prefix.join(OsStr::from_bytes(entry)))
+                .collect();
+        } else if !data.is_empty() {
+            warn!(
+                "Link manifest {} is damaged, treating all existing links as foreign",
+                p2s(filename)
+            );
+            m.dirty = true;
+        } else {
+            m.adopt(store);
+            m.dirty = true;
+            info!(
+                "Adopted {} existing links into {}",
+                m.links.len(),
+                p2s(filename)
+            );
+        }
+        m
+    }
+
+    /// Takes over all links which look like they have been created by fc-userscan.
+    fn adopt(&mut self, store: &Path) {
+        let links: Vec<PathBuf> = WalkBuilder::new(&self.prefix)
+            .hidden(false)
+            .ignore(false)
+            .build()
+            .filter_map(|res| res.ok())
+            .filter(|dent| match dent.file_type() {
+                Some(ft) => ft.is_symlink() && is_hash_link(dent.path(), store),
+                None => false,
+            })
+            .map(|dent| dent.into_path())
+            .collect();
+        for link in links {
+            self.insert(link);
+        }
+    }
+
+    /// Returns true if `link` has been created by fc-userscan.
+    pub fn owns(&self, link: &Path) -> bool {
+        self.links.contains(link)
+    }
+
+    pub fn insert(&mut self, link: PathBuf) {
+        self.dirty |= self.links.insert(link);
+    }
+
+    pub fn remove(&mut self, link: &Path) {
+        self.dirty |= self.links.remove(link);
+    }
+
+    /// Writes the manifest back if it has been changed. Links which have vanished in the
+    /// meantime are dropped.
+    pub fn save(&mut self) -> Result<(), UErr> {
+        let before = self.links.len();
+        self.links.retain(|l| fs::symlink_metadata(l).is_ok());
+        self.dirty |= self.links.len() != before;
+        let prefix = &self.prefix;
+        let (file, filename) = match self.file {
+            Some((ref mut file, ref filename)) if self.dirty => (file, filename),
+            _ => return Ok(()),
+        };
+        let mut entries: Vec<&[u8]> = self
+            .links
+            .iter()
+            .filter_map(|l| l.strip_prefix(prefix).ok())
+            .map(|l| l.as_os_str().as_bytes())
+            .collect();
+        entries.sort_unstable();
+        cachemap::replace(file, filename, |f| {
+            let mut buf = HEADER.to_vec();
+            for entry in entries {
+                buf.extend_from_slice(entry);
+                buf.push(0);
+            }
+            f.write_all(&buf)
+        })
+        .map_err(|e| UErr::Manifest(filename.clone(), e))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Returns true if `link` is named after the hash part of its target in `store`.
+fn is_hash_link(link: &Path, store: &Path) -> bool {
+    let name = match link.file_name() {
+        Some(name) if name.len() == 32 => name.as_bytes(),
+        _ => return false,
+    };
+    match fs::read_link(link) {
+        Ok(target) => match target.strip_prefix(store) {
+            Ok(storepath) => storepath.as_os_str().as_bytes().starts_with(name),
+            Err(_) => false,
+        },
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const STORE: &str = "/nix/store";
+
+    #[test]
+    fn open_should_tolerate_missing_prefix() {
+        let td = TempDir::new().unwrap();
+        let mut m = Manifest::open(&td.path().join("missing"), Path::new(STORE)).unwrap();
+        m.insert(td.path().join("missing/11111111111111111111111111111111"));
+        m.save().unwrap();
+        assert!(!td.path().join("missing").exists());
+    }
+
+    #[test]
+    fn open_should_adopt_hash_named_links() {
+        let td = TempDir::new().unwrap();
+        let p = td.path();
+        fs::create_dir(p.join("d")).unwrap();
+        let ours = p.join("d/11111111111111111111111111111111");
+        symlink("/nix/store/11111111111111111111111111111111-foo", &ours).unwrap();
+        symlink(
+            "/nix/store/11111111111111111111111111111111-foo",
+            p.join("d/foo"),
+        )
+        .unwrap();
+        symlink("/etc/passwd", p.join("d/22222222222222222222222222222222")).unwrap();
+        let mut m = Manifest::open(p, Path::new(STORE)).unwrap();
+        assert_eq!(vec![&ours], m.links.iter().collect::<Vec<_>>());
+        m.save().unwrap();
+        drop(m);
+
+        // links which appear later are not adopted anymore
+        let later = p.join("33333333333333333333333333333333");
+        symlink("/nix/store/33333333333333333333333333333333-bar", &later).unwrap();
+        let m = Manifest::open(p, Path::new(STORE)).unwrap();
+        assert!(m.owns(&ours));
+        assert!(!m.owns(&later));
+    }
+
+    #[test]
+    fn open_should_wait_for_concurrent_holder() {
+        let td = TempDir::new().unwrap();
+        let m = Manifest::open(td.path(), Path::new(STORE)).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let prefix = td.path().to_owned();
+        let t = thread::spawn(move || {
+            let res = Manifest::open(&prefix, Path::new(STORE)).map(|_| ());
+            tx.send(res.is_ok()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(m);
+        assert!(rx.recv().unwrap());
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn damaged_manifest_should_not_adopt_links() {
+        let td = TempDir::new().unwrap();
+        let p = td.path();
+        let link = p.join("11111111111111111111111111111111");
+        symlink("/nix/store/11111111111111111111111111111111-foo", &link).unwrap();
+        fs::write(p.join(MANIFEST), b"garbage").unwrap();
+        let mut m = Manifest::open(p, Path::new(STORE)).unwrap();
+        assert!(!m.owns(&link));
+        m.save().unwrap();
+        drop(m);
+        assert_eq!(HEADER.to_vec(), fs::read(p.join(MANIFEST)).unwrap());
+    }
+
+    #[test]
+    fn read_should_not_create_manifest() {
+        let td = TempDir::new().unwrap();
+        let p = td.path();
+        let link = p.join("11111111111111111111111111111111");
+        symlink("/nix/store/11111111111111111111111111111111-foo", &link).unwrap();
+        let mut m = Manifest::read(p, Path::new(STORE)).unwrap();
+        assert!(m.owns(&link));
+        m.save().unwrap();
+        assert!(!p.join(MANIFEST).exists());
+    }
+
+    #[test]
+    fn save_should_drop_vanished_links() {
+        let td = TempDir::new().unwrap();
+        let p = td.path();
+        let mut m = Manifest::open(p, Path::new(STORE)).unwrap();
+        let link = p.join("11111111111111111111111111111111");
+        symlink("/nix/store/11111111111111111111111111111111-foo", &link).unwrap();
+        m.insert(link.clone());
+        m.insert(p.join("gone"));
+        m.save().unwrap();
+        drop(m);
+        assert_eq!(
+            b"fc-userscan link manifest v1\n11111111111111111111111111111111\0".to_vec(),
+            fs::read(p.join(MANIFEST)).unwrap()
+        );
+        let m = Manifest::open(p, Path::new(STORE)).unwrap();
+        assert!(m.owns(&link));
+    }
+}
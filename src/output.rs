@@ -1,21 +1,115 @@
-use crate::storepaths::StorePaths;
-use crate::{Opt, STORE};
+use crate::scan::DEFAULT_STORE_DIR;
+use crate::statistics::Summary;
+use crate::storepaths::{group_origins, Origin, StorePaths, StoreRef};
+use crate::Opt;
 
 use atty::{self, Stream};
 use colored::{self, ColoredString, Colorize};
 use env_logger::Builder;
 use log::{Level, LevelFilter};
+use serde::{Serialize, Serializer};
 use std::io;
 use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
+/// Set in NUL-separated output mode: paths are rendered without loss in log messages as well.
+static BYTE_EXACT: AtomicBool = AtomicBool::new(false);
+
+/// Listing formats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Colorful output meant for humans, optionally one line per file
+    Human,
+    /// Single JSON document containing all files and the summary
+    Json,
+    /// One JSON object per line for each file, followed by the summary
+    Ndjson,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Output {
     pub level: LevelFilter,
     pub oneline: bool,
     pub color: bool,
     pub list: bool,
+    pub explain: bool,
+    /// Prefix for listed store references
+    pub store_dir: String,
+    pub format: Format,
+    /// Terminates each file and reference with NUL instead of pretty-printing
+    pub null: bool,
+    /// Number of files listed so far
+    listed: usize,
+}
+
+/// Serializes a path as string if it is valid UTF-8 and as array of bytes otherwise.
+pub struct JsonPath<P: AsRef<Path>>(pub P);
+
+impl<P: AsRef<Path>> Serialize for JsonPath<P> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let path = self.0.as_ref();
+        match path.to_str() {
+            Some(p) => s.serialize_str(p),
+            None => s.serialize_bytes(path.as_os_str().as_bytes()),
+        }
+    }
+}
+
+/// Machine-readable listing of a single file.
+#[derive(Serialize)]
+struct FileRecord<'a> {
+    path: JsonPath<&'a Path>,
+    /// Unknown for files listed from the cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_type: Option<&'static str>,
+    cached: bool,
+    bytes_scanned: u64,
+    refs: Vec<JsonPath<String>>,
+}
+
+impl<'a> FileRecord<'a> {
+    fn new(sp: &'a StorePaths, store_dir: &str) -> Self {
+        let file_type = match sp.file_type() {
+            Some(ft) if ft.is_file() => "file",
+            Some(ft) if ft.is_dir() => "dir",
+            Some(ft) if ft.is_symlink() => "symlink",
+            _ => "other",
+        };
+        FileRecord {
+            path: JsonPath(sp.path()),
+            file_type: Some(file_type),
+            cached: sp.cached(),
+            bytes_scanned: sp.bytes_scanned(),
+            refs: Self::full_refs(sp.iter_refs(), store_dir),
+        }
+    }
+
+    fn from_cache(path: &'a Path, refs: &[StoreRef], store_dir: &str) -> Self {
+        FileRecord {
+            path: JsonPath(path),
+            file_type: None,
+            cached: true,
+            bytes_scanned: 0,
+            refs: Self::full_refs(group_origins(refs).map(|(p, _)| p), store_dir),
+        }
+    }
+
+    fn full_refs<'r, I>(refs: I, store_dir: &str) -> Vec<JsonPath<String>>
+    where
+        I: Iterator<Item = &'r Path>,
+    {
+        refs.map(|r| JsonPath(format!("{}/{}", store_dir, r.display())))
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct SummaryRecord<'a> {
+    summary: &'a Summary,
 }
 
 impl Default for Output {
@@ -25,12 +119,24 @@ impl Default for Output {
             oneline: false,
             color: false,
             list: false,
+            explain: false,
+            store_dir: DEFAULT_STORE_DIR.to_owned(),
+            format: Format::Human,
+            null: false,
+            listed: 0,
         }
     }
 }
 
 impl Output {
-    pub fn new(verbose: bool, debug: bool, oneline: bool, color: &str, list: bool) -> Output {
+    pub fn new(
+        verbose: bool,
+        debug: bool,
+        oneline: bool,
+        color: &str,
+        list: bool,
+        explain: bool,
+    ) -> Output {
         Output {
             level: match (verbose, debug) {
                 (_, true) => LevelFilter::Debug,
@@ -44,11 +150,14 @@ impl Output {
             },
             oneline,
             list,
+            explain,
+            ..Output::default()
         }
     }
 
     /// Sets up logging with colored output if requested.
     pub fn log_init(&self) {
+        BYTE_EXACT.store(self.null, Ordering::Relaxed);
         colored::control::set_override(self.color);
         Builder::new()
             .format(|buf, r| match r.level() {
@@ -64,24 +173,96 @@ impl Output {
     }
 
     /// Outputs the name of a scanned file together with the store paths found inside.
+    pub fn write_store_paths(&mut self, w: &mut dyn Write, sp: &StorePaths) -> io::Result<()> {
+        let res = match self.format {
+            Format::Human if self.null => self.write_null(w, sp.path(), sp.iter_refs()),
+            Format::Human => self.write_human(w, sp.path(), sp.iter_origins()),
+            _ => self.write_record(w, &FileRecord::new(sp, &self.store_dir)),
+        };
+        self.listed += 1;
+        res
+    }
+
+    /// Outputs a file and its references as recorded in the cache.
+    pub fn write_cached(
+        &mut self,
+        w: &mut dyn Write,
+        path: &Path,
+        refs: &[StoreRef],
+    ) -> io::Result<()> {
+        let res = match self.format {
+            Format::Human if self.null => {
+                self.write_null(w, path, group_origins(refs).map(|(p, _)| p))
+            }
+            Format::Human => self.write_human(w, path, group_origins(refs)),
+            _ => self.write_record(w, &FileRecord::from_cache(path, refs, &self.store_dir)),
+        };
+        self.listed += 1;
+        res
+    }
+
+    fn write_record(&self, w: &mut dyn Write, rec: &FileRecord) -> io::Result<()> {
+        if self.format == Format::Json {
+            write!(w, "{}", if self.listed > 0 { ",\n" } else { "\n" })?;
+            serde_json::to_writer(&mut *w, rec).map_err(io::Error::from)
+        } else {
+            serde_json::to_writer(&mut *w, rec)?;
+            writeln!(w)
+        }
+    }
+
+    /// Human-readable listing.
     ///
     /// Depending on the desired output format the files are either space- or newline-separated.
-    pub fn write_store_paths(&self, w: &mut dyn Write, sp: &StorePaths) -> io::Result<()> {
-        let filename = format!(
-            "{}{}",
-            sp.path().display(),
-            if self.oneline { ":" } else { "" }
-        );
+    /// In explain mode, each store path is followed by the places where it has been found.
+    fn write_human<'a, I>(&self, w: &mut dyn Write, path: &Path, refs: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (&'a Path, Vec<&'a Origin>)>,
+    {
+        let filename = format!("{}{}", path.display(), if self.oneline { ":" } else { "" });
         write!(w, "{}", filename.purple().bold())?;
         let sep = if self.oneline { " " } else { "\n" };
-        for r in sp.iter_refs() {
-            write!(w, "{}{}{}", sep, STORE, r.display())?
+        if self.explain {
+            for (r, origins) in refs {
+                let origins: Vec<_> = origins.iter().map(|o| o.to_string()).collect();
+                write!(
+                    w,
+                    "{}{}/{} ({})",
+                    sep,
+                    self.store_dir,
+                    r.display(),
+                    origins.join(", ")
+                )?
+            }
+        } else {
+            for (r, _) in refs {
+                write!(w, "{}{}/{}", sep, self.store_dir, r.display())?
+            }
         }
         writeln!(w, "{}", if self.oneline { "" } else { "\n" })
     }
 
+    /// Byte-exact listing for consumption by `xargs -0` and friends.
+    ///
+    /// The file name and each store path are terminated by NUL. Store paths can be told apart
+    /// from file names since they always start with the store dir.
+    fn write_null<'a, I>(&self, w: &mut dyn Write, path: &Path, refs: I) -> io::Result<()>
+    where
+        I: Iterator<Item = &'a Path>,
+    {
+        w.write_all(path.as_os_str().as_bytes())?;
+        w.write_all(b"\0")?;
+        for r in refs {
+            w.write_all(self.store_dir.as_bytes())?;
+            w.write_all(b"/")?;
+            w.write_all(r.as_os_str().as_bytes())?;
+            w.write_all(b"\0")?;
+        }
+        Ok(())
+    }
+
     #[inline]
-    pub fn print_store_paths(&self, sp: &StorePaths) {
+    pub fn print_store_paths(&mut self, sp: &StorePaths) {
         if !self.list {
             return;
         }
@@ -89,17 +270,111 @@ impl Output {
         let mut w = io::BufWriter::new(w.lock());
         self.write_store_paths(&mut w, sp).ok();
     }
+
+    /// Opens the JSON document which encloses the listing.
+    pub fn write_header(&self, w: &mut dyn Write) -> io::Result<()> {
+        match self.format {
+            Format::Json => write!(w, "{{\"files\":["),
+            _ => Ok(()),
+        }
+    }
+
+    /// Finishes machine-readable listings with a summary record.
+    pub fn write_summary(&self, w: &mut dyn Write, summary: &Summary) -> io::Result<()> {
+        match self.format {
+            Format::Human => Ok(()),
+            Format::Json => {
+                write!(w, "\n],\"summary\":")?;
+                serde_json::to_writer(&mut *w, summary)?;
+                writeln!(w, "}}")
+            }
+            Format::Ndjson => {
+                serde_json::to_writer(&mut *w, &SummaryRecord { summary })?;
+                writeln!(w)
+            }
+        }
+    }
+
+    /// Closes the JSON document if there is no summary to write.
+    pub fn write_footer(&self, w: &mut dyn Write) -> io::Result<()> {
+        match self.format {
+            Format::Json => writeln!(w, "\n]}}"),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn print_header(&self) {
+        if self.list {
+            self.write_header(&mut io::stdout()).ok();
+        }
+    }
+
+    pub fn print_summary(&self, summary: &Summary) {
+        if self.list {
+            self.write_summary(&mut io::stdout(), summary).ok();
+        }
+    }
 }
 
 impl<'a> From<&'a Opt> for Output {
     fn from(opt: &'a Opt) -> Self {
-        Output::new(opt.verbose, opt.debug, opt.oneline, &opt.color, opt.list)
+        Output {
+            store_dir: opt.store_dir.clone(),
+            format: match opt.format.as_str() {
+                "json" => Format::Json,
+                "ndjson" => Format::Ndjson,
+                _ => Format::Human,
+            },
+            null: opt.null,
+            ..Output::new(
+                opt.verbose,
+                opt.debug,
+                opt.oneline,
+                &opt.color,
+                opt.list,
+                opt.explain,
+            )
+        }
     }
 }
 
 /// Path to String with coloring
 pub fn p2s<P: AsRef<Path>>(path: P) -> ColoredString {
-    path.as_ref().display().to_string().green()
+    display(path).green()
+}
+
+/// Path to String
+///
+/// Non-UTF-8 paths are converted lossily by default. In NUL-separated output mode, invalid bytes
+/// are escaped as `\xNN` (and backslashes as `\\`) instead so that no information gets lost.
+pub fn display<P: AsRef<Path>>(path: P) -> String {
+    let path = path.as_ref();
+    if BYTE_EXACT.load(Ordering::Relaxed) {
+        escape_bytes(path.as_os_str().as_bytes())
+    } else {
+        path.display().to_string()
+    }
+}
+
+fn escape_bytes(mut bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+    loop {
+        match str::from_utf8(bytes) {
+            Ok(valid) => {
+                s.push_str(&valid.replace('\\', "\\\\"));
+                return s;
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                s.push_str(&str::from_utf8(valid).unwrap().replace('\\', "\\\\"));
+                let invalid = e.error_len().unwrap_or(rest.len());
+                for b in &rest[..invalid] {
+                    s.push_str(&format!("\\x{:02x}", b));
+                }
+                bytes = &rest[invalid..];
+            }
+        }
+    }
 }
 
 /// Duration to seconds
@@ -112,13 +387,189 @@ pub fn d2s(d: Duration) -> f32 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storepaths::{Origin, StoreRef};
+    use crate::tests::{dent, FIXTURES};
+    use std::ffi::OsStr;
 
     #[test]
     fn color_default_argument() {
-        let o = Output::new(false, false, false, "never", false);
+        let o = Output::new(false, false, false, "never", false, false);
         assert!(!o.color);
 
-        let o = Output::new(false, false, false, "always", false);
+        let o = Output::new(false, false, false, "always", false, false);
         assert!(o.color);
     }
+
+    #[test]
+    fn explain_should_list_origins() {
+        let sp = StorePaths::new(
+            dent("dir2/lftp"),
+            vec![
+                StoreRef::new(
+                    "q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24",
+                    Origin::ElfInterpreter,
+                ),
+                StoreRef::new("q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24", Origin::Text),
+            ],
+            0,
+            None,
+        );
+        let mut o = Output {
+            oneline: true,
+            ..Output::default()
+        };
+        let mut buf = Vec::new();
+        o.write_store_paths(&mut buf, &sp).unwrap();
+        assert!(String::from_utf8_lossy(&buf)
+            .ends_with(": /nix/store/q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24\n"));
+
+        o.explain = true;
+        buf.clear();
+        o.write_store_paths(&mut buf, &sp).unwrap();
+        assert!(String::from_utf8_lossy(&buf).ends_with(
+            ": /nix/store/q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24 (ELF interpreter, text)\n"
+        ));
+
+        o.explain = false;
+        o.store_dir = "/opt/nix/store".to_owned();
+        buf.clear();
+        o.write_store_paths(&mut buf, &sp).unwrap();
+        assert!(String::from_utf8_lossy(&buf)
+            .ends_with(": /opt/nix/store/q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24\n"));
+    }
+
+    fn summary() -> Summary {
+        Summary {
+            files: 2,
+            bytes_read: 3,
+            files_decompressed: 0,
+            bytes_decompressed: 0,
+            softerrors: 0,
+            rejected: 0,
+            skipped: vec![],
+            elapsed: 0.5,
+        }
+    }
+
+    fn sp_lftp() -> StorePaths {
+        StorePaths::new(
+            dent("dir2/lftp"),
+            vec![StoreRef::new(
+                "q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24",
+                Origin::Text,
+            )],
+            1000,
+            None,
+        )
+    }
+
+    #[test]
+    fn ndjson_should_emit_one_object_per_file() {
+        let mut o = Output {
+            format: Format::Ndjson,
+            ..Output::default()
+        };
+        let mut buf = Vec::new();
+        o.write_header(&mut buf).unwrap();
+        o.write_store_paths(&mut buf, &sp_lftp()).unwrap();
+        o.write_store_paths(&mut buf, &sp_lftp()).unwrap();
+        o.write_summary(&mut buf, &summary()).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(buf)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(3, lines.len());
+        assert_eq!(
+            serde_json::json!({
+                "path": FIXTURES.join("dir2/lftp").to_str().unwrap(),
+                "file_type": "file",
+                "cached": false,
+                "bytes_scanned": 1000,
+                "refs": ["/nix/store/q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24"],
+            }),
+            lines[0]
+        );
+        assert_eq!(2, lines[2]["summary"]["files"]);
+    }
+
+    #[test]
+    fn json_should_emit_single_document() {
+        let mut o = Output {
+            format: Format::Json,
+            ..Output::default()
+        };
+        let mut buf = Vec::new();
+        o.write_header(&mut buf).unwrap();
+        o.write_store_paths(&mut buf, &sp_lftp()).unwrap();
+        o.write_store_paths(&mut buf, &sp_lftp()).unwrap();
+        o.write_summary(&mut buf, &summary()).unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(2, doc["files"].as_array().unwrap().len());
+        assert_eq!(3, doc["summary"]["bytes_read"]);
+
+        // empty listing
+        let o = Output {
+            format: Format::Json,
+            ..Output::default()
+        };
+        let mut buf = Vec::new();
+        o.write_header(&mut buf).unwrap();
+        o.write_summary(&mut buf, &summary()).unwrap();
+        let doc: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert!(doc["files"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn json_paths_should_be_bytes_safe() {
+        let p = Path::new(OsStr::from_bytes(b"caf\xe9"));
+        assert_eq!(
+            "[99,97,102,233]",
+            serde_json::to_string(&JsonPath(p)).unwrap()
+        );
+        assert_eq!(
+            "\"caf\u{e9}\"",
+            serde_json::to_string(&JsonPath(Path::new("caf\u{e9}"))).unwrap()
+        );
+    }
+
+    #[test]
+    fn null_should_terminate_each_item() {
+        let td = tempfile::TempDir::new().unwrap();
+        let f = td.path().join(OsStr::from_bytes(b"caf\xe9 1"));
+        std::fs::write(&f, "").unwrap();
+        let sp = StorePaths::new(
+            dent(&f),
+            vec![
+                StoreRef::new("q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24", Origin::Text),
+                StoreRef::new(
+                    "q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24",
+                    Origin::Symlink,
+                ),
+                StoreRef::new("zzwx1gab2ysnk5nyvyyg56ana2v4r2ar-foo", Origin::Text),
+            ],
+            0,
+            None,
+        );
+        let mut o = Output {
+            null: true,
+            oneline: true,
+            ..Output::default()
+        };
+        let mut buf = Vec::new();
+        o.write_store_paths(&mut buf, &sp).unwrap();
+        let mut expected = f.as_os_str().as_bytes().to_vec();
+        expected.extend_from_slice(
+            b"\0/nix/store/q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24\0\
+              /nix/store/zzwx1gab2ysnk5nyvyyg56ana2v4r2ar-foo\0",
+        );
+        assert_eq!(expected, buf);
+    }
+
+    #[test]
+    fn escape_should_preserve_invalid_bytes() {
+        assert_eq!("/tmp/caf\\xe9", escape_bytes(b"/tmp/caf\xe9"));
+        assert_eq!("a\\\\xe9\u{e9}", escape_bytes("a\\xe9\u{e9}".as_bytes()));
+        assert_eq!("\\xff\\xfe", escape_bytes(b"\xff\xfe"));
+    }
 }
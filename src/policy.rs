@@ -0,0 +1,206 @@
+//! What to do about errors encountered during the directory walk.
+
+use crate::output::JsonPath;
+
+use anyhow::{anyhow, Error, Result};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Kinds of errors which are handled according to a configurable policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Access to a file or directory has been denied
+    Permission,
+    /// Any other failure to read a directory
+    Traversal,
+    /// Any other failure to scan a file
+    Other,
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ErrorClass::Permission => "permission",
+            ErrorClass::Traversal => "traversal",
+            ErrorClass::Other => "other",
+        })
+    }
+}
+
+impl FromStr for ErrorClass {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "permission" => Ok(ErrorClass::Permission),
+            "traversal" => Ok(ErrorClass::Traversal),
+            "other" => Ok(ErrorClass::Other),
+            _ => Err(anyhow!(
+                "unknown error class '{}' (use permission, traversal or other)",
+                s
+            )),
+        }
+    }
+}
+
+impl Serialize for ErrorClass {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Stops the whole run with a hard error
+    Abort,
+    /// Leaves out the offending file or directory including everything below it
+    SkipSubtree,
+    /// Logs the error and carries on as far as possible
+    Continue,
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "abort" => Ok(Policy::Abort),
+            "skip-subtree" => Ok(Policy::SkipSubtree),
+            "continue" => Ok(Policy::Continue),
+            _ => Err(anyhow!(
+                "unknown error policy '{}' (use abort, skip-subtree or continue)",
+                s
+            )),
+        }
+    }
+}
+
+/// Single --on-error argument of the form [CLASS=]POLICY
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// Applies to all classes if not given
+    class: Option<ErrorClass>,
+    policy: Policy,
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.find('=') {
+            Some(i) => Ok(Rule {
+                class: Some(s[..i].parse()?),
+                policy: s[i + 1..].parse()?,
+            }),
+            None => Ok(Rule {
+                class: None,
+                policy: s.parse()?,
+            }),
+        }
+    }
+}
+
+/// Policy for each error class
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorPolicy {
+    permission: Policy,
+    traversal: Policy,
+    other: Policy,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self {
+            permission: Policy::Abort,
+            traversal: Policy::Abort,
+            other: Policy::Continue,
+        }
+    }
+}
+
+impl ErrorPolicy {
+    /// Applies `rules` in order on top of the defaults.
+    pub fn new(rules: &[Rule]) -> Self {
+        let mut ep = Self::default();
+        for rule in rules {
+            match rule.class {
+                Some(class) => *ep.policy_mut(class) = rule.policy,
+                None => {
+                    ep.permission = rule.policy;
+                    ep.traversal = rule.policy;
+                    ep.other = rule.policy;
+                }
+            }
+        }
+        ep
+    }
+
+    fn policy_mut(&mut self, class: ErrorClass) -> &mut Policy {
+        match class {
+            ErrorClass::Permission => &mut self.permission,
+            ErrorClass::Traversal => &mut self.traversal,
+            ErrorClass::Other => &mut self.other,
+        }
+    }
+
+    pub fn get(&self, class: ErrorClass) -> Policy {
+        match class {
+            ErrorClass::Permission => self.permission,
+            ErrorClass::Traversal => self.traversal,
+            ErrorClass::Other => self.other,
+        }
+    }
+}
+
+/// Path which has been left out due to an error
+#[derive(Debug, Clone, PartialEq)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub class: ErrorClass,
+    /// Set if `path` is a directory whose contents have not been walked
+    pub subtree: bool,
+}
+
+impl Serialize for Skipped {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut st = s.serialize_struct("Skipped", 3)?;
+        st.serialize_field("path", &JsonPath(&self.path))?;
+        st.serialize_field("class", &self.class)?;
+        st.serialize_field("subtree", &self.subtree)?;
+        st.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_should_override_defaults() {
+        let rules: Vec<Rule> = ["skip-subtree", "other=abort"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        let ep = ErrorPolicy::new(&rules);
+        assert_eq!(Policy::SkipSubtree, ep.get(ErrorClass::Permission));
+        assert_eq!(Policy::SkipSubtree, ep.get(ErrorClass::Traversal));
+        assert_eq!(Policy::Abort, ep.get(ErrorClass::Other));
+        assert_eq!(ErrorPolicy::default(), ErrorPolicy::new(&[]));
+    }
+
+    #[test]
+    fn parse_rule() {
+        assert_eq!(
+            Rule {
+                class: Some(ErrorClass::Permission),
+                policy: Policy::Continue
+            },
+            "permission=continue".parse().unwrap()
+        );
+        assert!("permission=ignore".parse::<Rule>().is_err());
+        assert!("perms=abort".parse::<Rule>().is_err());
+        assert!("".parse::<Rule>().is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Reverse index: which files reference a given store path.
+//!
+//! Scanning results are grouped by referenced store path instead of by file. This answers the
+//! question why a store path refuses to be garbage collected.
+
+use crate::output::{display, Format, JsonPath, Output};
+use crate::registry::{GCRootsRx, Register};
+
+use colored::Colorize;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct Referrers {
+    /// If set, only these store paths are reported
+    wanted: Option<BTreeSet<PathBuf>>,
+    /// Store paths together with all files referencing them
+    files: BTreeMap<PathBuf, Vec<PathBuf>>,
+    output: Output,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    storepath: JsonPath<String>,
+    files: Vec<JsonPath<&'a Path>>,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    storepaths: Vec<Record<'a>>,
+}
+
+/// Reduces `arg` to the store path's base name, e.g. /nix/store/HASH-foo/bin/foo to HASH-foo.
+///
+/// Absolute paths must be located inside `store_dir`.
+pub fn parse_storepath(arg: &str, store_dir: &str) -> Option<PathBuf> {
+    let p = Path::new(arg);
+    let rel = if p.is_absolute() {
+        p.strip_prefix(store_dir).ok()?
+    } else {
+        p
+    };
+    match rel.components().next() {
+        Some(Component::Normal(name)) => Some(PathBuf::from(name)),
+        _ => None,
+    }
+}
+
+impl Referrers {
+    /// Creates a reverse index over all referenced store paths.
+    pub fn new(output: &Output) -> Self {
+        Referrers {
+            output: output.clone(),
+            ..Referrers::default()
+        }
+    }
+
+    /// Restricts the reverse index to `storepaths` (base names relative to the store dir).
+    pub fn only<I: IntoIterator<Item = PathBuf>>(mut self, storepaths: I) -> Self {
+        self.wanted = Some(storepaths.into_iter().collect());
+        self
+    }
+
+    /// Wanted store paths which are not referenced by any file.
+    pub fn unreferenced(&self) -> Vec<&Path> {
+        match self.wanted {
+            Some(ref wanted) => wanted
+                .iter()
+                .filter(|p| !self.files.contains_key(*p))
+                .map(|p| p.as_path())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Records that `file` references `refs`.
+    pub fn add<'a, I: IntoIterator<Item = &'a Path>>(&mut self, file: &Path, refs: I) {
+        for r in refs {
+            if let Some(ref wanted) = self.wanted {
+                if !wanted.contains(r) {
+                    continue;
+                }
+            }
+            let files = self.files.entry(r.to_owned()).or_default();
+            if files.last().map(|f| f.as_path()) != Some(file) {
+                files.push(file.to_owned());
+            }
+        }
+    }
+
+    fn storepath(&self, p: &Path) -> PathBuf {
+        Path::new(&self.output.store_dir).join(p)
+    }
+
+    /// Lists each store path followed by the files referencing it.
+    pub fn write_report(&self, w: &mut dyn Write) -> io::Result<()> {
+        let records = || {
+            self.files.iter().map(move |(sp, files)| Record {
+                storepath: JsonPath(format!("{}/{}", self.output.store_dir, sp.display())),
+                files: files.iter().map(|f| JsonPath(f.as_path())).collect(),
+            })
+        };
+        match self.output.format {
+            Format::Human if self.output.null => {
+                for (sp, files) in &self.files {
+                    w.write_all(self.storepath(sp).as_os_str().as_bytes())?;
+                    w.write_all(b"\0")?;
+                    for f in files {
+                        w.write_all(f.as_os_str().as_bytes())?;
+                        w.write_all(b"\0")?;
+                    }
+                }
+            }
+            Format::Human => {
+                for (sp, files) in &self.files {
+                    let sp = display(self.storepath(sp));
+                    writeln!(w, "{}", sp.purple().bold())?;
+                    for f in files {
+                        writeln!(w, "    {}", display(f))?;
+                    }
+                }
+            }
+            Format::Json => {
+                serde_json::to_writer(
+                    &mut *w,
+                    &Document {
+                        storepaths: records().collect(),
+                    },
+                )?;
+                writeln!(w)?;
+            }
+            Format::Ndjson => {
+                for r in records() {
+                    serde_json::to_writer(&mut *w, &r)?;
+                    writeln!(w)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints the report to stdout.
+    pub fn report(&self) {
+        let w = io::stdout();
+        let mut w = io::BufWriter::new(w.lock());
+        self.write_report(&mut w).ok();
+        for p in self.unreferenced() {
+            info!("No files reference {}", display(self.storepath(p)));
+        }
+    }
+}
+
+impl Register for Referrers {
+    fn register_loop(&mut self, rx: GCRootsRx) {
+        for sp in rx {
+            self.output.print_store_paths(&sp);
+            self.add(sp.path(), sp.iter_refs());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storepaths::{Origin, StorePaths, StoreRef};
+    use crate::tests::{dent, FIXTURES};
+    use std::sync::mpsc::channel;
+
+    fn sp(path: &str, refs: &[&str]) -> StorePaths {
+        StorePaths::new(
+            dent(path),
+            refs.iter()
+                .map(|r| StoreRef::new(r, Origin::Text))
+                .collect(),
+            0,
+            None,
+        )
+    }
+
+    fn run(mut referrers: Referrers) -> Referrers {
+        let (tx, rx) = channel::<StorePaths>();
+        tx.send(sp(
+            "dir1/proto-http.la",
+            &[
+                "11111111111111111111111111111111-foo",
+                "22222222222222222222222222222222-bar",
+            ],
+        ))
+        .unwrap();
+        tx.send(sp("dir2/lftp", &["22222222222222222222222222222222-bar"]))
+            .unwrap();
+        drop(tx);
+        referrers.register_loop(rx);
+        referrers
+    }
+
+    #[test]
+    fn should_group_files_by_storepath() {
+        let r = run(Referrers::new(&Output::default()));
+        let mut buf = Vec::new();
+        r.write_report(&mut buf).unwrap();
+        assert_eq!(
+            format!(
+                "/nix/store/11111111111111111111111111111111-foo\n    {lt}\n\
+                 /nix/store/22222222222222222222222222222222-bar\n    {lt}\n    {lftp}\n",
+                lt = FIXTURES.join("dir1/proto-http.la").display(),
+                lftp = FIXTURES.join("dir2/lftp").display()
+            ),
+            String::from_utf8_lossy(&buf)
+        );
+    }
+
+    #[test]
+    fn why_should_report_only_wanted_storepaths() {
+        let r = run(Referrers::new(&Output::default()).only(vec![
+            PathBuf::from("22222222222222222222222222222222-bar"),
+            PathBuf::from("33333333333333333333333333333333-baz"),
+        ]));
+        assert_eq!(1, r.files.len());
+        assert_eq!(
+            2,
+            r.files[Path::new("22222222222222222222222222222222-bar")].len()
+        );
+        assert_eq!(
+            vec![Path::new("33333333333333333333333333333333-baz")],
+            r.unreferenced()
+        );
+    }
+
+    #[test]
+    fn ndjson_report() {
+        let mut output = Output::default();
+        output.format = Format::Ndjson;
+        let r = run(Referrers::new(&output));
+        let mut buf = Vec::new();
+        r.write_report(&mut buf).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(buf)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(2, lines.len());
+        assert_eq!(
+            "/nix/store/22222222222222222222222222222222-bar",
+            lines[1]["storepath"]
+        );
+        assert_eq!(2, lines[1]["files"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn parse_storepath_should_strip_store_dir() {
+        let foo = Some(PathBuf::from("11111111111111111111111111111111-foo"));
+        assert_eq!(
+            foo,
+            parse_storepath(
+                "/nix/store/11111111111111111111111111111111-foo",
+                "/nix/store"
+            )
+        );
+        assert_eq!(
+            foo,
+            parse_storepath(
+                "/nix/store/11111111111111111111111111111111-foo/bin/foo",
+                "/nix/store"
+            )
+        );
+        assert_eq!(
+            foo,
+            parse_storepath("11111111111111111111111111111111-foo", "/nix/store")
+        );
+        assert_eq!(None, parse_storepath("/usr/lib/foo", "/nix/store"));
+        assert_eq!(None, parse_storepath("/nix/store", "/nix/store"));
+    }
+}
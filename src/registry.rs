@@ -1,6 +1,10 @@
-use super::STORE;
+use crate::checkpoint::Walked;
+use crate::diff::{Change, Diff};
 use crate::errors::*;
+use crate::manifest::Manifest;
 use crate::output::{p2s, Output};
+use crate::policy::Skipped;
+use crate::storecheck::StoreCheck;
 use crate::storepaths::StorePaths;
 use crate::system::ExecutionContext;
 
@@ -24,11 +28,15 @@ pub type GCRootsRx = mpsc::Receiver<StorePaths>;
 #[derive(Debug, Default)]
 pub struct GCRoots {
     prefix: PathBuf, // /nix/var/nix/gcroots/profiles/per-user/$USER
+    store: PathBuf,  // e.g., /nix/store
     topdir: PathBuf, // e.g., $PREFIX/srv/www if /srv/www was scanned
     cwd: PathBuf,    // current dir when the scan was started
     todo: Vec<StorePaths>,
     seen: HashSet<PathBuf>,
+    preserve: Vec<Skipped>,
     output: Output,
+    check_store: bool,
+    dry_run: bool,
 }
 
 /// IPC endpoint for garbage collection roots registry
@@ -40,12 +48,29 @@ pub trait Register {
     fn commit(&mut self, _ctx: &ExecutionContext) -> Result<()> {
         Ok(())
     }
+
+    /// Like `commit`, but after an incomplete scan: links are only removed below the parts of
+    /// the start dir which have been `walked` completely, since files which have not been seen
+    /// may still need theirs.
+    fn commit_partial(&mut self, _ctx: &ExecutionContext, _walked: &Walked) -> Result<()> {
+        Ok(())
+    }
+
+    /// Leaves existing links belonging to `skipped` paths alone during cleanup, since they could
+    /// not be scanned.
+    fn preserve(&mut self, _skipped: &[Skipped]) {}
 }
 
 impl GCRoots {
     /// Creates Nix garbage collector handler, with `peruser` as user-level gc root (usually
-    /// /nix/var/nix/gcroots/per-user) and `startdir` as initial scan dir (e.g., /home/user).
-    pub fn new<P: AsRef<Path>>(peruser: &str, startdir: P, output: &Output) -> Result<Self> {
+    /// /nix/var/nix/gcroots/per-user), `store` as Nix store dir (usually /nix/store) and
+    /// `startdir` as initial scan dir (e.g., /home/user).
+    pub fn new<P: AsRef<Path>>(
+        peruser: &str,
+        store: &str,
+        startdir: P,
+        output: &Output,
+    ) -> Result<Self> {
         let user = match get_current_username() {
             Some(u) => u,
             None => return Err(UErr::WhoAmI),
@@ -60,11 +85,24 @@ impl GCRoots {
                     .map_err(|_| UErr::Relative)?,
             ),
             prefix,
+            store: PathBuf::from(store),
             cwd,
             output: output.to_owned(),
             ..GCRoots::default()
         })
     }
+
+    /// Registers only references to store paths which actually exist.
+    pub fn check_store(mut self, enable: bool) -> Self {
+        self.check_store = enable;
+        self
+    }
+
+    /// Prints the changes to the GC roots instead of carrying them out.
+    pub fn dry_run(mut self, enable: bool) -> Self {
+        self.dry_run = enable;
+        self
+    }
 }
 
 impl Register for GCRoots {
@@ -76,34 +114,113 @@ impl Register for GCRoots {
     }
 
     fn commit(&mut self, ctx: &ExecutionContext) -> Result<()> {
+        self.commit_with(ctx, None)
+    }
+
+    fn commit_partial(&mut self, ctx: &ExecutionContext, walked: &Walked) -> Result<()> {
+        self.commit_with(ctx, Some(walked))
+    }
+
+    fn preserve(&mut self, skipped: &[Skipped]) {
+        self.preserve.extend_from_slice(skipped);
+    }
+}
+
+impl GCRoots {
+    /// Cleans up everything below `topdir` unless restricted to the `walked` parts.
+    fn commit_with(&mut self, ctx: &ExecutionContext, walked: Option<&Walked>) -> Result<()> {
         // Create `prefix` (/nix/var/nix/gcroots/per-user/$USER) on a best-effort basis before
-        // dropping privileges. Failure may be or may be not a problem here, so defer error
-        // handling to RegistryWorker::link later on.
-        fs::create_dir(&self.prefix).ok();
-        nix::unistd::chown(&self.prefix, Some(ctx.uid), Some(ctx.gid)).ok();
+        // dropping privileges. Failure may be or may be not a problem here: Manifest::open
+        // falls back to an unsaved manifest, and RegistryWorker::link reports links which cannot
+        // be created.
+        if !self.dry_run {
+            fs::create_dir(&self.prefix).ok();
+            nix::unistd::chown(&self.prefix, Some(ctx.uid), Some(ctx.gid)).ok();
+        }
         ctx.with_dropped_privileges(|| {
-            let mut worker = RegistryWorker::new(&self.prefix, &self.cwd);
-            let cleaned = worker.cleanup(&self.topdir)?;
-            let registered = self
-                .todo
-                .iter()
-                .map(|sp| worker.register(sp))
-                .sum::<Result<usize>>()?;
+            let mut worker = RegistryWorker::new(&self.prefix, &self.cwd, &self.store);
+            if self.dry_run {
+                worker.manifest = Manifest::read(&self.prefix, &self.store)?;
+                worker.diff = Some(Diff::default());
+            } else {
+                worker.manifest = Manifest::open(&self.prefix, &self.store)?;
+            }
+            if self.check_store {
+                worker.check_store(self.todo.iter().flat_map(|sp| sp.iter_refs()));
+            }
+            worker.preserve(&self.preserve);
+            let (registered, cleaned) = self.update(&mut worker, walked)?;
             info!(
                 "{} references in {}",
                 self.seen.len().to_string().cyan(),
                 p2s(&self.topdir)
             );
-            if registered > 0 || cleaned > 0 {
+            if let Some(ref diff) = worker.diff {
+                diff.print(self.output.format)?;
+                info!(
+                    "dry run: {} changes to GC roots, none carried out",
+                    diff.changes().len().to_string().cyan()
+                );
+            } else if registered > 0 || cleaned > 0 {
                 info!(
                     "newly registered: {}, cleaned: {}",
                     registered.to_string().green(),
                     cleaned.to_string().purple()
                 );
             }
+            worker.manifest.save()?;
+            if worker.foreign > 0 {
+                info!(
+                    "foreign links left alone: {}",
+                    worker.foreign.to_string().yellow()
+                );
+            }
+            let missing = worker.missing();
+            if missing > 0 {
+                info!(
+                    "skipped references to non-existent store paths: {}",
+                    missing.to_string().yellow()
+                );
+            }
             Ok(())
         })
     }
+
+    /// Registers all store paths first, so that cleanup knows which links are still in use.
+    /// Returns the number of links registered and cleaned up.
+    fn update(
+        &self,
+        worker: &mut RegistryWorker,
+        walked: Option<&Walked>,
+    ) -> Result<(usize, usize)> {
+        let registered = self
+            .todo
+            .iter()
+            .map(|sp| worker.register(sp))
+            .sum::<Result<usize>>()?;
+        let cleaned = match walked {
+            None => worker.cleanup(&self.topdir, None)?,
+            Some(walked) => self.cleanup_walked(worker, walked)?,
+        };
+        Ok((registered, cleaned))
+    }
+
+    /// Cleans up only below the parts of the start dir which have been walked completely.
+    fn cleanup_walked(&self, worker: &mut RegistryWorker, walked: &Walked) -> Result<usize> {
+        info!(
+            "cleaning up only completely walked parts of {}",
+            p2s(&self.topdir)
+        );
+        let mut cleaned = 0;
+        if walked.toplevel {
+            cleaned += worker.cleanup(&self.topdir, Some(1))?;
+        }
+        for dir in &walked.subtrees {
+            let linkdir = worker.link_dir(dir);
+            cleaned += worker.cleanup(&linkdir, None)?;
+        }
+        Ok(cleaned)
+    }
 }
 
 fn extract_hash(path: &Path) -> &[u8] {
@@ -114,92 +231,223 @@ fn extract_hash(path: &Path) -> &[u8] {
 pub struct RegistryWorker<'a> {
     prefix: &'a Path,
     cwd: &'a Path,
+    store: &'a Path,
     seen: HashSet<PathBuf>,
+    check: Option<StoreCheck>,
+    /// Link dirs which must not be cleaned up
+    preserved: Vec<PathBuf>,
+    /// Link dirs whose direct links must not be cleaned up, since they hold the links of
+    /// skipped files
+    preserved_files: HashSet<PathBuf>,
+    /// Links created by us
+    manifest: Manifest,
+    /// Number of links left alone since they have not been created by us
+    foreign: usize,
+    /// Collects changes instead of carrying them out if present
+    diff: Option<Diff>,
 }
 
 impl<'a> RegistryWorker<'a> {
     /// `prefix` - e.g. /nix/var/nix/gcroots/profiles/per-user/$USER
     /// `cwd` - directory where the scan was started
-    fn new(prefix: &'a Path, cwd: &'a Path) -> Self {
+    /// `store` - Nix store dir, e.g. /nix/store
+    fn new(prefix: &'a Path, cwd: &'a Path, store: &'a Path) -> Self {
         Self {
             prefix,
             cwd,
+            store,
             seen: HashSet::new(),
+            check: None,
+            preserved: Vec::new(),
+            preserved_files: HashSet::new(),
+            manifest: Manifest::default(),
+            foreign: 0,
+            diff: None,
+        }
+    }
+
+    /// Keeps all links below the link dirs of skipped subtrees and the links of skipped files
+    /// during cleanup.
+    ///
+    /// Links are not tracked per file, so all links next to the ones of a skipped file are kept.
+    fn preserve(&mut self, skipped: &[Skipped]) {
+        for s in skipped {
+            if s.subtree {
+                self.preserved.push(self.link_dir(&s.path));
+            } else {
+                self.preserved_files.insert(self.gc_link_dir(&s.path));
+            }
         }
     }
 
-    /// Removes dangling symlinks below `topdir`
-    fn cleanup(&self, topdir: &Path) -> Result<usize> {
+    /// Tells if the link at `path` belongs to a skipped file or subtree.
+    fn is_preserved(&self, path: &Path) -> bool {
+        self.preserved.iter().any(|p| path.starts_with(p))
+            || match path.parent() {
+                Some(dir) => self.preserved_files.contains(dir),
+                None => false,
+            }
+    }
+
+    /// Skips references to non-existent store paths from now on.
+    ///
+    /// `refs` are checked in advance so that all lookups happen in one batch.
+    fn check_store<'b, I: IntoIterator<Item = &'b Path>>(&mut self, refs: I) {
+        let mut check = StoreCheck::new(self.store);
+        check.prefetch(refs);
+        self.check = Some(check);
+    }
+
+    /// Number of distinct non-existent store paths encountered.
+    fn missing(&self) -> usize {
+        self.check.as_ref().map_or(0, |c| c.missing().len())
+    }
+
+    /// Removes dangling symlinks below `topdir`, descending at most `max_depth` levels
+    ///
+    /// Only links listed in the manifest are removed. Others are reported and left alone.
+    fn cleanup(&mut self, topdir: &Path, max_depth: Option<usize>) -> Result<usize> {
         if !topdir.exists() {
             return Ok(0);
         }
-        WalkBuilder::new(topdir)
+        let mut dirs = Vec::new();
+        let cleaned = WalkBuilder::new(topdir)
             .hidden(false)
             .ignore(false)
+            .max_depth(max_depth)
             .build()
             .map(|res: result::Result<DirEntry, ignore::Error>| {
                 let dent = res?;
                 let path = dent.path();
                 match dent.file_type() {
                     Some(ft) if ft.is_dir() => {
-                        if fs::remove_dir(path).is_ok() {
-                            debug!("removing empty dir {}", path.display())
-                        }
+                        dirs.push(path.to_owned());
                         Ok(0)
                     }
                     Some(ft) if ft.is_symlink() => {
-                        if self.seen.contains(path) {
+                        if self.seen.contains(path) || self.is_preserved(path) {
+                            Ok(0)
+                        } else if !self.manifest.owns(path) {
+                            info!("keeping foreign link {}", p2s(path));
+                            self.foreign += 1;
                             Ok(0)
                         } else {
-                            info!("removing link {}", p2s(&path));
-                            fs::remove_file(path)?;
+                            if let Some(ref mut diff) = self.diff {
+                                diff.push(Change::Remove {
+                                    link: path.to_owned(),
+                                    target: fs::read_link(path)?,
+                                })
+                            } else {
+                                info!("removing link {}", p2s(path));
+                                fs::remove_file(path)?;
+                            }
+                            self.manifest.remove(path);
                             Ok(1)
                         }
                     }
                     _ => Ok(0),
                 }
             })
-            .sum()
+            .sum::<Result<usize>>()?;
+        // contents first, so that directories emptied during this run are removed as well
+        for dir in dirs.iter().rev() {
+            self.remove_dir(dir)?;
+        }
+        Ok(cleaned)
+    }
+
+    /// Removes `dir` if it is empty. A dry run considers the changes collected so far.
+    fn remove_dir(&mut self, dir: &Path) -> Result<()> {
+        if let Some(ref mut diff) = self.diff {
+            // links to be created are not there yet in a dry run
+            let empty = fs::read_dir(dir)?.all(|e| matches!(e, Ok(e) if diff.removes(&e.path())));
+            if empty && !diff.creates_in(dir) {
+                diff.push(Change::RemoveDir {
+                    dir: dir.to_owned(),
+                })
+            }
+        } else if fs::remove_dir(dir).is_ok() {
+            debug!("removing empty dir {}", dir.display())
+        }
+        Ok(())
     }
 
     /// Determines exactly where a GC link should live.
     fn gc_link_dir<P: AsRef<Path>>(&self, scanned: P) -> PathBuf {
-        let dir = scanned.as_ref().parent().unwrap_or_else(|| Path::new("."));
+        self.link_dir(scanned.as_ref().parent().unwrap_or_else(|| Path::new(".")))
+    }
+
+    /// Directory which holds the GC links for files directly in `dir`.
+    fn link_dir(&self, dir: &Path) -> PathBuf {
         self.prefix
             .join(self.cwd.join(dir).strip_prefix("/").unwrap())
     }
 
-    fn create_link(&mut self, dir: &Path, linkname: PathBuf, target: &Path) -> Result<usize> {
-        info!("creating link {}", p2s(&linkname));
-        fs::create_dir_all(dir).map_err(|e| UErr::Create(dir.to_owned(), e))?;
-        symlink(target, &linkname).map_err(|e| UErr::Create(linkname.to_owned(), e))?;
+    /// Creates a link, replacing the existing one which points to `old` if given.
+    fn create_link(
+        &mut self,
+        dir: &Path,
+        linkname: PathBuf,
+        target: &Path,
+        old: Option<PathBuf>,
+    ) -> Result<usize> {
+        if let Some(ref mut diff) = self.diff {
+            diff.push(match old {
+                Some(old) => Change::Replace {
+                    link: linkname.clone(),
+                    old,
+                    target: target.to_owned(),
+                },
+                None => Change::Create {
+                    link: linkname.clone(),
+                    target: target.to_owned(),
+                },
+            });
+        } else {
+            if old.is_some() {
+                fs::remove_file(&linkname).map_err(|e| UErr::Remove(linkname.to_owned(), e))?;
+            }
+            info!("creating link {}", p2s(&linkname));
+            fs::create_dir_all(dir).map_err(|e| UErr::Create(dir.to_owned(), e))?;
+            symlink(target, &linkname).map_err(|e| UErr::Create(linkname.to_owned(), e))?;
+        }
+        self.manifest.insert(linkname.clone());
         self.seen.insert(linkname);
         Ok(1)
     }
 
     /// Creates or updates a single GC link.
     ///
-    /// `target` is assumed to be without leading `/nix/store/` prefix.
+    /// `target` is assumed to be relative to the Nix store dir.
     fn link<P: AsRef<Path>, T: AsRef<Path>>(&mut self, dir: P, target: T) -> Result<usize> {
         let linkname = dir
             .as_ref()
-            .join(&OsStr::from_bytes(extract_hash(target.as_ref())));
-        let target = Path::new(STORE).join(target);
+            .join(OsStr::from_bytes(extract_hash(target.as_ref())));
+        let target = self.store.join(target);
         if self.seen.contains(&linkname) {
             return Ok(0);
         }
         match fs::read_link(&linkname) {
-            Ok(ref p) => {
-                if *p == *target {
+            Ok(p) => {
+                if p == target {
+                    self.seen.insert(linkname);
+                    Ok(0)
+                } else if !self.manifest.owns(&linkname) {
+                    warn!(
+                        "keeping foreign link {} -> {}, not registering {}",
+                        p2s(&linkname),
+                        p2s(&p),
+                        p2s(&target)
+                    );
+                    self.foreign += 1;
                     self.seen.insert(linkname);
                     Ok(0)
                 } else {
-                    fs::remove_file(&linkname).map_err(|e| UErr::Remove(linkname.to_owned(), e))?;
-                    self.create_link(dir.as_ref(), linkname, &target)
+                    self.create_link(dir.as_ref(), linkname, &target, Some(p))
                 }
             }
             Err(e) => match e.kind() {
-                io::ErrorKind::NotFound => self.create_link(dir.as_ref(), linkname, &target),
+                io::ErrorKind::NotFound => self.create_link(dir.as_ref(), linkname, &target, None),
                 _ => Err(e).map_err(|e| UErr::ReadLink(linkname.to_owned(), e)),
             },
         }
@@ -208,21 +456,33 @@ impl<'a> RegistryWorker<'a> {
     /// Registers all Nix store paths with the garbage collector.
     fn register(&mut self, sp: &StorePaths) -> Result<usize> {
         let dir = self.gc_link_dir(sp.path());
-        sp.iter_refs().map(|p| self.link(dir.as_path(), p)).sum()
+        let mut registered = 0;
+        for p in sp.iter_refs() {
+            if let Some(ref mut check) = self.check {
+                if !check.exists(p) {
+                    debug!(
+                        "{}: skipping non-existent store path {}",
+                        sp.path().display(),
+                        p.display()
+                    );
+                    continue;
+                }
+            }
+            registered += self.link(dir.as_path(), p)?;
+        }
+        Ok(registered)
     }
 }
 
 #[derive(Debug, Default)]
 pub struct NullGCRoots {
     output: Output,
-    rx: Option<GCRootsRx>,
 }
 
 impl NullGCRoots {
     pub fn new(output: &Output) -> Self {
         NullGCRoots {
             output: output.clone(),
-            rx: None,
         }
     }
 }
@@ -238,6 +498,8 @@ impl Register for NullGCRoots {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::policy::ErrorClass;
+    use crate::storepaths::{Origin, StoreRef};
     use crate::tests::FIXTURES;
 
     use std::env;
@@ -247,20 +509,24 @@ pub mod tests {
 
     fn _gcroots() -> (TempDir, GCRoots) {
         let tempdir = TempDir::new().expect("failed to create gcroots tempdir");
-        let mut gc = GCRoots::new("/", Path::new("/"), &Output::default()).unwrap();
+        let mut gc = GCRoots::new("/", "/nix/store", Path::new("/"), &Output::default()).unwrap();
         gc.prefix = tempdir.path().to_owned();
         gc.topdir = PathBuf::from("/home/user/www");
         gc.cwd = PathBuf::from("/home/user");
         (tempdir, gc)
     }
 
-    fn _worker(tempdir: &TempDir) -> RegistryWorker {
-        RegistryWorker::new(tempdir.path(), Path::new("/home/user"))
+    fn _worker(tempdir: &TempDir) -> RegistryWorker<'_> {
+        RegistryWorker::new(
+            tempdir.path(),
+            Path::new("/home/user"),
+            Path::new("/nix/store"),
+        )
     }
 
     fn is_symlink(p: &Path) -> bool {
         fs::symlink_metadata(p)
-            .expect(&format!("symlink {} does not exist", p.display()))
+            .unwrap_or_else(|_| panic!("symlink {} does not exist", p.display()))
             .file_type()
             .is_symlink()
     }
@@ -290,6 +556,22 @@ pub mod tests {
         assert_eq!(w.link(td.path(), storepath).expect("link 2 failed"), 0);
     }
 
+    #[test]
+    fn link_should_point_into_configured_store() {
+        let td = TempDir::new().unwrap();
+        let mut w = RegistryWorker::new(
+            td.path(),
+            Path::new("/home/user"),
+            Path::new("/home/user/.nix/store"),
+        );
+        w.link(td.path(), "gmy86w4020xzjw9s8qzzz0bgx8ldkhhk-e34kjk")
+            .unwrap();
+        assert_eq!(
+            Path::new("/home/user/.nix/store/gmy86w4020xzjw9s8qzzz0bgx8ldkhhk-e34kjk"),
+            fs::read_link(td.path().join("gmy86w4020xzjw9s8qzzz0bgx8ldkhhk")).unwrap()
+        );
+    }
+
     #[test]
     fn create_link_should_create_dir() {
         let td = TempDir::new().unwrap();
@@ -311,6 +593,7 @@ pub mod tests {
         let mut w = _worker(&td);
         let link = td.path().join("f0vdg3cb0005ksjb0fd5qs6f56zg2qs5");
         symlink("changeme", &link).unwrap();
+        w.manifest.insert(link.clone());
         w.link(td.path(), "f0vdg3cb0005ksjb0fd5qs6f56zg2qs5-v")
             .unwrap();
         assert_eq!(
@@ -319,23 +602,51 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn foreign_links_should_be_left_alone() {
+        let td = TempDir::new().unwrap();
+        let mut w = _worker(&td);
+        let clash = td.path().join("f0vdg3cb0005ksjb0fd5qs6f56zg2qs5");
+        symlink("/etc/passwd", &clash).unwrap();
+        let stale = td.path().join("foo");
+        symlink("/nix/store/33333333333333333333333333333333-baz", &stale).unwrap();
+        let ours = td.path().join("44444444444444444444444444444444");
+        symlink("/nix/store/44444444444444444444444444444444-gone", &ours).unwrap();
+        w.manifest.insert(ours.clone());
+
+        assert_eq!(
+            0,
+            w.link(td.path(), "f0vdg3cb0005ksjb0fd5qs6f56zg2qs5-v")
+                .unwrap()
+        );
+        assert_eq!(PathBuf::from("/etc/passwd"), fs::read_link(&clash).unwrap());
+        assert!(!w.manifest.owns(&clash));
+        assert_eq!(1, w.foreign);
+
+        w.cleanup(td.path(), None).unwrap();
+        assert!(fs::symlink_metadata(&ours).is_err());
+        assert!(is_symlink(&clash));
+        assert!(is_symlink(&stale));
+        assert_eq!(2, w.foreign);
+    }
+
     #[test]
     fn cleanup_nonexistent_dir_should_succeed() {
         let td = TempDir::new().unwrap();
-        let w = _worker(&td);
-        assert_eq!(w.cleanup(&td.path().join("no/such/dir")).unwrap(), 0);
+        let mut w = _worker(&td);
+        assert_eq!(w.cleanup(&td.path().join("no/such/dir"), None).unwrap(), 0);
     }
 
     #[test]
     fn should_create_links_no_earlier_than_in_commit() -> Result<()> {
         let (td, mut gc) = _gcroots();
         let (tx, rx) = channel::<StorePaths>();
-        let dent = ignore::Walk::new(td.path()).into_iter().next().unwrap()?;
+        let dent = ignore::Walk::new(td.path()).next().unwrap()?;
         tx.send(StorePaths::new(
             dent,
             vec![
-                PathBuf::from("11111111111111111111111111111111-foo"),
-                PathBuf::from("22222222222222222222222222222222-bar"),
+                StoreRef::new("11111111111111111111111111111111-foo", Origin::Text),
+                StoreRef::new("22222222222222222222222222222222-bar", Origin::Text),
             ],
             1000,
             None,
@@ -345,8 +656,7 @@ pub mod tests {
 
         let contents = |base: &Path| -> Vec<PathBuf> {
             let mut paths = read_dir(base)
-                .expect(&format!("failed to read_dir() {}", base.display()))
-                .into_iter()
+                .unwrap_or_else(|_| panic!("failed to read_dir() {}", base.display()))
                 .map(|e| e.unwrap().path())
                 .collect::<Vec<_>>();
             paths.sort();
@@ -368,13 +678,257 @@ pub mod tests {
         assert_eq!(
             contents(&base),
             &[
-                PathBuf::from(base.join("11111111111111111111111111111111")),
-                PathBuf::from(base.join("22222222222222222222222222222222")),
+                base.join("11111111111111111111111111111111"),
+                base.join("22222222222222222222222222222222"),
             ],
         );
         Ok(())
     }
 
+    #[test]
+    fn commit_partial_should_not_clean_up() -> Result<()> {
+        let (td, mut gc) = _gcroots();
+        let base = td.path().join(env::temp_dir().strip_prefix("/").unwrap());
+        fs::create_dir_all(&base).unwrap();
+        gc.topdir = base.clone();
+        let stale = base.join("33333333333333333333333333333333");
+        symlink("/nix/store/33333333333333333333333333333333-baz", &stale).unwrap();
+        let (tx, rx) = channel::<StorePaths>();
+        let dent = ignore::Walk::new(td.path()).next().unwrap()?;
+        tx.send(StorePaths::new(
+            dent,
+            vec![StoreRef::new(
+                "11111111111111111111111111111111-foo",
+                Origin::Text,
+            )],
+            1000,
+            None,
+        ))
+        .unwrap();
+        drop(tx);
+        gc.register_loop(rx);
+        gc.commit_partial(&ExecutionContext::new(), &Walked::default())?;
+        assert!(fs::symlink_metadata(base.join("11111111111111111111111111111111")).is_ok());
+        assert!(fs::symlink_metadata(&stale).is_ok());
+        gc.commit(&ExecutionContext::new())?;
+        assert!(fs::symlink_metadata(&stale).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn commit_partial_should_clean_up_walked_parts() -> Result<()> {
+        let (td, mut gc) = _gcroots();
+        gc.topdir = td.path().join("srv");
+        let stale = |dir: &str| {
+            let dir = td.path().join("srv").join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            let link = dir.join("33333333333333333333333333333333");
+            symlink("/nix/store/33333333333333333333333333333333-baz", &link).unwrap();
+            link
+        };
+        let (top, a, a_nested, b) = (stale(""), stale("a"), stale("a/x"), stale("b"));
+        let ctx = ExecutionContext::new();
+        let walked = Walked {
+            toplevel: false,
+            subtrees: vec![PathBuf::from("/srv/a")],
+        };
+        gc.commit_partial(&ctx, &walked)?;
+        assert!(fs::symlink_metadata(&a).is_err());
+        assert!(fs::symlink_metadata(&a_nested).is_err());
+        assert!(fs::symlink_metadata(&top).is_ok());
+        assert!(fs::symlink_metadata(&b).is_ok());
+        let walked = Walked {
+            toplevel: true,
+            subtrees: vec![],
+        };
+        gc.commit_partial(&ctx, &walked)?;
+        assert!(fs::symlink_metadata(&top).is_err());
+        assert!(fs::symlink_metadata(&b).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn commit_should_leave_preserved_dirs_alone() -> Result<()> {
+        let (td, mut gc) = _gcroots();
+        gc.topdir = td.path().join("srv");
+        let stale = |dir: &str| {
+            let dir = td.path().join("srv").join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            let link = dir.join("33333333333333333333333333333333");
+            symlink("/nix/store/33333333333333333333333333333333-baz", &link).unwrap();
+            link
+        };
+        let (a, a_nested, b) = (stale("a"), stale("a/x"), stale("b"));
+        let (c, c_nested) = (stale("c"), stale("c/x"));
+        gc.preserve(&[
+            Skipped {
+                path: PathBuf::from("/srv/a"),
+                class: ErrorClass::Permission,
+                subtree: true,
+            },
+            Skipped {
+                path: PathBuf::from("/srv/c/file"),
+                class: ErrorClass::Other,
+                subtree: false,
+            },
+        ]);
+        gc.commit(&ExecutionContext::new())?;
+        assert!(fs::symlink_metadata(&a).is_ok());
+        assert!(fs::symlink_metadata(&a_nested).is_ok());
+        assert!(fs::symlink_metadata(&b).is_err());
+        // only links directly next to the ones of a skipped file are kept
+        assert!(fs::symlink_metadata(&c).is_ok());
+        assert!(fs::symlink_metadata(&c_nested).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_should_only_collect_changes() -> Result<()> {
+        let td = TempDir::new().unwrap();
+        let mut w = _worker(&td);
+        w.diff = Some(Diff::default());
+        let p = td.path();
+        fs::create_dir(p.join("empty")).unwrap();
+        let stale = p.join("33333333333333333333333333333333");
+        symlink("/nix/store/33333333333333333333333333333333-baz", &stale).unwrap();
+        w.manifest.insert(stale.clone());
+        let changed = p.join("22222222222222222222222222222222");
+        symlink("/nix/store/22222222222222222222222222222222-old", &changed).unwrap();
+        w.manifest.insert(changed.clone());
+        assert_eq!(1, w.link(p, "11111111111111111111111111111111-foo")?);
+        assert_eq!(1, w.link(p, "22222222222222222222222222222222-new")?);
+        assert_eq!(1, w.cleanup(p, None)?);
+        let diff = w.diff.unwrap();
+        for change in &[
+            Change::RemoveDir {
+                dir: p.join("empty"),
+            },
+            Change::Remove {
+                link: stale.clone(),
+                target: PathBuf::from("/nix/store/33333333333333333333333333333333-baz"),
+            },
+            Change::Create {
+                link: p.join("11111111111111111111111111111111"),
+                target: PathBuf::from("/nix/store/11111111111111111111111111111111-foo"),
+            },
+            Change::Replace {
+                link: changed.clone(),
+                old: PathBuf::from("/nix/store/22222222222222222222222222222222-old"),
+                target: PathBuf::from("/nix/store/22222222222222222222222222222222-new"),
+            },
+        ] {
+            assert!(diff.changes().contains(change), "missing {:?}", change);
+        }
+        assert_eq!(4, diff.changes().len());
+        assert!(p.join("empty").exists());
+        assert!(fs::symlink_metadata(&stale).is_ok());
+        assert!(fs::symlink_metadata(p.join("11111111111111111111111111111111")).is_err());
+        assert_eq!(
+            PathBuf::from("/nix/store/22222222222222222222222222222222-old"),
+            fs::read_link(&changed).unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_should_match_real_run() -> Result<()> {
+        let td = TempDir::new().unwrap();
+        let top = td.path().join("top");
+        fs::create_dir_all(top.join("d")).unwrap();
+        let stale = top.join("d/33333333333333333333333333333333");
+        symlink("/nix/store/33333333333333333333333333333333-baz", &stale).unwrap();
+        let paths = [stale.clone(), top.join("d"), top.clone()];
+
+        let mut w = _worker(&td);
+        w.manifest.insert(stale.clone());
+        w.diff = Some(Diff::default());
+        assert_eq!(1, w.cleanup(&top, None)?);
+        let mut dry: Vec<PathBuf> = w
+            .diff
+            .unwrap()
+            .changes()
+            .iter()
+            .map(|c| c.path().to_owned())
+            .collect();
+        dry.sort();
+        assert!(paths.iter().all(|p| fs::symlink_metadata(p).is_ok()));
+
+        let mut w = _worker(&td);
+        w.manifest.insert(stale.clone());
+        assert_eq!(1, w.cleanup(&top, None)?);
+        let mut removed: Vec<PathBuf> = paths
+            .iter()
+            .filter(|p| fs::symlink_metadata(p).is_err())
+            .cloned()
+            .collect();
+        removed.sort();
+        assert_eq!(3, removed.len());
+        assert_eq!(removed, dry);
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_should_not_report_links_in_use() -> Result<()> {
+        let (td, mut gc) = _gcroots();
+        let base = td.path().join(env::temp_dir().strip_prefix("/").unwrap());
+        fs::create_dir_all(&base).unwrap();
+        gc.topdir = base.clone();
+        let link = base.join("11111111111111111111111111111111");
+        symlink("/nix/store/11111111111111111111111111111111-foo", &link).unwrap();
+        let (tx, rx) = channel::<StorePaths>();
+        let dent = ignore::Walk::new(td.path()).next().unwrap()?;
+        tx.send(StorePaths::new(
+            dent,
+            vec![StoreRef::new(
+                "11111111111111111111111111111111-foo",
+                Origin::Text,
+            )],
+            1000,
+            None,
+        ))
+        .unwrap();
+        drop(tx);
+        gc.register_loop(rx);
+        let mut w = RegistryWorker::new(&gc.prefix, &gc.cwd, &gc.store);
+        w.manifest.insert(link.clone());
+        w.diff = Some(Diff::default());
+        assert_eq!((0, 0), gc.update(&mut w, None)?);
+        assert_eq!(0, w.diff.unwrap().changes().len());
+        assert!(fs::symlink_metadata(&link).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_nonexistent_store_paths() -> Result<()> {
+        let (td, gc) = _gcroots();
+        let store = TempDir::new().unwrap();
+        fs::create_dir(store.path().join("11111111111111111111111111111111-foo")).unwrap();
+        let mut gc = GCRoots {
+            store: store.path().to_owned(),
+            ..gc
+        }
+        .check_store(true);
+        let (tx, rx) = channel::<StorePaths>();
+        let dent = ignore::Walk::new(td.path()).next().unwrap()?;
+        tx.send(StorePaths::new(
+            dent,
+            vec![
+                StoreRef::new("11111111111111111111111111111111-foo", Origin::Text),
+                StoreRef::new("22222222222222222222222222222222-bar", Origin::Text),
+            ],
+            1000,
+            None,
+        ))
+        .unwrap();
+        drop(tx);
+        gc.register_loop(rx);
+        gc.commit(&ExecutionContext::new())?;
+        let base = td.path().join(env::temp_dir().strip_prefix("/").unwrap());
+        assert!(base.join("11111111111111111111111111111111").exists());
+        assert!(fs::symlink_metadata(base.join("22222222222222222222222222222222")).is_err());
+        Ok(())
+    }
+
     /*
      * passive GCRoots consumer to test walker/scanner
      */
@@ -383,7 +937,6 @@ pub mod tests {
     pub struct FakeGCRoots {
         pub registered: Vec<String>,
         prefix: PathBuf,
-        rx: Option<GCRootsRx>,
     }
 
     impl FakeGCRoots {
@@ -391,13 +944,12 @@ pub mod tests {
             FakeGCRoots {
                 registered: Vec::new(),
                 prefix: reldir.canonicalize().unwrap(),
-                rx: None,
             }
         }
     }
 
     pub fn fake_gc() -> FakeGCRoots {
-        FakeGCRoots::new(&*FIXTURES)
+        FakeGCRoots::new(&FIXTURES)
     }
 
     impl Register for FakeGCRoots {
@@ -1,36 +1,218 @@
 use crate::errors::*;
-use crate::output::p2s;
-use crate::storepaths::StorePaths;
+use crate::output::{display, p2s};
+use crate::storepaths::{Origin, StorePaths, StoreRef};
 
 use anyhow::Context;
 use anyhow::Result as AResult;
 use bytesize::ByteSize;
+use bzip2::read::BzDecoder;
+use flate2::read::MultiGzDecoder;
+use goblin::elf::dynamic::{DT_RPATH, DT_RUNPATH};
+use goblin::elf::Elf;
 use ignore::overrides::Override;
 use ignore::{DirEntry, Match};
-use lazy_static::lazy_static;
 use memmap::Mmap;
 use probes::load;
 use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeSet;
 use std::ffi::OsStr;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read, Seek};
+use std::ops::Range;
 use std::os::unix::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::thread::sleep;
 use std::time::Duration;
+use tar::Archive as TarArchive;
+use xz2::read::XzDecoder;
 use zip::read::ZipArchive;
+use zip::result::{ZipError, ZipResult};
 
-lazy_static! {
-    static ref STORE_RE: Regex =
-        Regex::new(r"(?-u)/nix/store/([0-9a-z]{32}-[0-9a-zA-Z+._?=-]+)").unwrap();
+/// Nix store location unless configured otherwise.
+pub const DEFAULT_STORE_DIR: &str = "/nix/store";
+
+/// Characters used by Nix to encode store path hashes. Note that e, o, t and u are missing.
+const NIX_BASE32: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Length of the hash part of a store path name.
+const HASH_LEN: usize = 32;
+
+/// Nix refuses store path names (without hash) longer than this.
+const MAX_NAME_LEN: usize = 211;
+
+/// Store references found while scanning a single file.
+#[derive(Debug, Default)]
+struct Found {
+    refs: Vec<StoreRef>,
+    /// Number of candidates which look like store references but violate Nix' naming rules
+    rejected: usize,
+}
+
+/// Recognizes Nix store references in raw data.
+#[derive(Debug, Clone)]
+struct Matcher {
+    /// Matches `$STORE_DIR/hash-name`, capturing `hash-name`
+    regex: Regex,
+    /// Store dir including trailing slash. Files without it cannot contain references.
+    needle: Vec<u8>,
+    /// Accepts all candidates regardless of Nix' naming rules.
+    lenient: bool,
+}
+
+impl Default for Matcher {
+    fn default() -> Self {
+        Self::new(DEFAULT_STORE_DIR)
+    }
+}
+
+impl Matcher {
+    /// Creates a matcher for references into `store_dir` (without trailing slash).
+    fn new(store_dir: &str) -> Self {
+        let regex = Regex::new(&format!(
+            r"(?-u){}/([0-9a-z]{{{}}}-[0-9a-zA-Z+._?=-]+)",
+            regex::escape(store_dir),
+            HASH_LEN
+        ))
+        .unwrap();
+        Self {
+            regex,
+            needle: format!("{}/", store_dir).into_bytes(),
+            lenient: false,
+        }
+    }
+
+    /// Minimum length to fit a single store reference.
+    ///
+    /// Store dir + 32 characters hash + `-` + at least 1 character name.
+    fn min_len(&self) -> u64 {
+        (self.needle.len() + HASH_LEN + 2) as u64
+    }
+
+    /// Upper bound for the length of a store reference.
+    ///
+    /// Store dir + 32 characters hash + `-` + up to 211 characters name.
+    fn max_len(&self) -> usize {
+        self.needle.len() + HASH_LEN + 1 + MAX_NAME_LEN
+    }
+
+    /// Iterates over all candidates in `data`.
+    ///
+    /// Yields the position of the whole match and the store path name (hash and name without
+    /// store directory). Candidates have not been validated yet.
+    fn candidates<'a>(
+        &'a self,
+        data: &'a [u8],
+    ) -> impl Iterator<Item = (Range<usize>, &'a [u8])> + 'a {
+        self.regex.captures_iter(data).map(move |cap| {
+            let m = cap.get(0).unwrap();
+            let name = cap.get(1).unwrap();
+            (m.start()..m.end(), &data[name.start()..name.end()])
+        })
+    }
+
+    /// Checks a store path name against the rules enforced by Nix: the hash must be Nix base32
+    /// encoded and the name part must neither be too long nor start with a dot.
+    fn is_valid(name: &[u8]) -> bool {
+        let (hash, name) = name.split_at(HASH_LEN);
+        // `name` starts with the dash separating hash and name
+        hash.iter().all(|c| NIX_BASE32.contains(c))
+            && name.len() - 1 <= MAX_NAME_LEN
+            && name.get(1) != Some(&b'.')
+    }
+
+    /// Records a single candidate as reference with the given origin or rejects it.
+    fn add(&self, found: &mut Found, name: &[u8], origin: Origin) {
+        if self.lenient || Self::is_valid(name) {
+            found.refs.push(StoreRef::from_bytes(name, origin))
+        } else {
+            debug!(
+                "Rejecting invalid store path {}",
+                String::from_utf8_lossy(name)
+            );
+            found.rejected += 1
+        }
+    }
+
+    /// Records all candidates in `data`, tagged with `origin`.
+    fn find(&self, data: &[u8], origin: &Origin, found: &mut Found) {
+        for (_, name) in self.candidates(data) {
+            self.add(found, name, origin.clone())
+        }
+    }
 }
 
-const MIN_STOREREF_LEN: u64 = 45;
+/// Streamed data is scanned in pieces of this size.
+const CHUNK_SIZE: usize = 64 << 10;
 
 struct ScanResult {
-    refs: Vec<PathBuf>,
+    found: Found,
     meta: fs::Metadata,
     bytes_scanned: u64,
+    bytes_decompressed: u64,
+    softerrors: usize,
+}
+
+/// Scanner settings which influence scan results.
+///
+/// Recorded with cached results: results produced under a weaker configuration must not be
+/// reused since they might lack references.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ScanConfig {
+    /// Quickcheck size in bytes. 0 means that files are always read completely.
+    pub quickcheck: u64,
+    pub unzip: BTreeSet<String>,
+    pub untar: BTreeSet<String>,
+    pub max_nesting: usize,
+    /// 0 means no limit
+    pub unpack_limit: u64,
+    /// 0 means no limit
+    pub max_ratio: u64,
+    /// Decompression limit if decompression is enabled. 0 means no limit.
+    pub decompress: Option<u64>,
+    /// Full scan of file contents (disabled for ELF binaries with --elf-only)
+    pub text: bool,
+    /// References from ELF headers
+    pub elf: bool,
+    pub lenient: bool,
+}
+
+/// Returns true if a limit `a` is at least as generous as `b`. 0 means no limit.
+fn limit_covers(a: u64, b: u64) -> bool {
+    a == 0 || (b != 0 && a >= b)
+}
+
+impl ScanConfig {
+    /// Configuration assumed for cached results written by versions which didn't record it.
+    pub fn legacy() -> Self {
+        ScanConfig {
+            quickcheck: ByteSize::kib(512).as_u64(),
+            text: true,
+            lenient: true,
+            ..ScanConfig::default()
+        }
+    }
+
+    /// Returns true if results produced under `self` contain everything `other` would find.
+    pub fn covers(&self, other: &ScanConfig) -> bool {
+        limit_covers(self.quickcheck, other.quickcheck)
+            && self.unzip.is_superset(&other.unzip)
+            && self.untar.is_superset(&other.untar)
+            && ((other.unzip.is_empty() && other.untar.is_empty())
+                || (self.max_nesting >= other.max_nesting
+                    && limit_covers(self.unpack_limit, other.unpack_limit)
+                    && limit_covers(self.max_ratio, other.max_ratio)))
+            && match (self.decompress, other.decompress) {
+                (_, None) => true,
+                (Some(a), Some(b)) => limit_covers(a, b),
+                (None, Some(_)) => false,
+            }
+            && (self.text || !other.text)
+            && (self.elf || !other.elf)
+            && (self.lenient || !other.lenient)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -39,8 +221,26 @@ pub struct Scanner {
     quickcheck: ByteSize,
     /// Unzips files matched by the given globs and scans inside.
     unzip: Override,
+    /// Unpacks tar archives (optionally compressed) matched by the given globs and scans inside.
+    untar: Override,
     /// Pauses scanning if the current load1 is higher than this
     max_load: f32,
+    /// Scans inside gzip, bzip2 and xz compressed files.
+    decompress: bool,
+    /// Gives up on compressed files which unpack to more than this. No limit if 0.
+    decompress_limit: ByteSize,
+    /// Unpacks archives nested inside archives up to this level.
+    max_nesting: usize,
+    /// Stops unpacking after this many bytes per top-level file. No limit if 0.
+    unpack_limit: ByteSize,
+    /// Stops unpacking archives with a higher overall compression ratio. No limit if 0.
+    max_ratio: u64,
+    /// Parses ELF binaries and extracts references from their headers.
+    elf: bool,
+    /// Skips the full scan of ELF binaries if their headers contain references.
+    elf_only: bool,
+    /// Decides which candidates are accepted as store references.
+    matcher: Matcher,
 }
 
 impl Default for Scanner {
@@ -48,15 +248,129 @@ impl Default for Scanner {
         Scanner {
             quickcheck: ByteSize::b(0),
             unzip: Override::empty(),
+            untar: Override::empty(),
             max_load: 0.0,
+            decompress: false,
+            decompress_limit: ByteSize::b(0),
+            max_nesting: 0,
+            unpack_limit: ByteSize::b(0),
+            max_ratio: 0,
+            elf: false,
+            elf_only: false,
+            matcher: Matcher::default(),
         }
     }
 }
 
+/// Compression formats which can be unpacked on the fly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+}
+
+/// Number of bytes needed to recognize all supported compression formats.
+const MAGIC_LEN: usize = 6;
+
+impl Compression {
+    /// Recognizes compressed data by its magic bytes.
+    fn detect(head: &[u8]) -> Option<Self> {
+        if head.starts_with(b"\x1f\x8b") {
+            Some(Compression::Gzip)
+        } else if head.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if head.starts_with(b"\xfd7zXZ\x00") {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+
+    /// Recognizes compressed data at the start of `r` and unpacks it if necessary.
+    fn wrap<'a, R: Read + 'a>(mut r: R) -> io::Result<Box<dyn Read + 'a>> {
+        let mut head = Vec::with_capacity(MAGIC_LEN);
+        (&mut r).take(MAGIC_LEN as u64).read_to_end(&mut head)?;
+        let compression = Self::detect(&head);
+        let r = io::Cursor::new(head).chain(r);
+        Ok(match compression {
+            Some(c) => c.decoder(r),
+            None => Box::new(r),
+        })
+    }
+
+    /// Wraps `r` into a decompressing reader.
+    fn decoder<'a, R: Read + 'a>(self, r: R) -> Box<dyn Read + 'a> {
+        match self {
+            Compression::Gzip => Box::new(MultiGzDecoder::new(r)),
+            Compression::Bzip2 => Box::new(BzDecoder::new(r)),
+            Compression::Xz => Box::new(XzDecoder::new(r)),
+        }
+    }
+}
+
+/// Reads the first few bytes of a file to recognize its format.
+fn read_head(path: &Path) -> io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(MAGIC_LEN);
+    fs::File::open(path)?
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Reads from `r` until `buf` is full or EOF is reached. Returns the number of bytes read.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(i) => n += i,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Scans a stream for Nix store references in constant memory.
+///
+/// Data is processed in chunks of CHUNK_SIZE. The tail of each chunk is retained so that
+/// references spanning a chunk boundary are found as well. A match which touches the end of a
+/// chunk may be truncated and is deferred to the next round. Matches which lie completely inside
+/// the retained tail have been seen in the previous round and are skipped. Found references are
+/// added to `found`. Returns the number of bytes read.
+fn scan_stream<R: Read>(
+    mut r: R,
+    m: &Matcher,
+    found: &mut Found,
+    origin: &Origin,
+) -> io::Result<u64> {
+    let mut buf = vec![0; m.max_len() + CHUNK_SIZE];
+    let mut keep = 0;
+    let mut total = 0;
+    loop {
+        let n = read_full(&mut r, &mut buf[keep..])?;
+        total += n as u64;
+        let end = keep + n;
+        let eof = end < buf.len();
+        for (pos, name) in m.candidates(&buf[..end]) {
+            if pos.end >= keep && (eof || pos.end < end) {
+                m.add(found, name, origin.clone())
+            }
+        }
+        if eof {
+            return Ok(total);
+        }
+        keep = m.max_len();
+        buf.copy_within(end - keep..end, 0);
+    }
+}
+
 /// Scans a regular file.
 ///
 /// Only the first `quickcheck` bytes are considered. The whole file is read if `quickcheck` is 0.
 fn scan_regular_quickcheck(
+    m: &Matcher,
     dent: &DirEntry,
     meta: fs::Metadata,
     quickcheck: u64,
@@ -65,89 +379,495 @@ fn scan_regular_quickcheck(
     let mmap = unsafe { Mmap::map(&fs::File::open(dent.path())?)? };
     if quickcheck > 0
         && meta.len() > quickcheck
-        && twoway::find_bytes(&mmap[0..(quickcheck as usize)], b"/nix/store/").is_none()
+        && twoway::find_bytes(&mmap[0..(quickcheck as usize)], &m.needle).is_none()
     {
         return Ok(ScanResult {
-            refs: vec![],
+            found: Found::default(),
             meta,
             bytes_scanned: quickcheck,
+            bytes_decompressed: 0,
+            softerrors: 0,
         });
     }
     let bytes_scanned = meta.len();
     Ok(ScanResult {
-        refs: STORE_RE
-            .captures_iter(&mmap)
-            .map(|cap| OsStr::from_bytes(&cap[1]).into())
-            .collect(),
+        found: text_refs(m, &mmap),
         meta,
         bytes_scanned,
+        bytes_decompressed: 0,
+        softerrors: 0,
     })
 }
 
-fn scan_regular(dent: &DirEntry, quickcheck: ByteSize) -> AResult<ScanResult> {
+/// Finds references in a file's contents.
+///
+/// References on the `#!` line of scripts are told apart from those in the rest of the file.
+fn text_refs(m: &Matcher, data: &[u8]) -> Found {
+    let shebang_end = if data.starts_with(b"#!") {
+        twoway::find_bytes(data, b"\n").unwrap_or(data.len())
+    } else {
+        0
+    };
+    let mut found = Found::default();
+    for (pos, name) in m.candidates(data) {
+        let origin = if pos.start < shebang_end {
+            Origin::Shebang
+        } else {
+            Origin::Text
+        };
+        m.add(&mut found, name, origin)
+    }
+    found
+}
+
+fn scan_regular(m: &Matcher, dent: &DirEntry, quickcheck: ByteSize) -> AResult<ScanResult> {
     let meta = dent.metadata()?;
-    if meta.len() < MIN_STOREREF_LEN {
+    if meta.len() < m.min_len() {
         // minimum length to fit a single store reference not reached
         let bytes_scanned = meta.len();
         Ok(ScanResult {
-            refs: vec![],
+            found: Found::default(),
             meta,
             bytes_scanned,
+            bytes_decompressed: 0,
+            softerrors: 0,
         })
     } else {
-        scan_regular_quickcheck(dent, meta, quickcheck.as_u64())
+        scan_regular_quickcheck(m, dent, meta, quickcheck.as_u64())
     }
 }
 
-/// Unpacks a ZIP archive on the fly and scans its contents.
-fn scan_zip_archive(dent: &DirEntry) -> AResult<ScanResult> {
-    debug!("Scanning ZIP archive {}", dent.path().display());
-    let meta = dent.metadata()?;
-    let mut archive = match ZipArchive::new(fs::File::open(&dent.path())?) {
-        Ok(a) => a,
-        Err(e) => return Err(UErr::ZIP(dent.path().to_owned(), e).into()),
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// Extracts references from an ELF binary's program interpreter and dynamic section.
+///
+/// Returns None if `data` cannot be parsed as ELF binary.
+fn elf_refs(m: &Matcher, data: &[u8]) -> Option<Found> {
+    let elf = match Elf::parse(data) {
+        Ok(elf) => elf,
+        Err(e) => {
+            debug!("Failed to parse ELF headers: {}", e);
+            return None;
+        }
     };
-    let mut buf = Vec::new();
-    let mut refs = Vec::new();
-    if archive.len() > 1000 || meta.len() > 2 << 20 {
-        warn!(
-            "{}: unpacking large ZIP archives may be slow",
-            p2s(dent.path())
-        );
+    let mut found = Found::default();
+    let mut add = |origin, s: &str| m.find(s.as_bytes(), &origin, &mut found);
+    if let Some(interp) = elf.interpreter {
+        add(Origin::ElfInterpreter, interp);
+    }
+    if let Some(ref dynamic) = elf.dynamic {
+        for d in &dynamic.dyns {
+            if d.d_tag == DT_RPATH || d.d_tag == DT_RUNPATH {
+                if let Some(Ok(rpath)) = elf.dynstrtab.get(d.d_val as usize) {
+                    add(Origin::ElfRpath, rpath);
+                }
+            }
+        }
+    }
+    for lib in &elf.libraries {
+        add(Origin::ElfNeeded, lib);
+    }
+    Some(found)
+}
+
+/// Scans an ELF binary.
+///
+/// References are extracted from the program interpreter, RPATH/RUNPATH and DT_NEEDED entries
+/// first. If nothing is found there, the binary is scanned like any other regular file. Otherwise
+/// the whole binary is scanned in addition unless `skip_strings` is set.
+fn scan_elf(
+    m: &Matcher,
+    dent: &DirEntry,
+    quickcheck: ByteSize,
+    skip_strings: bool,
+) -> AResult<ScanResult> {
+    let meta = dent.metadata()?;
+    if meta.len() < m.min_len() {
+        return scan_regular(m, dent, quickcheck);
     }
-    for i in 0..archive.len() {
-        let mut f = archive
-            .by_index(i)
-            .map_err(|e| UErr::ZIP(dent.path().to_owned(), e))?;
-        f.read_to_end(&mut buf)?;
-        refs.extend(
-            STORE_RE
-                .captures_iter(&buf)
-                .map(|cap| OsStr::from_bytes(&cap[1]).into()),
+    debug!("Scanning ELF binary {}", dent.path().display());
+    let mmap = unsafe { Mmap::map(&fs::File::open(dent.path())?)? };
+    let mut found = elf_refs(m, &mmap).unwrap_or_default();
+    if found.refs.is_empty() {
+        return scan_regular_quickcheck(m, dent, meta, quickcheck.as_u64());
+    }
+    // only headers have been looked at if the full scan is skipped
+    let mut bytes_scanned = 0;
+    if !skip_strings {
+        m.find(&mmap, &Origin::Text, &mut found);
+        bytes_scanned = meta.len();
+    }
+    for r in &found.refs {
+        debug!(
+            "{}: {} {}",
+            dent.path().display(),
+            r.origin,
+            r.path.display()
         );
     }
+    Ok(ScanResult {
+        found,
+        meta,
+        bytes_scanned,
+        bytes_decompressed: 0,
+        softerrors: 0,
+    })
+}
+
+/// Decompressed output which is allowed regardless of the compression ratio. Small archives like
+/// tar files with lots of padding can be highly compressible.
+const RATIO_SLACK: u64 = 64 << 10;
+
+/// Fails reading once the data decompressed at all nesting levels of an archive exceeds its
+/// compression ratio budget.
+///
+/// This relies on the bytes actually decompressed, not on sizes declared in archive headers.
+struct RatioLimit<R> {
+    inner: R,
+    /// Archive member (or nested archive) being decompressed, reported on failure
+    name: PathBuf,
+    budget: RatioBudget,
+}
+
+impl<R: Read> Read for RatioLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let b = &self.budget;
+        b.output.set(b.output.get() + n as u64);
+        if b.output.get() > b.max_output {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                UErr::CompressionRatio(self.name.clone(), b.max_ratio),
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// Decompressed bytes allowed for a top-level archive: `max_ratio` times its size on disk.
+#[derive(Debug, Clone)]
+struct RatioBudget {
+    /// Bytes decompressed so far, shared by all nesting levels
+    output: Rc<Cell<u64>>,
+    max_output: u64,
+    max_ratio: u64,
+}
+
+impl RatioBudget {
+    /// No limit if `max_ratio` is 0.
+    fn new(size: u64, max_ratio: u64) -> Self {
+        Self {
+            output: Rc::new(Cell::new(0)),
+            max_output: match max_ratio {
+                0 => u64::MAX,
+                r => size.saturating_mul(r).saturating_add(RATIO_SLACK),
+            },
+            max_ratio,
+        }
+    }
+}
+
+/// Returns the archive member named in `e` if it has been raised by `RatioLimit`.
+fn ratio_exceeded(e: &io::Error) -> Option<&Path> {
+    match e.get_ref().and_then(|e| e.downcast_ref()) {
+        Some(UErr::CompressionRatio(name, _)) => Some(name),
+        _ => None,
+    }
+}
+
+/// Archive formats which can be unpacked on the fly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+}
+
+/// Unpacks a top-level archive and all archives nested inside it.
+///
+/// Violations of the nesting depth, unpack budget and compression ratio limits are reported as
+/// soft errors. Unpacking continues with the next member if possible.
+///
+/// The compression ratio is checked against the top-level file's size on disk, counting the data
+/// decompressed at all nesting levels.
+struct Unpacker<'a> {
+    scanner: &'a Scanner,
+    /// Top-level file, used for messages
+    path: &'a Path,
+    /// Bytes which may still be unpacked
+    remaining: u64,
+    ratio: RatioBudget,
+    /// Set once the unpack or compression ratio budget is used up; stops further unpacking
+    exhausted: bool,
+    found: Found,
+    bytes_unpacked: u64,
+    /// Members which have not been unpacked or scanned completely
+    softerrors: Vec<PathBuf>,
+}
+
+impl<'a> Unpacker<'a> {
+    fn new(scanner: &'a Scanner, path: &'a Path, size: u64) -> Self {
+        Self {
+            scanner,
+            path,
+            remaining: match scanner.unpack_limit.as_u64() {
+                0 => u64::MAX,
+                limit => limit,
+            },
+            ratio: RatioBudget::new(size, scanner.max_ratio),
+            exhausted: false,
+            found: Found::default(),
+            bytes_unpacked: 0,
+            softerrors: Vec::new(),
+        }
+    }
+
+    /// Reports a problem with `member`, which is empty for the top-level file itself.
+    fn soft_error(&mut self, member: &Path, msg: &str) {
+        if member.as_os_str().is_empty() {
+            warn!("{}: {}", p2s(self.path), msg);
+        } else {
+            warn!("{}: {}: {}", p2s(self.path), display(member), msg);
+        }
+        self.softerrors.push(member.to_owned());
+    }
+
+    /// Charges everything read from `r` to the compression ratio budget.
+    fn ratio_limit<R: Read>(&self, r: R, name: &Path) -> RatioLimit<R> {
+        RatioLimit {
+            inner: r,
+            name: name.to_owned(),
+            budget: self.ratio.clone(),
+        }
+    }
+
+    /// Reports `e` if the compression ratio budget has been exceeded. This stops unpacking.
+    ///
+    /// Returns `e` unchanged otherwise.
+    fn ratio_error(&mut self, e: io::Error) -> io::Result<()> {
+        match ratio_exceeded(&e) {
+            Some(name) => {
+                let name = name.to_owned();
+                let msg = format!(
+                    "compression ratio exceeds {}:1, skipping rest of archive",
+                    self.ratio.max_ratio
+                );
+                self.soft_error(&name, &msg);
+                self.exhausted = true;
+                Ok(())
+            }
+            None => Err(e),
+        }
+    }
+
+    /// Decides if an archive member should be unpacked as nested archive.
+    fn nested_kind(&self, name: &Path) -> Option<ArchiveKind> {
+        if let Match::Whitelist(_) = self.scanner.unzip.matched(name, false) {
+            Some(ArchiveKind::Zip)
+        } else if let Match::Whitelist(_) = self.scanner.untar.matched(name, false) {
+            Some(ArchiveKind::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// Deducts `n` bytes from the unpack budget.
+    ///
+    /// Returns false if the budget has been exceeded.
+    fn charge(&mut self, n: u64) -> bool {
+        if n > self.remaining {
+            self.exhausted = true;
+            return false;
+        }
+        self.remaining -= n;
+        self.bytes_unpacked += n;
+        true
+    }
+
+    /// Reads `r` completely into `buf`, charging the unpack budget.
+    ///
+    /// Only needed for nested ZIP archives which must be seekable. Returns false if the budget has
+    /// been exceeded.
+    fn read_member<R: Read>(&mut self, r: R, buf: &mut Vec<u8>) -> io::Result<bool> {
+        buf.clear();
+        let n = r.take(self.remaining.saturating_add(1)).read_to_end(buf)? as u64;
+        Ok(self.charge(n))
+    }
+
+    /// Scans `r` in constant memory, charging the unpack budget.
+    ///
+    /// Returns false if the budget has been exceeded.
+    fn scan_member<R: Read>(&mut self, name: &Path, r: R) -> io::Result<bool> {
+        let origin = Origin::Archive(name.to_owned());
+        let n = scan_stream(
+            r.take(self.remaining.saturating_add(1)),
+            &self.scanner.matcher,
+            &mut self.found,
+            &origin,
+        )?;
+        Ok(self.charge(n))
+    }
+
+    /// Scans a single archive member. Nested archives are descended into.
+    ///
+    /// `name` is the member's path below the top-level archive, i.e. including the names of all
+    /// enclosing nested archives.
+    fn member<R: Read>(&mut self, name: &Path, r: R, depth: usize) -> io::Result<()> {
+        let max_depth = self.scanner.max_nesting;
+        match self.nested_kind(name) {
+            Some(kind) if depth < max_depth => {
+                debug!("Unpacking nested archive {}", name.display());
+                let res = match kind {
+                    ArchiveKind::Tar => self.tar(r, name, depth + 1).map_err(ZipError::from),
+                    ArchiveKind::Zip => {
+                        let mut buf = Vec::new();
+                        if !self.read_member(r, &mut buf)? {
+                            self.soft_error(name, "unpack limit exceeded");
+                            return Ok(());
+                        }
+                        self.zip(io::Cursor::new(buf), name, depth + 1)
+                    }
+                };
+                if let Err(e) = res {
+                    self.soft_error(name, &format!("cannot unpack nested archive: {}", e));
+                }
+                return Ok(());
+            }
+            Some(_) if max_depth > 0 => self.soft_error(
+                name,
+                &format!(
+                    "archives nested deeper than {} levels are not unpacked",
+                    max_depth
+                ),
+            ),
+            _ => (),
+        }
+        if !self.scan_member(name, r)? {
+            self.soft_error(name, "unpack limit exceeded");
+        }
+        Ok(())
+    }
+
+    /// Unpacks a ZIP archive and scans its members.
+    ///
+    /// Member names are prefixed with `parent`, which is empty for the top-level archive.
+    fn zip<R: Read + Seek>(&mut self, r: R, parent: &Path, depth: usize) -> ZipResult<()> {
+        let mut archive = ZipArchive::new(r)?;
+        for i in 0..archive.len() {
+            if self.exhausted {
+                break;
+            }
+            let f = archive.by_index(i)?;
+            if f.is_dir() {
+                continue;
+            }
+            let name = parent.join(f.name());
+            let f = self.ratio_limit(f, &name);
+            if let Err(e) = self.member(&name, f, depth) {
+                self.ratio_error(e)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unpacks a (possibly compressed) tar archive and scans its members.
+    ///
+    /// Link targets of symlink members are considered as well. Member names are prefixed with
+    /// `parent`, which is empty for the top-level archive.
+    ///
+    /// The decompressed stream is charged to the compression ratio budget as a whole, so
+    /// exceeding it is reported for the tar archive rather than for one of its members.
+    fn tar<R: Read>(&mut self, r: R, parent: &Path, depth: usize) -> io::Result<()> {
+        let res = Compression::wrap(r).and_then(|r| {
+            let mut archive = TarArchive::new(self.ratio_limit(r, parent));
+            self.tar_entries(&mut archive, parent, depth)
+        });
+        res.or_else(|e| self.ratio_error(e))
+    }
+
+    /// Scans all tar members.
+    fn tar_entries<R: Read>(
+        &mut self,
+        archive: &mut TarArchive<R>,
+        parent: &Path,
+        depth: usize,
+    ) -> io::Result<()> {
+        for entry in archive.entries()? {
+            if self.exhausted {
+                break;
+            }
+            let entry = entry?;
+            let name = parent.join(OsStr::from_bytes(&entry.path_bytes()));
+            if let Some(target) = entry.link_name_bytes() {
+                let origin = Origin::Archive(name.clone());
+                self.scanner.matcher.find(&target, &origin, &mut self.found);
+            }
+            self.member(&name, entry, depth)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self, meta: fs::Metadata) -> ScanResult {
+        ScanResult {
+            found: self.found,
+            bytes_scanned: meta.len(),
+            meta,
+            bytes_decompressed: self.bytes_unpacked,
+            softerrors: self.softerrors.len(),
+        }
+    }
+}
+
+/// Unpacks a compressed file on the fly and scans the decompressed contents.
+///
+/// Gives up if the decompressed contents exceed `limit` bytes. No limit is imposed if `limit` is 0.
+fn scan_compressed(
+    m: &Matcher,
+    dent: &DirEntry,
+    compression: Compression,
+    limit: u64,
+) -> AResult<ScanResult> {
+    debug!(
+        "Scanning {:?} compressed {}",
+        compression,
+        dent.path().display()
+    );
+    let meta = dent.metadata()?;
+    let decoder = compression.decoder(fs::File::open(dent.path())?);
+    let mut found = Found::default();
+    let bytes_decompressed = if limit > 0 {
+        scan_stream(decoder.take(limit + 1), m, &mut found, &Origin::Compressed)?
+    } else {
+        scan_stream(decoder, m, &mut found, &Origin::Compressed)?
+    };
+    if limit > 0 && bytes_decompressed > limit {
+        return Err(UErr::DecompressLimit(dent.path().to_owned(), ByteSize::b(limit)).into());
+    }
     let bytes_scanned = meta.len();
     Ok(ScanResult {
-        refs,
+        found,
         meta,
         bytes_scanned,
+        bytes_decompressed,
+        softerrors: 0,
     })
 }
 
 /// Scans the symlink's target name (i.e., readlink() output).
-fn scan_symlink(dent: &DirEntry) -> AResult<ScanResult> {
+fn scan_symlink(m: &Matcher, dent: &DirEntry) -> AResult<ScanResult> {
     debug!("Scanning link {}", dent.path().display());
     let meta = dent.metadata()?;
     let target = fs::read_link(dent.path())?;
     let len = target.as_os_str().len() as u64;
-    let refs = match STORE_RE.captures(target.as_os_str().as_bytes()) {
-        Some(cap) => vec![OsStr::from_bytes(&cap[1]).into()],
-        None => vec![],
-    };
+    let mut found = Found::default();
+    if let Some((_, name)) = m.candidates(target.as_os_str().as_bytes()).next() {
+        m.add(&mut found, name, Origin::Symlink);
+    }
     Ok(ScanResult {
-        refs,
+        found,
         meta,
         bytes_scanned: len,
+        bytes_decompressed: 0,
+        softerrors: 0,
     })
 }
 
@@ -157,7 +877,109 @@ impl Scanner {
             quickcheck,
             unzip,
             max_load,
+            ..Self::default()
+        }
+    }
+
+    /// Unpacks files matching `untar` as tar archives and scans inside.
+    pub fn untar(mut self, untar: Override) -> Self {
+        self.untar = untar;
+        self
+    }
+
+    /// Sets limits which apply while unpacking archives.
+    ///
+    /// Archives nested up to `max_nesting` levels deep are unpacked as well. At most
+    /// `unpack_limit` bytes are unpacked from each top-level archive. Unpacking stops once more
+    /// than `max_ratio` times the archive's size has been decompressed. A value of 0 disables the
+    /// respective limit.
+    pub fn archive_limits(
+        mut self,
+        max_nesting: usize,
+        unpack_limit: ByteSize,
+        max_ratio: u64,
+    ) -> Self {
+        self.max_nesting = max_nesting;
+        self.unpack_limit = unpack_limit;
+        self.max_ratio = max_ratio;
+        self
+    }
+
+    /// Enables parsing of ELF binaries.
+    ///
+    /// If `skip_strings` is set, ELF binaries are not scanned completely if references have been
+    /// found in their headers.
+    pub fn elf(mut self, enable: bool, skip_strings: bool) -> Self {
+        self.elf = enable;
+        self.elf_only = skip_strings;
+        self
+    }
+
+    /// Looks for references into `store_dir` instead of the default Nix store.
+    pub fn store_dir(mut self, store_dir: &str) -> Self {
+        self.matcher = Matcher {
+            lenient: self.matcher.lenient,
+            ..Matcher::new(store_dir)
+        };
+        self
+    }
+
+    /// Accepts store references which violate Nix' naming rules if `lenient` is set.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.matcher.lenient = lenient;
+        self
+    }
+
+    /// Unpacks a top-level archive on the fly and scans its contents.
+    fn scan_archive(&self, dent: &DirEntry, kind: ArchiveKind) -> AResult<ScanResult> {
+        debug!("Scanning {:?} archive {}", kind, dent.path().display());
+        let meta = dent.metadata()?;
+        let file = fs::File::open(dent.path())?;
+        let mut unpacker = Unpacker::new(self, dent.path(), meta.len());
+        match kind {
+            ArchiveKind::Zip => unpacker
+                .zip(file, Path::new(""), 0)
+                .map_err(|e| UErr::ZIP(dent.path().to_owned(), e))?,
+            ArchiveKind::Tar => unpacker
+                .tar(file, Path::new(""), 0)
+                .map_err(|e| UErr::Tar(dent.path().to_owned(), e))?,
+        }
+        Ok(unpacker.finish(meta))
+    }
+
+    /// Enables transparent decompression of gzip, bzip2 and xz compressed files.
+    ///
+    /// Compressed files which unpack to more than `limit` are skipped (0 means no limit).
+    pub fn decompress(mut self, enable: bool, limit: ByteSize) -> Self {
+        self.decompress = enable;
+        self.decompress_limit = limit;
+        self
+    }
+
+    /// Selects a scan strategy for a regular file.
+    fn scan_file(&self, dent: &DirEntry) -> AResult<ScanResult> {
+        if !self.unzip.is_empty() {
+            if let Match::Whitelist(_) = self.unzip.matched(dent.path(), false) {
+                return self.scan_archive(dent, ArchiveKind::Zip);
+            }
+        }
+        if !self.untar.is_empty() {
+            if let Match::Whitelist(_) = self.untar.matched(dent.path(), false) {
+                return self.scan_archive(dent, ArchiveKind::Tar);
+            }
+        }
+        if self.decompress || self.elf {
+            let head = read_head(dent.path())?;
+            if self.decompress {
+                if let Some(c) = Compression::detect(&head) {
+                    return scan_compressed(&self.matcher, dent, c, self.decompress_limit.as_u64());
+                }
+            }
+            if self.elf && head.starts_with(ELF_MAGIC) {
+                return scan_elf(&self.matcher, dent, self.quickcheck, self.elf_only);
+            }
         }
+        scan_regular(&self.matcher, dent, self.quickcheck)
     }
 
     /// Scans a thing that has a file type.
@@ -165,15 +987,10 @@ impl Scanner {
     /// Returns Some(result) if a scan strategy was found, None otherwise.
     fn scan_inode(&self, dent: &DirEntry, ft: fs::FileType) -> Option<AResult<ScanResult>> {
         if ft.is_file() {
-            if !self.unzip.is_empty() {
-                if let Match::Whitelist(_) = self.unzip.matched(dent.path(), false) {
-                    return Some(scan_zip_archive(dent));
-                }
-            }
-            return Some(scan_regular(dent, self.quickcheck));
+            return Some(self.scan_file(dent));
         }
         if ft.is_symlink() {
-            return Some(scan_symlink(dent));
+            return Some(scan_symlink(&self.matcher, dent));
         }
         None
     }
@@ -217,9 +1034,12 @@ impl Scanner {
             self.pause_for_load();
         }
         self.scan(&dent).map(|mut r| {
-            r.refs.sort();
-            r.refs.dedup();
-            StorePaths::new(dent, r.refs, r.bytes_scanned, Some(r.meta))
+            r.found.refs.sort();
+            r.found.refs.dedup();
+            StorePaths::new(dent, r.found.refs, r.bytes_scanned, Some(r.meta))
+                .with_bytes_decompressed(r.bytes_decompressed)
+                .with_softerrors(r.softerrors)
+                .with_rejected(r.found.rejected)
         })
     }
 }
@@ -228,33 +1048,217 @@ impl Scanner {
 mod tests {
     use super::*;
     use crate::tests::{assert_eq_vecs, dent, FIXTURES};
+    use bzip2::write::BzEncoder;
+    use flate2::write::GzEncoder;
     use ignore::overrides::OverrideBuilder;
+    use std::io::Write;
     use std::path::Path;
+    use tar::{Builder as TarBuilder, EntryType, Header};
+    use tempfile::TempDir;
+    use xz2::write::XzEncoder;
+    use zip::write::{FileOptions, ZipWriter};
+
+    const PAYLOAD: &[u8] = b"#!/nix/store/xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23/bin/sh\n";
+    const REPEAT: u64 = 20;
+    const PAYLOAD_LEN: u64 = PAYLOAD.len() as u64 * REPEAT;
+
+    fn write_compressed(path: &Path, c: Compression) {
+        let f = fs::File::create(path).unwrap();
+        let mut w: Box<dyn Write> = match c {
+            Compression::Gzip => Box::new(GzEncoder::new(f, flate2::Compression::default())),
+            Compression::Bzip2 => Box::new(BzEncoder::new(f, bzip2::Compression::default())),
+            Compression::Xz => Box::new(XzEncoder::new(f, 6)),
+        };
+        // repeated so that the payload gets really compressed and not stored as-is
+        for _ in 0..REPEAT {
+            w.write_all(PAYLOAD).unwrap();
+        }
+    }
+
+    #[test]
+    fn scan_stream_should_find_refs_across_chunk_boundaries() {
+        let storeref = &PAYLOAD[2..58];
+        let first = Matcher::default().max_len() + CHUNK_SIZE;
+        let second = first + CHUNK_SIZE;
+        for boundary in &[first, second] {
+            for offset in boundary - storeref.len() - 2..boundary + 2 {
+                let mut data = vec![b' '; 3 * CHUNK_SIZE];
+                data[offset..offset + storeref.len()].copy_from_slice(storeref);
+                let mut found = Found::default();
+                assert_eq!(
+                    data.len() as u64,
+                    scan_stream(&data[..], &Matcher::default(), &mut found, &Origin::Text).unwrap()
+                );
+                assert_eq!(
+                    vec![StoreRef::new(
+                        "xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23",
+                        Origin::Text
+                    )],
+                    found.refs,
+                    "offset {}",
+                    offset
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn should_validate_store_path_names() {
+        let name = |hash: &str, name: &str| format!("{}-{}", hash, name).into_bytes();
+        let hash = "xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp";
+        assert!(Matcher::is_valid(&name(hash, "bash-4.4-p23")));
+        assert!(Matcher::is_valid(&name(hash, &"x".repeat(MAX_NAME_LEN))));
+        // e, o, t and u are not part of the Nix base32 alphabet
+        for c in &["e", "o", "t", "u"] {
+            assert!(!Matcher::is_valid(&name(&hash.replace("x", c), "bash")));
+        }
+        assert!(!Matcher::is_valid(&name(
+            hash,
+            &"x".repeat(MAX_NAME_LEN + 1)
+        )));
+        assert!(!Matcher::is_valid(&name(hash, ".bash")));
+    }
+
+    #[test]
+    fn should_reject_invalid_store_paths_unless_lenient() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("minified.js");
+        fs::write(
+            &path,
+            "var a=\"/nix/store/abcdefghijklmnopqrstuvwxyz012345-foo\";\n\
+             var b=\"/nix/store/xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23\";\n",
+        )
+        .unwrap();
+        let sp = Scanner::default().find_paths(dent(&path)).unwrap();
+        assert_eq!(
+            vec![Path::new("xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23")],
+            sp.refs()
+        );
+        assert_eq!(1, sp.rejected());
+
+        let sp = Scanner::default()
+            .lenient(true)
+            .find_paths(dent(&path))
+            .unwrap();
+        assert_eq!(2, sp.refs().len());
+        assert_eq!(0, sp.rejected());
+    }
+
+    #[test]
+    fn should_find_refs_in_custom_store_dir() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("script");
+        fs::write(
+            &path,
+            "#!/home/joe/.nix/store/xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23/bin/sh\n\
+             exec /nix/store/q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24/bin/ldd\n",
+        )
+        .unwrap();
+        let sp = Scanner::default()
+            .store_dir("/home/joe/.nix/store")
+            .find_paths(dent(&path))
+            .unwrap();
+        assert_eq!(
+            vec![Path::new("xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23")],
+            sp.refs()
+        );
+        // quickcheck looks for the configured store dir as well
+        let mut scanner = Scanner::default().store_dir("/opt/store");
+        scanner.quickcheck = ByteSize::b(50);
+        assert!(scanner.find_paths(dent(&path)).unwrap().refs().is_empty());
+    }
 
     #[test]
     fn should_not_look_further_than_quickcheck() {
         let mut scanner = Scanner::default();
         assert_eq_vecs(
-            scanner
-                .find_paths(dent("dir2/lftp.offset"))
-                .unwrap()
-                .refs()
-                .to_vec(),
+            scanner.find_paths(dent("dir2/lftp.offset")).unwrap().refs(),
             |path| path.to_string_lossy().into_owned(),
             &["q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24"],
         );
         scanner.quickcheck = ByteSize::kib(4);
         assert_eq_vecs(
-            scanner
-                .find_paths(dent("dir2/lftp.offset"))
-                .unwrap()
-                .refs()
-                .to_vec(),
+            scanner.find_paths(dent("dir2/lftp.offset")).unwrap().refs(),
             |path| path.to_string_lossy().into_owned(),
             &[],
         );
     }
 
+    #[test]
+    fn should_tell_shebang_from_text() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("script");
+        let mut script = PAYLOAD.to_vec();
+        script.extend_from_slice(
+            b"exec /nix/store/q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24/bin/ldd\n",
+        );
+        fs::write(&path, script).unwrap();
+        let sp = Scanner::default().find_paths(dent(&path)).unwrap();
+        assert_eq!(
+            vec![
+                (
+                    Path::new("q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24"),
+                    vec![&Origin::Text]
+                ),
+                (
+                    Path::new("xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23"),
+                    vec![&Origin::Shebang]
+                ),
+            ],
+            sp.iter_origins().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_parse_elf_headers() {
+        let all = &[
+            "1b4i3gm31j1ipfbx1v9a3hhgmp2wvyyw-python2.7-six-1.9.0",
+            "9w3ci6fskmz3nw27fb68hybfa5v1r33f-libidn-1.33",
+            "knvydciispmr4nr2rxg0iyyff3n1v4ax-gcc-6.2.0-lib",
+            "q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24",
+        ];
+        let sp = Scanner::default()
+            .elf(true, false)
+            .find_paths(dent("elf/hello"))
+            .unwrap();
+        assert_eq!(all.iter().map(Path::new).collect::<Vec<_>>(), sp.refs());
+
+        // string constant is not found in headers
+        let sp = Scanner::default()
+            .elf(true, true)
+            .find_paths(dent("elf/hello"))
+            .unwrap();
+        assert_eq!(
+            all[1..].iter().map(Path::new).collect::<Vec<_>>(),
+            sp.refs()
+        );
+        assert_eq!(0, sp.bytes_scanned());
+    }
+
+    #[test]
+    fn truncated_elf_should_be_scanned_normally() {
+        let elf = Path::new("q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24");
+        assert_eq!(
+            Some(vec![StoreRef::new(elf, Origin::ElfInterpreter)]),
+            elf_refs(
+                &Matcher::default(),
+                &fs::read(FIXTURES.join("elf/hello")).unwrap()
+            )
+            .map(|found| found.refs.into_iter().take(1).collect())
+        );
+        // truncated binary: fall back to normal scan
+        assert!(elf_refs(
+            &Matcher::default(),
+            &fs::read(FIXTURES.join("dir2/lftp")).unwrap()
+        )
+        .is_none());
+        let sp = Scanner::default()
+            .elf(true, true)
+            .find_paths(dent("dir2/lftp"))
+            .unwrap();
+        assert_eq!(vec![elf], sp.refs());
+    }
+
     #[test]
     fn should_unpack_eggs() {
         let sp = Scanner::default()
@@ -272,8 +1276,411 @@ mod tests {
             .unwrap();
         assert_eq!(
             vec![Path::new("76lhp1gvc3wbl6q4p2qgn2n7245imyvr-perl-5.22.3")],
-            *sp.refs()
+            sp.refs()
         );
         assert_eq!(2226, sp.bytes_scanned());
     }
+
+    #[test]
+    fn detect_compression_by_magic() {
+        assert_eq!(
+            Some(Compression::Gzip),
+            Compression::detect(b"\x1f\x8b\x08\x00")
+        );
+        assert_eq!(Some(Compression::Bzip2), Compression::detect(b"BZh91AY"));
+        assert_eq!(
+            Some(Compression::Xz),
+            Compression::detect(b"\xfd7zXZ\x00\x00")
+        );
+        assert_eq!(None, Compression::detect(b"\x1f"));
+        assert_eq!(None, Compression::detect(b"#!/bin/sh"));
+    }
+
+    #[test]
+    fn should_scan_inside_compressed_files() {
+        let td = TempDir::new().unwrap();
+        for (c, name) in &[
+            (Compression::Gzip, "data.gz"),
+            (Compression::Bzip2, "data.bz2"),
+            // magic bytes count, not the extension
+            (Compression::Xz, "data.txt"),
+        ] {
+            let path = td.path().join(name);
+            write_compressed(&path, *c);
+
+            let sp = Scanner::default().find_paths(dent(&path)).unwrap();
+            assert!(sp.refs().is_empty());
+
+            let sp = Scanner::default()
+                .decompress(true, ByteSize::kib(4))
+                .find_paths(dent(&path))
+                .unwrap();
+            assert_eq!(
+                vec![Path::new("xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23")],
+                sp.refs(),
+                "{:?}",
+                c
+            );
+            assert_eq!(fs::metadata(&path).unwrap().len(), sp.bytes_scanned());
+            assert_eq!(PAYLOAD_LEN, sp.bytes_decompressed());
+        }
+    }
+
+    #[test]
+    fn should_obey_decompress_limit() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("data.gz");
+        write_compressed(&path, Compression::Gzip);
+        let scanner = Scanner::default().decompress(true, ByteSize::b(PAYLOAD_LEN - 1));
+        let err = scanner.find_paths(dent(&path)).unwrap_err();
+        match err.downcast_ref::<UErr>() {
+            Some(UErr::DecompressLimit(..)) => (),
+            _ => panic!("unexpected error: {:#}", err),
+        }
+        let scanner = Scanner::default().decompress(true, ByteSize::b(PAYLOAD_LEN));
+        assert!(scanner.find_paths(dent(&path)).is_ok());
+    }
+
+    fn write_tarball<W: Write>(w: W) {
+        let mut tb = TarBuilder::new(w);
+        let mut h = Header::new_gnu();
+        h.set_size(PAYLOAD.len() as u64);
+        h.set_mode(0o755);
+        tb.append_data(&mut h, "bin/run", PAYLOAD).unwrap();
+        let mut h = Header::new_gnu();
+        h.set_entry_type(EntryType::Symlink);
+        h.set_size(0);
+        tb.append_link(
+            &mut h,
+            "lib/libc.so.6",
+            "/nix/store/q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24/lib/libc.so.6",
+        )
+        .unwrap();
+        tb.into_inner().unwrap().flush().unwrap();
+    }
+
+    #[test]
+    fn should_unpack_tarballs() {
+        let td = TempDir::new().unwrap();
+        write_tarball(fs::File::create(td.path().join("bundle.tar")).unwrap());
+        write_tarball(GzEncoder::new(
+            fs::File::create(td.path().join("bundle.tgz")).unwrap(),
+            flate2::Compression::default(),
+        ));
+        let untar = OverrideBuilder::new(td.path())
+            .add("*.tar")
+            .unwrap()
+            .add("*.tgz")
+            .unwrap()
+            .build()
+            .unwrap();
+        let scanner = Scanner::default().untar(untar);
+
+        let sp = Scanner::default()
+            .find_paths(dent(td.path().join("bundle.tgz")))
+            .unwrap();
+        assert!(sp.refs().is_empty());
+
+        for name in &["bundle.tar", "bundle.tgz"] {
+            let sp = scanner.find_paths(dent(td.path().join(name))).unwrap();
+            assert_eq!(
+                vec![
+                    Path::new("q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24"),
+                    Path::new("xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23"),
+                ],
+                sp.refs(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn softfail_on_broken_tarball() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("broken.tar");
+        fs::write(&path, &[0x42; 700][..]).unwrap();
+        let untar = OverrideBuilder::new(td.path())
+            .add("*.tar")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(Scanner::default()
+            .untar(untar)
+            .find_paths(dent(&path))
+            .is_err());
+    }
+
+    /// Creates a ZIP archive in memory. All members are deflated.
+    fn zip_bytes(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zw = ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, data) in members {
+            zw.start_file(*name, FileOptions::default()).unwrap();
+            zw.write_all(data).unwrap();
+        }
+        zw.finish().unwrap().into_inner()
+    }
+
+    fn payload() -> Vec<u8> {
+        PAYLOAD.repeat(REPEAT as usize)
+    }
+
+    fn nested_scanner(root: &Path) -> Scanner {
+        let unzip = OverrideBuilder::new(root)
+            .add("*.zip")
+            .unwrap()
+            .add("*.whl")
+            .unwrap()
+            .build()
+            .unwrap();
+        let untar = OverrideBuilder::new(root)
+            .add("*.tar.gz")
+            .unwrap()
+            .build()
+            .unwrap();
+        Scanner::new(ByteSize::default(), unzip, 0.0)
+            .untar(untar)
+            .archive_limits(4, ByteSize::default(), 0)
+    }
+
+    #[test]
+    fn should_unpack_nested_archives() {
+        let td = TempDir::new().unwrap();
+        let mut tgz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        write_tarball(&mut tgz);
+        let whl = zip_bytes(&[("pkg/mod.py", &payload())]);
+        let path = td.path().join("outer.zip");
+        fs::write(
+            &path,
+            zip_bytes(&[
+                ("deps/pkg-1.0-py3-none-any.whl", &whl),
+                ("bundle.tar.gz", &tgz.finish().unwrap()),
+            ]),
+        )
+        .unwrap();
+
+        let sp = nested_scanner(td.path()).find_paths(dent(&path)).unwrap();
+        assert_eq!(
+            vec![
+                Path::new("q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24"),
+                Path::new("xr9kxfx8j8rzb9c4kzal2yn3d8zv7jqp-bash-4.4-p23"),
+            ],
+            sp.refs()
+        );
+        assert_eq!(
+            vec![
+                vec![Origin::Archive("bundle.tar.gz/lib/libc.so.6".into())],
+                vec![
+                    Origin::Archive("bundle.tar.gz/bin/run".into()),
+                    Origin::Archive("deps/pkg-1.0-py3-none-any.whl/pkg/mod.py".into()),
+                ],
+            ],
+            sp.iter_origins()
+                .map(|(_, o)| o.into_iter().cloned().collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0, sp.softerrors());
+
+        // nested archives are opaque if nesting is disabled
+        let sp = nested_scanner(td.path())
+            .archive_limits(0, ByteSize::default(), 0)
+            .find_paths(dent(&path))
+            .unwrap();
+        assert!(sp.refs().is_empty());
+        assert_eq!(0, sp.softerrors());
+    }
+
+    #[test]
+    fn nesting_too_deep_should_softfail() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("l0.zip");
+        let l2 = zip_bytes(&[("file", &payload())]);
+        let l1 = zip_bytes(&[("l2.zip", &l2)]);
+        fs::write(&path, zip_bytes(&[("l1.zip", &l1), ("plain", PAYLOAD)])).unwrap();
+
+        let sp = nested_scanner(td.path())
+            .archive_limits(1, ByteSize::default(), 0)
+            .find_paths(dent(&path))
+            .unwrap();
+        // only found in `plain`
+        assert_eq!(1, sp.refs().len());
+        assert_eq!(1, sp.softerrors());
+    }
+
+    #[test]
+    fn unpack_limit_should_softfail() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("big.zip");
+        fs::write(
+            &path,
+            zip_bytes(&[
+                ("first", PAYLOAD),
+                ("second", &payload()),
+                ("third", PAYLOAD),
+            ]),
+        )
+        .unwrap();
+
+        let sp = nested_scanner(td.path())
+            .archive_limits(4, ByteSize::b(PAYLOAD_LEN), 0)
+            .find_paths(dent(&path))
+            .unwrap();
+        assert_eq!(1, sp.refs().len());
+        assert_eq!(1, sp.softerrors());
+        assert_eq!(PAYLOAD.len() as u64, sp.bytes_decompressed());
+    }
+
+    #[test]
+    fn compression_ratio_should_softfail() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("bomb.zip");
+        let mut zeroes = PAYLOAD.to_vec();
+        zeroes.resize(1 << 20, 0);
+        fs::write(&path, zip_bytes(&[("plain", PAYLOAD), ("zeroes", &zeroes)])).unwrap();
+
+        let sp = nested_scanner(td.path())
+            .archive_limits(4, ByteSize::default(), 100)
+            .find_paths(dent(&path))
+            .unwrap();
+        assert_eq!(1, sp.refs().len());
+        assert_eq!(1, sp.softerrors());
+        assert_eq!(PAYLOAD.len() as u64, sp.bytes_decompressed());
+    }
+
+    #[test]
+    fn compression_ratio_should_not_rely_on_declared_size() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("liar.zip");
+        let mut zip = zip_bytes(&[("plain", PAYLOAD), ("zeroes", &vec![0; 1 << 20])]);
+        // declare the second member's size to be its compressed size in local and central headers
+        for (sig, csize, usize) in &[(b"PK\x03\x04", 18, 22), (b"PK\x01\x02", 20, 24)] {
+            let pos = zip.windows(4).rposition(|w| w == &sig[..]).unwrap();
+            let compressed = zip[pos + csize..pos + csize + 4].to_vec();
+            zip[pos + usize..pos + usize + 4].copy_from_slice(&compressed);
+        }
+        fs::write(&path, zip).unwrap();
+
+        let sp = nested_scanner(td.path())
+            .archive_limits(4, ByteSize::default(), 100)
+            .find_paths(dent(&path))
+            .unwrap();
+        assert_eq!(1, sp.refs().len());
+        assert_eq!(1, sp.softerrors());
+        assert_eq!(PAYLOAD.len() as u64, sp.bytes_decompressed());
+    }
+
+    #[test]
+    fn compression_ratio_should_apply_to_tar_streams() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("bomb.tar.gz");
+        let mut tb = TarBuilder::new(GzEncoder::new(
+            fs::File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        ));
+        let mut h = Header::new_gnu();
+        h.set_size(PAYLOAD.len() as u64);
+        tb.append_data(&mut h, "plain", PAYLOAD).unwrap();
+        let zeroes = vec![0; 1 << 20];
+        h.set_size(zeroes.len() as u64);
+        tb.append_data(&mut h, "zeroes", &zeroes[..]).unwrap();
+        tb.into_inner().unwrap().finish().unwrap();
+
+        let sp = nested_scanner(td.path())
+            .archive_limits(4, ByteSize::default(), 100)
+            .find_paths(dent(&path))
+            .unwrap();
+        assert_eq!(1, sp.refs().len());
+        assert_eq!(1, sp.softerrors());
+
+        let sp = nested_scanner(td.path()).find_paths(dent(&path)).unwrap();
+        assert_eq!(0, sp.softerrors());
+    }
+
+    #[test]
+    fn compression_ratio_should_be_reported_for_nested_member() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("bundle.tar.gz");
+        let inner = zip_bytes(&[("zeroes", &vec![0; 1 << 20])]);
+        let mut tb = TarBuilder::new(GzEncoder::new(
+            fs::File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        ));
+        let mut h = Header::new_gnu();
+        h.set_size(PAYLOAD.len() as u64);
+        tb.append_data(&mut h, "plain", PAYLOAD).unwrap();
+        h.set_size(inner.len() as u64);
+        tb.append_data(&mut h, "inner.zip", &inner[..]).unwrap();
+        tb.into_inner().unwrap().finish().unwrap();
+
+        let scanner = nested_scanner(td.path()).archive_limits(4, ByteSize::default(), 100);
+        let size = fs::metadata(&path).unwrap().len();
+        let mut unpacker = Unpacker::new(&scanner, &path, size);
+        unpacker
+            .tar(fs::File::open(&path).unwrap(), Path::new(""), 0)
+            .unwrap();
+        assert_eq!(vec![PathBuf::from("inner.zip/zeroes")], unpacker.softerrors);
+        assert_eq!(1, unpacker.found.refs.len());
+    }
+
+    fn config() -> ScanConfig {
+        ScanConfig {
+            quickcheck: 512 << 10,
+            unzip: vec!["*.zip".to_owned()].into_iter().collect(),
+            max_nesting: 4,
+            unpack_limit: 1 << 30,
+            max_ratio: 250,
+            text: true,
+            ..ScanConfig::default()
+        }
+    }
+
+    #[test]
+    fn stronger_config_should_cover_weaker() {
+        let c = config();
+        assert!(c.covers(&c));
+        let full = ScanConfig {
+            quickcheck: 0,
+            ..config()
+        };
+        assert!(full.covers(&c));
+        assert!(!c.covers(&full));
+        let more_zip = ScanConfig {
+            unzip: vec!["*.zip".to_owned(), "*.egg".to_owned()]
+                .into_iter()
+                .collect(),
+            ..config()
+        };
+        assert!(more_zip.covers(&c));
+        assert!(!c.covers(&more_zip));
+        let unlimited = ScanConfig {
+            unpack_limit: 0,
+            max_ratio: 0,
+            ..config()
+        };
+        assert!(unlimited.covers(&c));
+        assert!(!c.covers(&unlimited));
+        let decompress = ScanConfig {
+            decompress: Some(100 << 20),
+            ..config()
+        };
+        assert!(decompress.covers(&c));
+        assert!(!c.covers(&decompress));
+        let elf_only = ScanConfig {
+            text: false,
+            elf: true,
+            ..config()
+        };
+        assert!(!elf_only.covers(&c));
+        assert!(!c.covers(&elf_only));
+    }
+
+    #[test]
+    fn legacy_config_should_cover_defaults_only() {
+        let defaults = ScanConfig {
+            unzip: BTreeSet::new(),
+            ..config()
+        };
+        assert!(ScanConfig::legacy().covers(&defaults));
+        assert!(!ScanConfig::legacy().covers(&config()));
+    }
 }
@@ -1,8 +1,10 @@
 use crate::output::{d2s, p2s};
+use crate::policy::Skipped;
 use crate::storepaths::StorePaths;
 use atty::{self, Stream};
 use bytesize::ByteSize;
 use colored::Colorize;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::hash::Hash;
@@ -48,12 +50,16 @@ impl AddAssign<u64> for Pair {
 #[derive(Debug, Clone)]
 pub enum StatsMsg {
     SoftError,
+    /// Left out due to an error; counts as soft error
+    Skipped(Skipped),
     Scan(File),
 }
 
 #[derive(Debug, Clone)]
 pub struct File {
     scanned: u64,
+    decompressed: u64,
+    rejected: usize,
     ext: OsString,
 }
 
@@ -65,6 +71,8 @@ impl<'a> From<&'a StorePaths> for File {
         };
         File {
             scanned: sp.bytes_scanned(),
+            decompressed: sp.bytes_decompressed(),
+            rejected: sp.rejected(),
             ext,
         }
     }
@@ -86,10 +94,29 @@ where
     res
 }
 
+/// Totals of a whole run, e.g. for machine-readable output.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub files: usize,
+    pub bytes_read: u64,
+    pub files_decompressed: usize,
+    pub bytes_decompressed: u64,
+    pub softerrors: usize,
+    pub rejected: usize,
+    /// Paths left out due to errors
+    pub skipped: Vec<Skipped>,
+    /// Wall clock time in seconds
+    pub elapsed: f32,
+}
+
 #[derive(Debug)]
 pub struct Statistics {
     pub softerrors: usize,
     pub total: Pair,
+    pub decompressed: Pair,
+    /// Store path candidates which violate Nix' naming rules
+    pub rejected: usize,
+    skipped: Vec<Skipped>,
     by_ext: HashMap<OsString, Pair>,
     rx: Option<mpsc::Receiver<StatsMsg>>,
     start: time::Instant,
@@ -105,6 +132,9 @@ impl Statistics {
         Statistics {
             softerrors: 0,
             total: Pair::default(),
+            decompressed: Pair::default(),
+            rejected: 0,
+            skipped: Vec::new(),
             by_ext: HashMap::new(),
             rx: None,
             start: time::Instant::now(),
@@ -118,16 +148,29 @@ impl Statistics {
         self.softerrors
     }
 
+    /// Files and directories which have been left out due to errors
+    pub fn skipped(&self) -> &[Skipped] {
+        &self.skipped
+    }
+
     fn process(&mut self, msg: StatsMsg) {
         match msg {
             StatsMsg::Scan(f) => {
                 self.total += f.scanned;
+                if f.decompressed > 0 {
+                    self.decompressed += f.decompressed;
+                }
+                self.rejected += f.rejected;
                 if self.detailed {
-                    let by_ext = self.by_ext.entry(f.ext).or_insert_with(Pair::default);
+                    let by_ext = self.by_ext.entry(f.ext).or_default();
                     *by_ext += f.scanned;
                 }
             }
             StatsMsg::SoftError => self.softerrors += 1,
+            StatsMsg::Skipped(s) => {
+                self.softerrors += 1;
+                self.skipped.push(s);
+            }
         }
     }
 
@@ -186,6 +229,19 @@ impl Statistics {
         println!();
     }
 
+    pub fn summary(&self) -> Summary {
+        Summary {
+            files: self.total.files,
+            bytes_read: self.total.bytes,
+            files_decompressed: self.decompressed.files,
+            bytes_decompressed: self.decompressed.bytes,
+            softerrors: self.softerrors,
+            rejected: self.rejected,
+            skipped: self.skipped.clone(),
+            elapsed: d2s(self.start.elapsed()),
+        }
+    }
+
     pub fn log_summary<P: AsRef<Path>>(&self, startdir: P) {
         let elapsed = self.start.elapsed();
         info!(
@@ -195,9 +251,35 @@ impl Statistics {
             d2s(elapsed).to_string().cyan(),
             " s".cyan()
         );
+        if self.decompressed.files > 0 {
+            info!(
+                "Decompressed {} files ({} unpacked)",
+                self.decompressed.files.to_string().cyan(),
+                ByteSize::b(self.decompressed.bytes)
+            );
+        }
+        if self.rejected > 0 {
+            info!(
+                "Rejected {} invalid store path candidate(s)",
+                self.rejected.to_string().cyan()
+            );
+        }
         if self.detailed {
             self.print_details()
         }
+        if !self.skipped.is_empty() {
+            warn!("Skipped {} path(s) due to errors:", self.skipped.len());
+            let mut skipped: Vec<&Skipped> = self.skipped.iter().collect();
+            skipped.sort_by(|a, b| a.path.cmp(&b.path));
+            for s in skipped {
+                warn!(
+                    "  {} ({} error{})",
+                    p2s(&s.path),
+                    s.class,
+                    if s.subtree { ", whole subtree" } else { "" }
+                );
+            }
+        }
         let dir = p2s(startdir.as_ref());
         if self.softerrors > 0 {
             warn!(
@@ -215,15 +297,49 @@ impl Statistics {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::policy::ErrorClass;
     use crate::tests::assert_eq_vecs;
+    use std::path::PathBuf;
 
     fn _msg_read(bytes: u64, ext: &str) -> StatsMsg {
         StatsMsg::Scan(File {
             scanned: bytes,
+            decompressed: 0,
+            rejected: 0,
             ext: ext.into(),
         })
     }
 
+    fn _msg_decompressed(bytes: u64, decompressed: u64) -> StatsMsg {
+        StatsMsg::Scan(File {
+            scanned: bytes,
+            decompressed,
+            rejected: 0,
+            ext: "gz".into(),
+        })
+    }
+
+    #[test]
+    fn skipped_should_count_as_softerror() {
+        let mut s = Statistics::new(false, false);
+        s.process(StatsMsg::Skipped(Skipped {
+            path: PathBuf::from("/srv/file"),
+            class: ErrorClass::Other,
+            subtree: false,
+        }));
+        s.process(StatsMsg::Skipped(Skipped {
+            path: PathBuf::from("/srv/dir"),
+            class: ErrorClass::Permission,
+            subtree: true,
+        }));
+        assert_eq!(2, s.softerrors());
+        assert_eq!(
+            vec![false, true],
+            s.skipped().iter().map(|s| s.subtree).collect::<Vec<_>>()
+        );
+        assert_eq!(2, s.summary().skipped.len());
+    }
+
     #[test]
     fn add_single_item_with_details() {
         let mut s = Statistics::new(true, false);
@@ -239,6 +355,29 @@ mod tests {
         assert_eq!(s.by_ext.len(), 0);
     }
 
+    #[test]
+    fn account_decompressed_separately() {
+        let mut s = Statistics::new(false, false);
+        s.process(_msg_read(100, "txt"));
+        s.process(_msg_decompressed(40, 350));
+        assert_eq!(s.total, Pair::new(2, 140));
+        assert_eq!(s.decompressed, Pair::new(1, 350));
+    }
+
+    #[test]
+    fn sum_up_rejected_candidates() {
+        let mut s = Statistics::new(false, false);
+        for rejected in &[2, 0, 1] {
+            s.process(StatsMsg::Scan(File {
+                scanned: 100,
+                decompressed: 0,
+                rejected: *rejected,
+                ext: "js".into(),
+            }));
+        }
+        assert_eq!(3, s.rejected);
+    }
+
     #[test]
     fn add_softerrors() {
         let mut s = Statistics::new(false, false);
@@ -0,0 +1,108 @@
+//! Existence checks for Nix store paths.
+//!
+//! Found references may point to store paths which have been garbage collected long ago or which
+//! never existed at all. Each store path is looked up at most once per run.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default)]
+pub struct StoreCheck {
+    store: PathBuf,
+    known: HashMap<PathBuf, bool>,
+}
+
+impl StoreCheck {
+    /// Creates an existence checker for store paths below `store` (e.g., /nix/store).
+    pub fn new<P: Into<PathBuf>>(store: P) -> Self {
+        StoreCheck {
+            store: store.into(),
+            known: HashMap::new(),
+        }
+    }
+
+    fn lookup(&self, storepath: &Path) -> bool {
+        fs::symlink_metadata(self.store.join(storepath)).is_ok()
+    }
+
+    /// Looks up all given store paths which have not been seen before in one go.
+    ///
+    /// Store paths are checked in sorted order, so that each is only looked up once and
+    /// directory lookups benefit from locality.
+    pub fn prefetch<'a, I>(&mut self, storepaths: I)
+    where
+        I: IntoIterator<Item = &'a Path>,
+    {
+        let todo: BTreeSet<&Path> = storepaths
+            .into_iter()
+            .filter(|p| !self.known.contains_key(*p))
+            .collect();
+        debug!("Checking {} store paths for existence", todo.len());
+        for p in todo {
+            let exists = self.lookup(p);
+            self.known.insert(p.to_owned(), exists);
+        }
+    }
+
+    /// Returns true if `storepath` (relative to the store dir) exists.
+    pub fn exists(&mut self, storepath: &Path) -> bool {
+        if let Some(exists) = self.known.get(storepath) {
+            return *exists;
+        }
+        let exists = self.lookup(storepath);
+        self.known.insert(storepath.to_owned(), exists);
+        exists
+    }
+
+    /// All store paths which have been found missing so far, sorted.
+    pub fn missing(&self) -> Vec<&Path> {
+        let mut missing: Vec<&Path> = self
+            .known
+            .iter()
+            .filter(|(_, exists)| !**exists)
+            .map(|(p, _)| p.as_path())
+            .collect();
+        missing.sort();
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn should_tell_existing_from_missing_store_paths() {
+        let td = TempDir::new().unwrap();
+        fs::create_dir(td.path().join("11111111111111111111111111111111-foo")).unwrap();
+        let mut check = StoreCheck::new(td.path());
+        check.prefetch(vec![
+            Path::new("22222222222222222222222222222222-bar"),
+            Path::new("11111111111111111111111111111111-foo"),
+            Path::new("22222222222222222222222222222222-bar"),
+        ]);
+        assert_eq!(2, check.known.len());
+        assert!(check.exists(Path::new("11111111111111111111111111111111-foo")));
+        assert!(!check.exists(Path::new("22222222222222222222222222222222-bar")));
+        assert!(!check.exists(Path::new("33333333333333333333333333333333-baz")));
+        assert_eq!(
+            vec![
+                Path::new("22222222222222222222222222222222-bar"),
+                Path::new("33333333333333333333333333333333-baz"),
+            ],
+            check.missing()
+        );
+    }
+
+    #[test]
+    fn results_should_be_cached() {
+        let td = TempDir::new().unwrap();
+        let mut check = StoreCheck::new(td.path());
+        let p = Path::new("11111111111111111111111111111111-foo");
+        assert!(!check.exists(p));
+        fs::create_dir(td.path().join(p)).unwrap();
+        assert!(!check.exists(p));
+    }
+}
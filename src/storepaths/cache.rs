@@ -3,14 +3,16 @@
 //! The cache persists scan results between `userscan` invocations so that unchanged files don't
 //! need to be scanned again. It is currently saved as compressed MessagePack file.
 
-use super::{Lookup, StorePaths};
+use super::{Lookup, StorePaths, StoreRef};
 use crate::cachemap::*;
 use crate::errors::*;
 use crate::output::p2s;
+use crate::scan::ScanConfig;
 use crate::system::ExecutionContext;
 use colored::Colorize;
 use ignore::DirEntry;
 use std::fs;
+use std::mem;
 use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
@@ -25,14 +27,57 @@ pub struct Cache {
     hits: AtomicUsize,
     misses: AtomicUsize,
     limit: usize,
+    /// Records file paths in cache lines
+    paths: bool,
+    /// Describes the current run; cache files written under other circumstances are discarded
+    header: Header,
+    /// Scanner configuration of the current run
+    config: ScanConfig,
+    /// Index of `config` in the cache's configuration table
+    config_id: u32,
+    /// Tells for each configuration in the cache's table if its results can be reused
+    valid: Vec<bool>,
+    /// Tells if results without recorded configuration can be reused
+    legacy_valid: bool,
 }
 
 impl Cache {
     pub fn new(limit: Option<usize>) -> Self {
-        Cache {
+        let mut cache = Cache {
             limit: limit.unwrap_or(0),
             ..Self::default()
-        }
+        };
+        cache.update_configs();
+        cache
+    }
+
+    /// Sets the scanner configuration of the current run.
+    ///
+    /// Cached results which have been produced under a weaker configuration are treated as
+    /// misses.
+    pub fn with_config(mut self, config: ScanConfig) -> Self {
+        self.config = config;
+        self.update_configs();
+        self
+    }
+
+    fn update_configs(&mut self) {
+        let mut map = self.map.write().expect("tainted lock");
+        self.config_id = map.intern_config(&self.config);
+        self.valid = map.covering(&self.config);
+        self.legacy_valid = ScanConfig::legacy().covers(&self.config);
+    }
+
+    /// Sets store dir and scanner options fingerprint of the current run.
+    pub fn with_header(mut self, header: Header) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Records the path of each cached file, so that the cache can be queried later on.
+    pub fn with_paths(mut self, paths: bool) -> Self {
+        self.paths = paths;
+        self
     }
 
     pub fn open<P: AsRef<Path>>(mut self, path: P, ctx: &ExecutionContext) -> Result<Self> {
@@ -45,7 +90,7 @@ impl Cache {
             let mut cachefile =
                 open_locked(&path).map_err(|e| UErr::LoadCache(self.filename.clone(), e))?;
             if cachefile.metadata().map_err(UErr::from)?.len() > 0 {
-                let map = CacheMap::load(&mut cachefile, &self.filename)
+                let map = CacheMap::load(&mut cachefile, &self.filename, &self.header)
                     .map_err(|e| UErr::LoadCache(self.filename.clone(), e))?;
                 debug!("loaded {} entries from cache", map.len());
                 self.map = RwLock::new(map);
@@ -57,33 +102,77 @@ impl Cache {
             }
             Ok(Some(cachefile))
         })?;
+        self.update_configs();
         Ok(self)
     }
 
-    pub fn commit(&mut self, ctx: &ExecutionContext) -> Result<()> {
+    /// Saves the cache file if anything has changed.
+    ///
+    /// Cache lines for files which have not been seen are dropped only after a `complete` scan.
+    pub fn commit(&mut self, ctx: &ExecutionContext, complete: bool) -> Result<()> {
         if let Some(ref mut file) = self.file {
-            if !self.dirty.compare_and_swap(true, false, Ordering::SeqCst) {
+            if !self.dirty.swap(false, Ordering::SeqCst) {
                 return Ok(());
             }
-            ctx.drop_privileges()?;
             let mut map = self.map.write().expect("tainted lock");
-            map.retain(|_, ref mut v| v.used);
-            debug!("writing {} entries to cache", map.len());
-            map.save(file)
-                .map_err(|e| UErr::SaveCache(self.filename.clone(), e))?;
-            ctx.regain_privileges()?;
+            let filename = &self.filename;
+            let header = &self.header;
+            ctx.with_dropped_privileges(|| {
+                if complete {
+                    map.retain(|_, ref mut v| v.used);
+                }
+                map.compact();
+                debug!("writing {} entries to cache", map.len());
+                map.save(file, filename, header)
+                    .map_err(|e| UErr::SaveCache(filename.clone(), e))
+            })?;
         }
         Ok(())
     }
 
-    fn get(&self, dent: &DirEntry) -> Option<(Vec<PathBuf>, fs::Metadata)> {
-        let ino = dent.ino()?;
+    /// Returns cached references of `dent` if the file is unchanged.
+    ///
+    /// If the start directory's device is known, files are looked up by inode number first and
+    /// stat'ed on a hit only.
+    fn get(&self, dent: &DirEntry) -> Option<(Vec<StoreRef>, fs::Metadata)> {
+        let (id, meta) = match (self.header.root, dent.ino()) {
+            (Some((dev, _)), Some(ino)) => {
+                if !self
+                    .map
+                    .read()
+                    .expect("tainted lock")
+                    .contains_key(&(dev, ino))
+                {
+                    return None;
+                }
+                let meta = dent.metadata().ok()?;
+                if meta.dev() != dev {
+                    return None;
+                }
+                ((dev, ino), meta)
+            }
+            _ => {
+                let meta = dent.metadata().ok()?;
+                ((meta.dev(), meta.ino()), meta)
+            }
+        };
         let mut map = self.map.write().expect("tainted lock");
-        let c = map.get_mut(&ino)?;
-        let meta = dent.metadata().ok()?;
-        if c.ctime == meta.ctime() && c.ctime_nsec == meta.ctime_nsec() as u8 {
+        let c = map.get_mut(&id)?;
+        let valid = match c.config_id() {
+            Some(id) => self.valid.get(id as usize).cloned().unwrap_or(false),
+            None => self.legacy_valid,
+        };
+        if valid && c.stamp.matches(&meta) {
             c.used = true;
-            Some((c.refs.clone(), meta))
+            // upgrade legacy stamps
+            let stamp = Stamp::from(&meta);
+            let mut dirty = mem::replace(&mut c.stamp, stamp) != stamp;
+            let refs = map.store_refs(&map[&id]);
+            dirty |= self.paths && map.set_path(id, dent.path());
+            if dirty {
+                self.dirty.store(true, Ordering::Release);
+            }
+            Some((refs, meta))
         } else {
             None
         }
@@ -97,6 +186,9 @@ impl Cache {
                     refs: vec![],
                     cached: true,
                     bytes_scanned: 0,
+                    bytes_decompressed: 0,
+                    softerrors: 0,
+                    rejected: 0,
                     metadata: None,
                 });
             }
@@ -109,6 +201,9 @@ impl Cache {
                     refs,
                     cached: true,
                     bytes_scanned: 0,
+                    bytes_decompressed: 0,
+                    softerrors: 0,
+                    rejected: 0,
                     metadata: Some(metadata),
                 })
             }
@@ -119,8 +214,10 @@ impl Cache {
         }
     }
 
+    /// Records scan results. Incomplete results due to soft errors are not cached so that the
+    /// file gets scanned again next time.
     pub fn insert(&self, sp: &mut StorePaths) -> Result<()> {
-        if sp.cached {
+        if sp.cached || sp.softerrors() > 0 {
             return Ok(());
         }
         let meta = sp.metadata()?;
@@ -128,14 +225,31 @@ impl Cache {
         if self.limit > 0 && map.len() >= self.limit {
             return Err(UErr::CacheFull(self.limit));
         }
-        map.insert(
-            sp.ino()?,
-            CacheLine::new(meta.ctime(), meta.ctime_nsec() as u8, &sp.refs),
-        );
+        let id = (meta.dev(), meta.ino());
+        let mut cl = map.line(Stamp::from(&meta), &sp.refs);
+        cl.set_config(Some(self.config_id));
+        map.insert_line(id, cl);
+        if self.paths {
+            map.set_path(id, sp.path());
+        }
         self.dirty.store(true, Ordering::Release);
         Ok(())
     }
 
+    /// All cached files together with their references, sorted by path.
+    ///
+    /// Also returns the number of cache lines whose path has not been recorded.
+    pub fn files(&self) -> (Vec<(PathBuf, Vec<StoreRef>)>, usize) {
+        let map = self.map.read().expect("tainted lock");
+        let mut files: Vec<_> = map
+            .iter()
+            .filter_map(|(id, cl)| Some((map.path(id)?, map.store_refs(cl))))
+            .collect();
+        files.sort();
+        let unknown = map.len() - files.len();
+        (files, unknown)
+    }
+
     /* statistics */
 
     pub fn len(&self) -> usize {
@@ -168,6 +282,7 @@ impl Cache {
 mod tests {
     use super::Lookup::*;
     use super::*;
+    use crate::storepaths::Origin;
     use crate::tests::{dent, FIXTURES};
     use std::fs;
     use tempfile::TempDir;
@@ -176,9 +291,15 @@ mod tests {
         let dent = tests::dent("dir2/lftp");
         StorePaths {
             dent,
-            refs: vec![PathBuf::from("q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24")],
+            refs: vec![StoreRef::new(
+                "q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24",
+                Origin::ElfInterpreter,
+            )],
             cached: false,
             bytes_scanned: 0,
+            bytes_decompressed: 0,
+            softerrors: 0,
+            rejected: 0,
             metadata: None,
         }
     }
@@ -189,10 +310,18 @@ mod tests {
             refs: vec![],
             cached: false,
             bytes_scanned: 0,
+            bytes_decompressed: 0,
+            softerrors: 0,
+            rejected: 0,
             metadata: None,
         }
     }
 
+    fn file_id(dent: &DirEntry) -> FileId {
+        let meta = dent.metadata().unwrap();
+        (meta.dev(), meta.ino())
+    }
+
     #[test]
     fn insert_cacheline() {
         let c = Cache::new(None);
@@ -201,11 +330,9 @@ mod tests {
 
         let dent = tests::dent("dir1/proto-http.la");
         let map = c.map.read().unwrap();
-        let entry = map
-            .get(&dent.ino().unwrap())
-            .expect("cache entry not found");
+        let entry = map.get(&file_id(&dent)).expect("cache entry not found");
         assert_eq!(
-            entry.ctime,
+            entry.stamp.ctime(),
             fs::metadata("dir1/proto-http.la").unwrap().ctime()
         );
     }
@@ -221,24 +348,221 @@ mod tests {
     #[test]
     fn lookup_should_miss_on_changed_metadata() {
         let c = Cache::new(None);
-        let ino = tests::dent("dir2/lftp").ino().unwrap();
+        let id = file_id(&tests::dent("dir2/lftp"));
         c.insert(&mut sp_dummy()).expect("insert failed");
 
         match c.lookup(tests::dent("dir2/lftp")) {
             Hit(sp) => assert_eq!(
-                vec![PathBuf::from("q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24")],
+                vec![StoreRef::new(
+                    "q3wx1gab2ysnk5nyvyyg56ana2v4r2ar-glibc-2.24",
+                    Origin::ElfInterpreter
+                )],
                 sp.refs
             ),
             _ => panic!("test failure: did not find dir2/lftp in cache"),
         }
 
-        c.map.write().unwrap().get_mut(&ino).unwrap().ctime = 6674;
+        c.map.write().unwrap().get_mut(&id).unwrap().stamp = Stamp::at(6674, 0);
         match c.lookup(tests::dent("dir2/lftp")) {
             Miss(_) => (),
             _ => panic!("should not hit: dir2/lftp"),
         }
     }
 
+    #[test]
+    fn lookup_should_miss_on_changed_stamp_or_device() {
+        let c = Cache::new(None);
+        let dent = tests::dent("dir2/lftp");
+        let meta = dent.metadata().unwrap();
+        let (dev, ino) = file_id(&dent);
+        c.insert(&mut sp_dummy()).unwrap();
+        let cl = c.map.write().unwrap().remove(&(dev, ino)).unwrap();
+        c.map.write().unwrap().insert((dev + 1, ino), cl.clone());
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Miss(_) => (),
+            _ => panic!("should not hit file on other device"),
+        }
+
+        let stamp = |ctime_nsec: i64, size: u64| {
+            Stamp::new(
+                meta.ctime(),
+                ctime_nsec as u32,
+                meta.mtime(),
+                meta.mtime_nsec() as u32,
+                size,
+            )
+        };
+        let lookup = |stamp: Stamp| {
+            let mut map = c.map.write().unwrap();
+            map.clear();
+            let mut cl = cl.clone();
+            cl.stamp = stamp;
+            map.insert((dev, ino), cl);
+            drop(map);
+            c.lookup(tests::dent("dir2/lftp"))
+        };
+        match lookup(stamp(meta.ctime_nsec(), meta.len() + 1)) {
+            Miss(_) => (),
+            _ => panic!("should not hit file with different size"),
+        }
+        match lookup(stamp((meta.ctime_nsec() + 256) % 1_000_000_000, meta.len())) {
+            Miss(_) => (),
+            _ => panic!("should not hit file with different ctime_nsec"),
+        }
+
+        // legacy stamps are accepted and upgraded
+        let legacy = Stamp::legacy(meta.ctime(), meta.ctime_nsec() as u8, None);
+        match lookup(legacy) {
+            Hit(_) => (),
+            _ => panic!("should hit"),
+        }
+        assert_eq!(Stamp::from(&meta), c.map.read().unwrap()[&(dev, ino)].stamp);
+    }
+
+    #[test]
+    fn incomplete_results_should_not_be_cached() {
+        let c = Cache::new(None);
+        c.insert(&mut sp_dummy().with_softerrors(1)).unwrap();
+        assert!(c.map.read().unwrap().is_empty());
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Miss(_) => (),
+            _ => panic!("should not hit incomplete result"),
+        }
+    }
+
+    #[test]
+    fn lookup_should_use_start_device() {
+        let dent = tests::dent("dir2/lftp");
+        let (dev, ino) = file_id(&dent);
+        let c = Cache::new(None).with_header(Header {
+            root: Some((dev, 1)),
+            ..Header::default()
+        });
+        c.insert(&mut sp_dummy()).unwrap();
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Hit(_) => (),
+            _ => panic!("should hit dir2/lftp"),
+        }
+
+        let c = Cache::new(None).with_header(Header {
+            root: Some((dev + 1, 1)),
+            ..Header::default()
+        });
+        c.insert(&mut sp_dummy()).unwrap();
+        let cl = c.map.write().unwrap().remove(&(dev, ino)).unwrap();
+        c.map.write().unwrap().insert((dev + 1, ino), cl);
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Miss(_) => (),
+            _ => panic!("should not hit file on other device"),
+        }
+    }
+
+    #[test]
+    fn paths_should_be_recorded_on_request() {
+        let c = Cache::new(None);
+        c.insert(&mut sp_dummy()).unwrap();
+        assert_eq!((vec![], 1), c.files());
+
+        let c = Cache::new(None).with_paths(true);
+        c.insert(&mut sp_dummy()).unwrap();
+        c.insert(&mut sp_fixture("dir1/proto-http.la")).unwrap();
+        let (files, unknown) = c.files();
+        assert_eq!(0, unknown);
+        assert_eq!(
+            vec![
+                FIXTURES.join("dir1/proto-http.la"),
+                FIXTURES.join("dir2/lftp")
+            ],
+            files.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>()
+        );
+        assert_eq!(sp_dummy().refs, files[1].1);
+    }
+
+    #[test]
+    fn lookup_should_miss_on_weaker_config() {
+        let quick = ScanConfig {
+            quickcheck: 512 << 10,
+            text: true,
+            ..ScanConfig::default()
+        };
+        let full = ScanConfig {
+            quickcheck: 0,
+            ..quick.clone()
+        };
+        let c = Cache::new(None).with_config(quick.clone());
+        c.insert(&mut sp_dummy()).unwrap();
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Hit(_) => (),
+            _ => panic!("should hit with same config"),
+        }
+        let c = c.with_config(full.clone());
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Miss(_) => (),
+            _ => panic!("should miss with stronger config"),
+        }
+        c.insert(&mut sp_dummy()).unwrap();
+        let c = c.with_config(quick);
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Hit(_) => (),
+            _ => panic!("should hit with weaker config"),
+        }
+    }
+
+    #[test]
+    fn legacy_cachelines_should_be_valid_for_default_config() {
+        let c = Cache::new(None).with_config(ScanConfig::legacy());
+        c.insert(&mut sp_dummy()).unwrap();
+        let id = file_id(&tests::dent("dir2/lftp"));
+        c.map
+            .write()
+            .unwrap()
+            .get_mut(&id)
+            .unwrap()
+            .set_config(None);
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Hit(_) => (),
+            _ => panic!("should hit"),
+        }
+        let c = c.with_config(ScanConfig {
+            elf: true,
+            ..ScanConfig::legacy()
+        });
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Miss(_) => (),
+            _ => panic!("should miss"),
+        }
+    }
+
+    #[test]
+    fn partial_commit_should_keep_unseen_cachelines() {
+        let td = TempDir::new().unwrap();
+        let cache_file = td.path().join("cache");
+        let ctx = ExecutionContext::new();
+        let mut c = Cache::new(None).open(&cache_file, &ctx).unwrap();
+        c.insert(&mut sp_dummy()).unwrap();
+        c.insert(&mut sp_fixture("dir1/proto-http.la")).unwrap();
+        c.commit(&ctx, true).unwrap();
+        drop(c);
+
+        // only dir2/lftp is seen during the next run
+        let mut c = Cache::new(None).open(&cache_file, &ctx).unwrap();
+        match c.lookup(tests::dent("dir2/lftp")) {
+            Hit(_) => (),
+            _ => panic!("should hit"),
+        }
+        c.dirty.store(true, Ordering::SeqCst);
+        c.commit(&ctx, false).unwrap();
+        assert_eq!(2, c.len());
+        drop(c);
+
+        let mut c = Cache::new(None).open(&cache_file, &ctx).unwrap();
+        assert_eq!(2, c.len());
+        c.lookup(tests::dent("dir2/lftp"));
+        c.dirty.store(true, Ordering::SeqCst);
+        c.commit(&ctx, true).unwrap();
+        assert_eq!(1, c.len());
+    }
+
     #[test]
     fn load_save_cache() {
         let td = TempDir::new().unwrap();
@@ -249,7 +573,7 @@ mod tests {
             .unwrap();
         assert_eq!(12, c.len());
         assert!(!c.dirty.load(Ordering::SeqCst));
-        for ref cl in c.map.read().unwrap().values() {
+        for cl in c.map.read().unwrap().values() {
             assert!(!cl.used);
         }
 
@@ -267,7 +591,7 @@ mod tests {
                 .len()
         );
 
-        c.commit(&ExecutionContext::new()).unwrap();
+        c.commit(&ExecutionContext::new(), true).unwrap();
         assert_eq!(1, c.len());
         let cache_len = fs::metadata(&cache_file).unwrap().len();
         assert!(cache_len > 60);
@@ -1,25 +1,110 @@
 use crate::errors::*;
 use ignore::{self, DirEntry};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
 use std::fmt;
 use std::fs;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
 mod cache;
 pub use self::cache::Cache;
 
+/// Describes where inside a file a Nix store reference has been found.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Origin {
+    /// Origin has not been recorded, e.g. in cache files written by older versions
+    Unknown,
+    /// Somewhere in the file's contents
+    Text,
+    /// `#!` line of a script
+    Shebang,
+    /// Symlink target
+    Symlink,
+    /// Program interpreter of an ELF binary
+    ElfInterpreter,
+    /// RPATH or RUNPATH entry of an ELF binary
+    ElfRpath,
+    /// DT_NEEDED entry of an ELF binary
+    ElfNeeded,
+    /// Contents of a gzip, bzip2 or xz compressed file
+    Compressed,
+    /// Archive member (contents or link target). Members of nested archives are given as path
+    /// below the top-level archive.
+    Archive(PathBuf),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Unknown => write!(f, "unknown"),
+            Origin::Text => write!(f, "text"),
+            Origin::Shebang => write!(f, "shebang"),
+            Origin::Symlink => write!(f, "symlink"),
+            Origin::ElfInterpreter => write!(f, "ELF interpreter"),
+            Origin::ElfRpath => write!(f, "ELF rpath"),
+            Origin::ElfNeeded => write!(f, "ELF needed"),
+            Origin::Compressed => write!(f, "compressed"),
+            Origin::Archive(member) => write!(f, "archive member {}", member.display()),
+        }
+    }
+}
+
+/// Single Nix store reference together with the place where it has been found.
+///
+/// `path` is relative to the Nix store, i.e. without leading `/nix/store/`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StoreRef {
+    pub path: PathBuf,
+    pub origin: Origin,
+}
+
+impl StoreRef {
+    pub fn new<P: Into<PathBuf>>(path: P, origin: Origin) -> Self {
+        Self {
+            path: path.into(),
+            origin,
+        }
+    }
+
+    /// Creates a reference from raw bytes as found by the scanner.
+    pub fn from_bytes(path: &[u8], origin: Origin) -> Self {
+        Self::new(OsStr::from_bytes(path), origin)
+    }
+}
+
+/// Groups references sorted by path into distinct paths and all their origins.
+pub fn group_origins<'a>(
+    refs: &'a [StoreRef],
+) -> Box<dyn Iterator<Item = (&'a Path, Vec<&'a Origin>)> + 'a> {
+    let mut rest = refs;
+    Box::new(std::iter::from_fn(move || {
+        let path = &rest.first()?.path;
+        let n = rest.iter().take_while(|r| r.path == *path).count();
+        let (group, tail) = rest.split_at(n);
+        rest = tail;
+        Some((path.as_path(), group.iter().map(|r| &r.origin).collect()))
+    }))
+}
+
 #[derive(Debug)]
 pub struct StorePaths {
     dent: DirEntry,
-    refs: Vec<PathBuf>,
+    /// Sorted by path, may contain the same path with different origins
+    refs: Vec<StoreRef>,
     cached: bool,
     bytes_scanned: u64,
+    bytes_decompressed: u64,
+    softerrors: usize,
+    /// Candidates which have not been accepted as store references
+    rejected: usize,
     metadata: Option<fs::Metadata>,
 }
 
 impl StorePaths {
     pub fn new(
         dent: DirEntry,
-        refs: Vec<PathBuf>,
+        refs: Vec<StoreRef>,
         bytes_scanned: u64,
         metadata: Option<fs::Metadata>,
     ) -> Self {
@@ -27,26 +112,51 @@ impl StorePaths {
             dent,
             refs,
             bytes_scanned,
+            bytes_decompressed: 0,
+            softerrors: 0,
+            rejected: 0,
             cached: false,
             metadata,
         }
     }
 
+    /// Records how many bytes have been unpacked from compressed data while scanning.
+    pub fn with_bytes_decompressed(mut self, bytes: u64) -> Self {
+        self.bytes_decompressed = bytes;
+        self
+    }
+
+    /// Records problems which did not prevent scanning as a whole, e.g. skipped archive members.
+    pub fn with_softerrors(mut self, softerrors: usize) -> Self {
+        self.softerrors = softerrors;
+        self
+    }
+
+    /// Records how many candidates violated Nix' store path naming rules.
+    pub fn with_rejected(mut self, rejected: usize) -> Self {
+        self.rejected = rejected;
+        self
+    }
+
     #[inline]
     pub fn path(&self) -> &Path {
         self.dent.path()
     }
 
     #[inline]
-    pub fn error(&self) -> Option<&ignore::Error> {
-        self.dent.error()
+    pub fn file_type(&self) -> Option<fs::FileType> {
+        self.dent.file_type()
+    }
+
+    /// Returns true if the results have been loaded from the cache instead of scanning.
+    #[inline]
+    pub fn cached(&self) -> bool {
+        self.cached
     }
 
     #[inline]
-    pub fn ino(&self) -> Result<u64> {
-        self.dent
-            .ino()
-            .ok_or_else(|| UErr::DentNoMetadata(self.path().to_owned()))
+    pub fn error(&self) -> Option<&ignore::Error> {
+        self.dent.error()
     }
 
     pub fn metadata(&mut self) -> Result<fs::Metadata> {
@@ -68,20 +178,50 @@ impl StorePaths {
         self.refs.is_empty()
     }
 
+    /// Iterates over all distinct store paths.
     #[inline]
-    pub fn iter_refs<'a>(&'a self) -> Box<dyn Iterator<Item = &Path> + 'a> {
-        Box::new(self.refs.iter().map(|p| p.as_path()))
+    pub fn iter_refs<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Path> + 'a> {
+        let mut prev = None;
+        Box::new(
+            self.refs
+                .iter()
+                .map(|r| r.path.as_path())
+                .filter(move |&p| prev.replace(p) != Some(p)),
+        )
+    }
+
+    /// Iterates over all distinct store paths together with all places where each has been
+    /// found.
+    pub fn iter_origins<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = (&'a Path, Vec<&'a Origin>)> + 'a> {
+        group_origins(&self.refs)
     }
 
     #[allow(dead_code)] // only used in tests
-    pub fn refs(&self) -> &Vec<PathBuf> {
-        &self.refs
+    pub fn refs(&self) -> Vec<&Path> {
+        self.iter_refs().collect()
     }
 
     #[inline]
     pub fn bytes_scanned(&self) -> u64 {
         self.bytes_scanned
     }
+
+    #[inline]
+    pub fn bytes_decompressed(&self) -> u64 {
+        self.bytes_decompressed
+    }
+
+    #[inline]
+    pub fn softerrors(&self) -> usize {
+        self.softerrors
+    }
+
+    #[inline]
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
 }
 
 impl fmt::Display for StorePaths {
@@ -90,7 +230,7 @@ impl fmt::Display for StorePaths {
             write!(f, "{}", self.dent.path().display())
         } else {
             write!(f, "{}:", self.dent.path().display())?;
-            for r in &self.refs {
+            for r in self.iter_refs() {
                 write!(f, " {}", r.display())?;
             }
             Ok(())
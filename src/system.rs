@@ -1,5 +1,15 @@
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use nix::unistd::{getegid, geteuid, getgid, getuid, setegid, seteuid, Gid, Uid};
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+
+/// Number of the signal which has requested a graceful stop, 0 if none
+static SIGNALLED: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(signo: nix::libc::c_int) {
+    SIGNALLED.store(signo, Ordering::SeqCst);
+}
 
 #[derive(Debug, Clone)]
 pub struct ExecutionContext {
@@ -9,6 +19,8 @@ pub struct ExecutionContext {
     pub euid: Uid,
     pub gid: Gid,
     pub egid: Gid,
+    /// Graceful stop requested without signal
+    interrupt: Arc<AtomicBool>,
 }
 
 impl Default for ExecutionContext {
@@ -26,6 +38,41 @@ impl ExecutionContext {
             euid: geteuid(),
             gid: getgid(),
             egid: getegid(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Installs handlers which request a graceful stop on SIGINT and SIGTERM. A second signal
+    /// terminates the process immediately.
+    pub fn catch_signals(&self) -> Result<(), nix::Error> {
+        let action = SigAction::new(
+            SigHandler::Handler(on_signal),
+            SaFlags::SA_RESTART | SaFlags::SA_RESETHAND,
+            SigSet::empty(),
+        );
+        for sig in &[Signal::SIGINT, Signal::SIGTERM] {
+            // on_signal only touches an atomic, so it is async-signal-safe
+            unsafe { sigaction(*sig, &action) }?;
+        }
+        Ok(())
+    }
+
+    /// Requests a graceful stop as if a signal has been received.
+    #[cfg(test)]
+    pub fn interrupt(&self) {
+        self.interrupt.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if a graceful stop has been requested.
+    pub fn interrupted(&self) -> bool {
+        self.signal().is_some() || self.interrupt.load(Ordering::SeqCst)
+    }
+
+    /// Signal which has requested a graceful stop, if any
+    pub fn signal(&self) -> Option<i32> {
+        match SIGNALLED.load(Ordering::SeqCst) {
+            0 => None,
+            signo => Some(signo),
         }
     }
 
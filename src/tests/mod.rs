@@ -1,6 +1,7 @@
 use super::*;
 use nix::unistd::chdir;
 use std::collections::HashSet;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 
 lazy_static! {
@@ -10,9 +11,14 @@ lazy_static! {
 /// Quick creation of an App instance for testing.
 pub fn app<P: AsRef<Path>>(startdir: P) -> App {
     chdir(&*FIXTURES).expect("chdir(fixtures) failed");
-    let mut a = App::default();
-    a.opt.unzip = vec!["*.zip".into()];
-    a.opt.startdir = PathBuf::from(startdir.as_ref());
+    let mut a = App::from(Opt::from_iter(&[
+        OsStr::new("userscan"),
+        OsStr::new("--unzip=*.zip"),
+        OsStr::new("--store-dir"),
+        OsStr::new(scan::DEFAULT_STORE_DIR),
+        startdir.as_ref().as_os_str(),
+    ]));
+    a.register = false;
     a
 }
 
@@ -32,7 +38,7 @@ pub fn assert_eq_vecs<R, F>(result: Vec<R>, map_res: F, expect: &[&str])
 where
     F: for<'a> Fn(&'a R) -> String,
 {
-    let mut expected: HashSet<&str> = expect.into_iter().map(|p| *p).collect();
+    let mut expected: HashSet<&str> = expect.iter().copied().collect();
     let mut unexpected = Vec::new();
     for r in result {
         let key = map_res(&r);
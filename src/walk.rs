@@ -1,18 +1,23 @@
+use crate::checkpoint::{Checkpoint, Walked};
 use crate::errors::UErr;
 use crate::output::p2s;
+use crate::policy::{ErrorClass, ErrorPolicy, Policy, Skipped};
 use crate::registry::{GCRootsTx, Register};
 use crate::scan::Scanner;
 use crate::statistics::{Statistics, StatsMsg, StatsTx};
 use crate::storepaths::{Cache, Lookup, StorePaths};
+use crate::system::ExecutionContext;
 use crate::App;
 
 use anyhow::{Context, Result};
 use ignore::{self, DirEntry, WalkParallel, WalkState};
 use std::io::{self, ErrorKind};
 use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Clone, Debug)]
 struct ProcessingContext {
@@ -22,17 +27,23 @@ struct ProcessingContext {
     stats: StatsTx,
     gc: GCRootsTx,
     abort: Arc<AtomicBool>,
+    exectx: ExecutionContext,
+    deadline: Option<Instant>,
+    policy: ErrorPolicy,
 }
 
 impl ProcessingContext {
     fn create(app: &App, stats: &mut Statistics, gc: GCRootsTx) -> Result<Self> {
         Ok(Self {
+            deadline: app.deadline()?,
             startdev: app.start_meta()?.dev(),
             cache: Arc::new(app.cache()?),
             scanner: Arc::new(app.scanner()?),
             stats: stats.tx(),
             gc,
             abort: Arc::new(AtomicBool::new(false)),
+            exectx: app.exectx.clone(),
+            policy: app.error_policy(),
         })
     }
 
@@ -53,6 +64,9 @@ impl ProcessingContext {
                 return Err(err.clone().into());
             }
         }
+        for _ in 0..sp.softerrors() {
+            self.stats.send(StatsMsg::SoftError).unwrap();
+        }
         if sp.metadata()?.dev() != self.startdev {
             return Ok(WalkState::Skip);
         }
@@ -64,81 +78,293 @@ impl ProcessingContext {
         Ok(WalkState::Continue)
     }
 
-    /// Walks through a directory hierachy and processes each found DirEntry.
-    fn walk(self, walker: WalkParallel) -> Result<Arc<Cache>> {
-        walker.run(|| {
-            let pctx = self.clone();
-            Box::new(move |res: Result<DirEntry, ignore::Error>| {
-                res.map_err(From::from)
-                    .and_then(|dent| pctx.scan_entry(dent))
-                    .unwrap_or_else(|err| {
-                        if let Some(UErr::WalkAbort) = err.downcast_ref::<UErr>() {
-                            error!("Traversal error: {:#}", &err);
-                            pctx.abort.store(true, Ordering::SeqCst);
-                            return WalkState::Quit;
-                        } else if let Some(UErr::FiletypeUnknown) = err.downcast_ref::<UErr>() {
-                            // ignore & continue
-                            return WalkState::Continue;
-                        } else if let Some(e) = err.downcast_ref::<io::Error>() {
-                            if e.kind() == ErrorKind::PermissionDenied {
-                                error!("I/O error: {:#}", &err);
-                                pctx.abort.store(true, Ordering::SeqCst);
-                                return WalkState::Quit;
-                            }
-                            else if e.kind() == ErrorKind::NotFound {
-                                // ignore & continue
-                                return WalkState::Continue;
+    /// Returns true if the scan has run out of time.
+    fn expired(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    /// Processes a single result of the directory walk and decides how to continue.
+    fn process(&self, res: Result<DirEntry, ignore::Error>) -> WalkState {
+        if self.exectx.interrupted() || self.expired() {
+            self.abort.store(true, Ordering::SeqCst);
+            return WalkState::Quit;
+        }
+        match res {
+            Ok(dent) => {
+                let path = dent.path().to_owned();
+                let dir = is_dir(&dent);
+                let err = match self.scan_entry(dent) {
+                    Ok(state) => return state,
+                    Err(err) => err,
+                };
+                match self.judge(&err) {
+                    None => WalkState::Continue,
+                    Some((_, Policy::Abort)) => WalkState::Quit,
+                    Some((class, Policy::SkipSubtree)) => {
+                        self.skip(path, class, dir);
+                        WalkState::Skip
+                    }
+                    Some((class, Policy::Continue)) => {
+                        self.skip(path, class, false);
+                        WalkState::Continue
+                    }
+                }
+            }
+            Err(err) => {
+                // directories which cannot be read end up here
+                let path = error_path(&err).map(Path::to_owned);
+                match self.judge(&err.into()) {
+                    None => WalkState::Continue,
+                    Some((_, Policy::Abort)) => WalkState::Quit,
+                    Some((class, _)) => {
+                        match path {
+                            Some(path) => {
+                                let dir = path.is_dir();
+                                self.skip(path, class, dir)
                             }
-                        } else if let Some(e) = err.downcast_ref::<ignore::Error>() {
-                            error!("Traversal failure: {:#}", &e);
-                            pctx.abort.store(true, Ordering::SeqCst);
-                            return WalkState::Quit;
+                            None => self.stats.send(StatsMsg::SoftError).unwrap(),
                         }
-                        warn!("{:#}", &err);
-                        pctx.stats.send(StatsMsg::SoftError).unwrap();
                         WalkState::Continue
-                    })
-            })
-        });
-        if self.abort.load(Ordering::SeqCst) {
+                    }
+                }
+            }
+        }
+    }
+
+    /// Classifies and logs an error. Returns the policy to apply or None if the error should be
+    /// ignored. Sets the abort flag if the policy says so.
+    fn judge(&self, err: &anyhow::Error) -> Option<(ErrorClass, Policy)> {
+        if let Some(UErr::WalkAbort) = err.downcast_ref::<UErr>() {
+            error!("Traversal error: {:#}", err);
+            self.abort.store(true, Ordering::SeqCst);
+            return Some((ErrorClass::Other, Policy::Abort));
+        }
+        let class = classify(err)?;
+        let policy = self.policy.get(class);
+        if policy == Policy::Abort {
+            match class {
+                ErrorClass::Permission => error!("I/O error: {:#}", err),
+                ErrorClass::Traversal => error!("Traversal failure: {:#}", err),
+                ErrorClass::Other => error!("Scan failure: {:#}", err),
+            }
+            self.abort.store(true, Ordering::SeqCst);
+        } else {
+            warn!("{:#}", err);
+        }
+        Some((class, policy))
+    }
+
+    /// Reports a path which has been left out due to an error.
+    fn skip(&self, path: PathBuf, class: ErrorClass, subtree: bool) {
+        self.stats
+            .send(StatsMsg::Skipped(Skipped {
+                path,
+                class,
+                subtree,
+            }))
+            .unwrap();
+    }
+
+    /// Determines whether the walk has stopped early after an interrupt or timeout (false) or
+    /// due to a hard error.
+    fn finish(&self) -> Result<bool> {
+        if !self.abort.load(Ordering::SeqCst) {
+            Ok(true)
+        } else if self.exectx.interrupted() {
+            warn!("Interrupted, stopping scan");
+            Ok(false)
+        } else if self.expired() {
+            warn!("Maximum runtime exceeded, stopping scan");
+            Ok(false)
+        } else {
             Err(UErr::WalkAbort.into())
+        }
+    }
+
+    /// Walks through a directory hierachy and processes each found DirEntry.
+    ///
+    /// Stops early if interrupted by a signal or out of time. Returns whether the walk has been
+    /// completed.
+    fn walk(&self, walker: WalkParallel) -> Result<bool> {
+        walker.run(|| {
+            let pctx = self.clone();
+            Box::new(move |res: Result<DirEntry, ignore::Error>| pctx.process(res))
+        });
+        self.finish()
+    }
+
+    /// Walks through the start dir's top-level subdirectories one after another.
+    ///
+    /// Files directly in the start dir are processed first. Subdirectories which are recorded as
+    /// done in `checkpoint` are skipped, newly completed ones are added.
+    fn walk_subtrees(&self, app: &App, checkpoint: &mut Checkpoint) -> Result<Progress> {
+        let mut walked = Walked::default();
+        let mut subtrees = Vec::new();
+        for res in app.walker()?.max_depth(Some(1)).build() {
+            match res {
+                Ok(ref dent) if dent.depth() == 1 && is_dir(dent) => {
+                    subtrees.push(dent.path().to_owned())
+                }
+                res => {
+                    if self.process(res) == WalkState::Quit {
+                        break;
+                    }
+                }
+            }
+        }
+        if !self.finish()? {
+            return Ok(Progress::Stopped(walked));
+        }
+        walked.toplevel = true;
+        subtrees.sort();
+        let mut resumed = false;
+        for dir in subtrees {
+            let name = dir.file_name().expect("top-level entry without file name");
+            if checkpoint.is_done(name) {
+                debug!("{}: done in a previous run, skipping", p2s(&dir));
+                resumed = true;
+                continue;
+            }
+            if !self.walk(app.walker_at(&dir)?.build_parallel())? {
+                return Ok(Progress::Stopped(walked));
+            }
+            checkpoint.mark_done(name);
+            walked.subtrees.push(dir);
+        }
+        if resumed {
+            Ok(Progress::Resumed(walked))
         } else {
-            Ok(self.cache)
+            Ok(Progress::Complete)
         }
     }
 }
 
+/// Determines the class of errors which are subject to the error policy. Returns None for errors
+/// which should be ignored, e.g. files which have vanished during the scan.
+fn classify(err: &anyhow::Error) -> Option<ErrorClass> {
+    if let Some(UErr::FiletypeUnknown) = err.downcast_ref::<UErr>() {
+        None
+    } else if let Some(e) = err.downcast_ref::<io::Error>() {
+        match e.kind() {
+            ErrorKind::PermissionDenied => Some(ErrorClass::Permission),
+            ErrorKind::NotFound => None,
+            _ => Some(ErrorClass::Other),
+        }
+    } else if let Some(e) = err.downcast_ref::<ignore::Error>() {
+        match e.io_error() {
+            Some(e) if e.kind() == ErrorKind::PermissionDenied => Some(ErrorClass::Permission),
+            _ => Some(ErrorClass::Traversal),
+        }
+    } else {
+        Some(ErrorClass::Other)
+    }
+}
+
+/// Path of the file or directory an error refers to, if known.
+fn error_path(err: &ignore::Error) -> Option<&Path> {
+    match err {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::Loop { child, .. } => Some(child),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            error_path(err)
+        }
+        _ => None,
+    }
+}
+
+fn is_dir(dent: &DirEntry) -> bool {
+    match dent.file_type() {
+        Some(ft) => ft.is_dir(),
+        None => false,
+    }
+}
+
+/// How far the scan has come
+#[derive(Debug, Clone, PartialEq)]
+enum Progress {
+    /// Everything below the start dir has been walked in this run
+    Complete,
+    /// The remaining subtrees have been walked after previous runs have run out of time
+    Resumed(Walked),
+    /// Stopped early due to interrupt or timeout
+    Stopped(Walked),
+}
+
 /// Creates threads, starts parallel scanning and collects results.
 pub fn spawn_threads(app: &App, gcroots: &mut dyn Register) -> Result<Statistics> {
     let mut stats = app.statistics();
     let (gc_tx, gc_rx) = channel::<StorePaths>();
-    let mut cache = crossbeam::scope(|sc| -> Result<Arc<Cache>> {
-        let pctx = ProcessingContext::create(app, &mut stats, gc_tx)?;
+    app.output.print_header();
+    app.exectx.catch_signals()?;
+    let pctx = ProcessingContext::create(app, &mut stats, gc_tx)?;
+    let mut checkpoint = app.checkpoint()?;
+    let cp = checkpoint.as_mut();
+    let (mut cache, progress) = crossbeam::scope(|sc| -> Result<(Arc<Cache>, Progress)> {
         let walker = app.walker()?.build_parallel();
-        info!("{}: Scouting {}", crate_name!(), p2s(&app.opt.startdir));
-        let walk_hdl = sc.spawn(|_| pctx.walk(walker));
+        info!("{}: Scouting {}", crate_name!(), p2s(app.startarg()?));
+        let walk_hdl = sc.spawn(move |_| -> Result<(Arc<Cache>, Progress)> {
+            let progress = match cp {
+                Some(cp) => pctx.walk_subtrees(app, cp)?,
+                None if pctx.walk(walker)? => Progress::Complete,
+                None => Progress::Stopped(Walked::default()),
+            };
+            Ok((pctx.cache.clone(), progress))
+        });
         sc.spawn(|_| stats.receive_loop());
         gcroots.register_loop(gc_rx);
         walk_hdl.join().expect("subthread panic")
     })
     .expect("thread panic")?;
     if app.register {
-        gcroots.commit(&app.exectx)?;
-        // don't touch cache if in no-register mode
-        Arc::get_mut(&mut cache)
-            .expect("dangling cache references (all threads terminated?)")
-            .commit(&app.exectx)?;
+        gcroots.preserve(stats.skipped());
+        match progress {
+            Progress::Complete => gcroots.commit(&app.exectx)?,
+            // files which have not been seen may still need their GC roots
+            Progress::Resumed(ref walked) | Progress::Stopped(ref walked) => {
+                gcroots.commit_partial(&app.exectx, walked)?
+            }
+        }
+        // don't touch cache if in no-register mode or in a dry run
+        if !app.opt.dry_run {
+            Arc::get_mut(&mut cache)
+                .expect("dangling cache references (all threads terminated?)")
+                .commit(&app.exectx, progress == Progress::Complete)?;
+        }
         cache.log_statistics();
+        // a dry run has not registered anything, so the next run must walk the same subtrees
+        if let Some(file) = app.checkpoint_file().filter(|_| !app.opt.dry_run) {
+            app.exectx
+                .with_dropped_privileges(|| match (&progress, &checkpoint) {
+                    (Progress::Stopped(_), Some(cp)) => {
+                        info!(
+                            "{} subtrees done, continuing in the next run",
+                            cp.completed()
+                        );
+                        cp.save(&file)
+                    }
+                    (Progress::Stopped(_), None) => Ok(()),
+                    _ => Checkpoint::remove(&file),
+                })?;
+        }
+    }
+    stats.log_summary(app.startarg()?);
+    app.output.print_summary(&stats.summary());
+    if app.exectx.interrupted() {
+        return Err(UErr::Interrupted.into());
     }
-    stats.log_summary(&app.opt.startdir);
     Ok(stats)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::output::Output;
     use crate::registry;
     use crate::registry::tests::{fake_gc, FakeGCRoots};
+    use crate::registry::GCRoots;
     use crate::tests::{app, assert_eq_vecs, FIXTURES};
 
     use ignore::WalkBuilder;
@@ -164,11 +390,9 @@ mod tests {
     pub fn walk2vec(wb: &WalkBuilder, prefix: &Path) -> Vec<PathBuf> {
         let mut paths = vec![];
         let prefix = prefix.canonicalize().unwrap();
-        for r in wb.build() {
-            if let Ok(dent) = r {
-                let p = dent.path().strip_prefix(&prefix).unwrap();
-                paths.push(p.to_owned());
-            }
+        for dent in wb.build().flatten() {
+            let p = dent.path().strip_prefix(&prefix).unwrap();
+            paths.push(p.to_owned());
         }
         paths.sort();
         paths
@@ -185,7 +409,7 @@ mod tests {
             F: FnOnce(&Path),
         {
             let temp = TempDir::new().unwrap();
-            setup(&temp.path());
+            setup(temp.path());
             Self { temp }
         }
 
@@ -197,10 +421,8 @@ mod tests {
     impl Drop for TestDir {
         /// Set read/exec bits everywhere -- else TempDir's cleanup might fail
         fn drop(&mut self) {
-            for entry in fs::read_dir(self.temp.path()).unwrap() {
-                if let Ok(f) = entry {
-                    set_permissions(f.path(), Permissions::from_mode(0o755)).ok();
-                }
+            for f in fs::read_dir(self.temp.path()).unwrap().flatten() {
+                set_permissions(f.path(), Permissions::from_mode(0o755)).ok();
             }
         }
     }
@@ -226,6 +448,138 @@ mod tests {
         assert_eq!(stats.softerrors, 0);
     }
 
+    /// Records found files and commits
+    #[derive(Default)]
+    struct Commits {
+        found: Vec<PathBuf>,
+        full: usize,
+        partial: Vec<Walked>,
+    }
+
+    impl Register for Commits {
+        fn register_loop(&mut self, rx: registry::GCRootsRx) {
+            for sp in rx {
+                self.found.push(sp.path().to_owned());
+            }
+        }
+
+        fn commit(&mut self, _ctx: &ExecutionContext) -> crate::errors::Result<()> {
+            self.full += 1;
+            Ok(())
+        }
+
+        fn commit_partial(
+            &mut self,
+            _ctx: &ExecutionContext,
+            walked: &Walked,
+        ) -> crate::errors::Result<()> {
+            self.partial.push(walked.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interrupted_walk_should_skip_cleanup() {
+        let mut app = app("dir1");
+        app.register = true;
+        let mut gc = Commits::default();
+        spawn_threads(&app, &mut gc).unwrap();
+        assert_eq!((1, 0), (gc.full, gc.partial.len()));
+
+        app.exectx.interrupt();
+        let mut gc = Commits::default();
+        match spawn_threads(&app, &mut gc) {
+            Err(e) => match e.downcast_ref::<UErr>() {
+                Some(UErr::Interrupted) => (),
+                _ => panic!("unexpected error: {:#}", e),
+            },
+            Ok(_) => panic!("should fail"),
+        }
+        assert_eq!(vec![Walked::default()], gc.partial);
+        assert_eq!(0, gc.full);
+    }
+
+    /// Start dir with files directly inside and in the subdirectories "a" and "b"
+    fn subtrees() -> TestDir {
+        TestDir::new(|p| {
+            wfile(
+                p.join("top"),
+                "/nix/store/dxscwf37hgq0xafs54h0c8xx47vg6d5g-n",
+            );
+            for dir in &["a", "b"] {
+                create_dir(p.join(dir)).unwrap();
+                wfile(
+                    p.join(dir).join("file"),
+                    "/nix/store/5hg176hhc19mg8vm2rg3lv2j3vlj166b-m",
+                );
+            }
+        })
+    }
+
+    #[test]
+    fn expired_scan_should_record_checkpoint() {
+        let t = subtrees();
+        let mut app = app(t.path());
+        app.register = true;
+        app.opt.cache = Some(t.path().join("cache"));
+        app.opt.max_runtime = Some(chrono::Duration::seconds(0));
+        let mut gc = Commits::default();
+        spawn_threads(&app, &mut gc).unwrap();
+        assert!(gc.found.is_empty());
+        assert_eq!(vec![Walked::default()], gc.partial);
+        assert!(t.path().join("cache.checkpoint").exists());
+    }
+
+    #[test]
+    fn resumed_scan_should_skip_completed_subtrees() {
+        let t = subtrees();
+        let startdir = t.path().canonicalize().unwrap();
+        let mut app = app(t.path());
+        app.register = true;
+        app.opt.cache = Some(t.path().join("cache"));
+        app.opt.max_runtime = Some(chrono::Duration::hours(1));
+        let checkpoint = t.path().join("cache.checkpoint");
+        let mut cp = Checkpoint::new(&startdir, app.fingerprint());
+        cp.mark_done(std::ffi::OsStr::new("a"));
+        cp.save(&checkpoint).unwrap();
+
+        let mut gc = Commits::default();
+        spawn_threads(&app, &mut gc).unwrap();
+        gc.found.sort();
+        assert_eq!(
+            vec![startdir.join("b/file"), startdir.join("top")],
+            gc.found
+        );
+        assert_eq!(
+            vec![Walked {
+                toplevel: true,
+                subtrees: vec![startdir.join("b")]
+            }],
+            gc.partial
+        );
+        assert!(!checkpoint.exists());
+
+        // a fresh cycle walks everything
+        let mut gc = Commits::default();
+        spawn_threads(&app, &mut gc).unwrap();
+        assert_eq!(3, gc.found.len());
+        assert_eq!((1, 0), (gc.full, gc.partial.len()));
+    }
+
+    #[test]
+    fn dry_run_should_not_save_cache() {
+        let t = subtrees();
+        let mut app = app(t.path());
+        app.register = true;
+        app.opt.dry_run = true;
+        app.opt.cache = Some(t.path().join("cache"));
+        let mut gc = Commits::default();
+        spawn_threads(&app, &mut gc).unwrap();
+        assert_eq!(1, gc.full);
+        let cache = fs::metadata(t.path().join("cache")).map_or(0, |m| m.len());
+        assert_eq!(0, cache);
+    }
+
     #[test]
     fn harderror_on_unreadable_file() {
         let t = TestDir::new(|p| {
@@ -242,7 +596,7 @@ mod tests {
             let d = p.join("unreadable_dir");
             create_dir(&d).unwrap();
             wfile(
-                &d.join("file3"),
+                d.join("file3"),
                 "/nix/store/5hg176hhc19mg8vm2rg3lv2j3vlj166b-m",
             );
             set_permissions(&d, Permissions::from_mode(0o111)).unwrap();
@@ -260,6 +614,28 @@ mod tests {
         assert!(spawn_threads(&app(t.path()), &mut FakeGCRoots::new(t.path())).is_err());
     }
 
+    #[test]
+    fn error_policy_on_broken_zip_archive() {
+        let t = TestDir::new(|p| {
+            fs::write(
+                p.join("broken.zip"),
+                &fs::read(&*FIXTURES.join("dir1/script.zip")).unwrap()[..200],
+            )
+            .unwrap()
+        });
+        let mut app = app(t.path());
+        let stats = spawn_threads(&app, &mut FakeGCRoots::new(t.path())).unwrap();
+        assert_eq!(1, stats.softerrors);
+        let skipped = stats.summary().skipped;
+        assert_eq!(1, skipped.len());
+        assert_eq!(ErrorClass::Other, skipped[0].class);
+        assert!(skipped[0].path.ends_with("broken.zip"));
+        assert!(!skipped[0].subtree);
+
+        app.opt.on_error = vec!["other=abort".parse().unwrap()];
+        assert!(spawn_threads(&app, &mut FakeGCRoots::new(t.path())).is_err());
+    }
+
     #[test]
     fn ignore_dangling_link() {
         let t = TestDir::new(|p| {
@@ -269,17 +645,47 @@ mod tests {
         assert_eq!(stats.softerrors, 0);
     }
 
+    /// All symlinks below `dir`
+    fn links(dir: &Path) -> Vec<PathBuf> {
+        let mut links: Vec<PathBuf> = WalkBuilder::new(dir)
+            .hidden(false)
+            .build()
+            .filter_map(|dent| dent.ok())
+            .filter(|dent| dent.path_is_symlink())
+            .map(|dent| dent.into_path())
+            .collect();
+        links.sort();
+        links
+    }
+
     #[test]
-    fn softfail_on_broken_zip_archive() {
+    fn skipped_file_should_keep_its_links() {
         let t = TestDir::new(|p| {
-            fs::write(
-                p.join("broken.zip"),
-                &fs::read(&*FIXTURES.join("dir1/script.zip")).unwrap()[..200],
+            fs::copy(FIXTURES.join("dir1/script.zip"), p.join("script.zip")).unwrap();
+        });
+        let startdir = t.path().canonicalize().unwrap();
+        let peruser = TempDir::new().unwrap();
+        let mut app = app(&startdir);
+        app.register = true;
+        let gcroots = || {
+            GCRoots::new(
+                peruser.path().to_str().unwrap(),
+                "/nix/store",
+                &startdir,
+                &Output::default(),
             )
             .unwrap()
-        });
-        let stats = spawn_threads(&app(t.path()), &mut FakeGCRoots::new(t.path())).unwrap();
-        assert_eq!(stats.softerrors, 1);
+        };
+        spawn_threads(&app, &mut gcroots()).unwrap();
+        let registered = links(peruser.path());
+        assert_eq!(1, registered.len());
+
+        // file cannot be scanned anymore
+        let data = fs::read(startdir.join("script.zip")).unwrap();
+        fs::write(startdir.join("script.zip"), &data[..200]).unwrap();
+        let stats = spawn_threads(&app, &mut gcroots()).unwrap();
+        assert_eq!(1, stats.summary().skipped.len());
+        assert_eq!(registered, links(peruser.path()));
     }
 
     #[test]
@@ -321,12 +727,14 @@ mod tests {
                 "dir2",
                 "dir2/ignored",
                 "dir2/link",
+                "elf",
+                "elf/hello",
                 "miniegg-1-py3.5.egg",
             ]
             .into_iter()
             .map(PathBuf::from)
             .collect::<Vec<_>>(),
-            walk2vec(&app.walker().unwrap(), &*FIXTURES)
+            walk2vec(&app.walker().unwrap(), &FIXTURES)
         );
     }
 
@@ -340,7 +748,7 @@ mod tests {
         let app = app(p);
 
         wfile(p.join(".userscan-ignore"), "file2\n*.jpg\ndata*\n");
-        for f in vec!["file1", "file2", "pic.jpg", "data.json"] {
+        for f in ["file1", "file2", "pic.jpg", "data.json"] {
             File::create(p.join(f)).unwrap();
         }
 